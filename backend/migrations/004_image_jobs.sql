@@ -0,0 +1,13 @@
+-- images whose derivatives still have to be generated by the processing worker
+CREATE TABLE image_jobs (
+    image_key TEXT PRIMARY KEY NOT NULL,
+    state TEXT NOT NULL DEFAULT 'queued', -- queued, processing or failed
+    error TEXT NULL,
+    created_at INTEGER NOT NULL, -- unix ts
+    updated_at INTEGER NOT NULL, -- unix ts
+
+    CONSTRAINT fk_image_key_assoc
+        FOREIGN KEY (image_key)
+        REFERENCES images (key)
+        ON DELETE CASCADE
+) STRICT;
@@ -0,0 +1,17 @@
+-- resumable uploads, the received chunks are appended to DATA_PATH/.uploads/<id>
+CREATE TABLE upload_sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    uploader TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    description TEXT NULL,
+    size_bytes INTEGER NOT NULL, -- announced size of the whole file
+    received_bytes INTEGER NOT NULL DEFAULT 0,
+    next_chunk INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL, -- unix ts
+    updated_at INTEGER NOT NULL, -- unix ts
+
+    CONSTRAINT fk_uploader_assoc
+        FOREIGN KEY (uploader)
+        REFERENCES users (username)
+        ON DELETE CASCADE
+) STRICT;
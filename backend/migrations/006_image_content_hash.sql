@@ -0,0 +1,4 @@
+-- sha256 of the original file, NULL for images uploaded before hashes were stored
+ALTER TABLE images ADD COLUMN content_hash TEXT NULL;
+
+CREATE INDEX images_content_hash ON images (content_hash);
@@ -0,0 +1,2 @@
+-- difference hash of the decoded image, computed while processing
+ALTER TABLE images ADD COLUMN perceptual_hash INTEGER NULL;
@@ -0,0 +1,11 @@
+-- additional metadata from EXIF and XMP, NULL for images uploaded before
+ALTER TABLE images ADD COLUMN iso INTEGER NULL;
+ALTER TABLE images ADD COLUMN lens_model TEXT NULL;
+ALTER TABLE images ADD COLUMN flash TEXT NULL;
+ALTER TABLE images ADD COLUMN white_balance TEXT NULL;
+ALTER TABLE images ADD COLUMN altitude REAL NULL; -- meters above sea level
+ALTER TABLE images ADD COLUMN gps_direction REAL NULL; -- degrees
+ALTER TABLE images ADD COLUMN width INTEGER NULL;
+ALTER TABLE images ADD COLUMN height INTEGER NULL;
+ALTER TABLE images ADD COLUMN title TEXT NULL;
+ALTER TABLE images ADD COLUMN keywords TEXT NULL; -- JSON array of strings
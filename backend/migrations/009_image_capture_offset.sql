@@ -0,0 +1,3 @@
+-- taken_at is a unix ts, these keep the time shown on the camera and its offset from UTC
+ALTER TABLE images ADD COLUMN taken_at_local TEXT NULL; -- e.g. 2008-10-22T16:28:39
+ALTER TABLE images ADD COLUMN taken_at_offset INTEGER NULL; -- seconds east of UTC
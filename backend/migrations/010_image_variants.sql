@@ -0,0 +1,14 @@
+-- dimensions of the generated derivatives, the ones of the original are on the image
+CREATE TABLE image_variants (
+    image_key TEXT NOT NULL,
+    file_name TEXT NOT NULL, -- e.g. medium.webp
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+
+    PRIMARY KEY (image_key, file_name),
+
+    CONSTRAINT fk_image_key_assoc
+        FOREIGN KEY (image_key)
+        REFERENCES images (key)
+        ON DELETE CASCADE
+) STRICT;
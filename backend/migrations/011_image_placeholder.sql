@@ -0,0 +1,3 @@
+-- shown by the frontend until a derivative is loaded, computed while processing
+ALTER TABLE images ADD COLUMN blurhash TEXT NULL;
+ALTER TABLE images ADD COLUMN dominant_color TEXT NULL; -- e.g. #a0b1c2
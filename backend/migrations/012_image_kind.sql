@@ -0,0 +1,3 @@
+-- images can be video clips too, their derivatives are generated from a poster frame
+ALTER TABLE images ADD COLUMN kind TEXT NOT NULL DEFAULT 'image'; -- image or video
+ALTER TABLE images ADD COLUMN duration REAL NULL; -- seconds, only for videos
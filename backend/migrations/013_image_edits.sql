@@ -0,0 +1,12 @@
+-- edits relative to the original, the derivatives are generated with the latest one
+CREATE TABLE image_edits (
+    id INTEGER PRIMARY KEY NOT NULL,
+    image_key TEXT NOT NULL,
+    operations TEXT NOT NULL, -- JSON array
+    created_at INTEGER NOT NULL, -- unix ts
+
+    CONSTRAINT fk_image_key_assoc
+        FOREIGN KEY (image_key)
+        REFERENCES images (key)
+        ON DELETE CASCADE
+) STRICT;
@@ -0,0 +1,3 @@
+-- whether location and camera metadata are removed from originals served to others
+ALTER TABLE users ADD COLUMN strip_metadata INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE images ADD COLUMN strip_metadata INTEGER NULL; -- NULL uses the setting of the uploader
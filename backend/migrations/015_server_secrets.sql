@@ -0,0 +1,5 @@
+-- keys generated on first use, e.g. to sign media urls
+CREATE TABLE server_secrets (
+    name TEXT PRIMARY KEY NOT NULL,
+    value BLOB NOT NULL
+) STRICT;
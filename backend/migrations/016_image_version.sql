@@ -0,0 +1,2 @@
+-- part of media urls so cached derivatives are replaced after they were generated again
+ALTER TABLE images ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
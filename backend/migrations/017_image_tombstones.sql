@@ -0,0 +1,5 @@
+-- images deleted from the database whose files the reaper still has to remove
+CREATE TABLE image_tombstones (
+    image_key TEXT PRIMARY KEY NOT NULL,
+    created_at INTEGER NOT NULL -- unix ts
+) STRICT;
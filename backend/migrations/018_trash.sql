@@ -0,0 +1,4 @@
+-- images, albums and comments in the trash of their owner, purged after the retention
+ALTER TABLE images ADD COLUMN deleted_at INTEGER NULL; -- unix ts
+ALTER TABLE albums ADD COLUMN deleted_at INTEGER NULL; -- unix ts
+ALTER TABLE comments ADD COLUMN deleted_at INTEGER NULL; -- unix ts
@@ -0,0 +1,4 @@
+-- shown in the list of sessions so users can tell them apart
+ALTER TABLE auth_sessions ADD COLUMN last_used_at INTEGER NULL; -- unix ts
+ALTER TABLE auth_sessions ADD COLUMN user_agent TEXT NULL;
+ALTER TABLE auth_sessions ADD COLUMN ip TEXT NULL;
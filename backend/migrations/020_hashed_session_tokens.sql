@@ -0,0 +1,20 @@
+-- tokens are only stored as SHA-256 hashes, the plaintext tokens of existing sessions can't be
+-- converted so everyone has to login again
+DROP TABLE auth_sessions;
+
+CREATE TABLE auth_sessions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL COLLATE NOCASE,
+    token_hash TEXT NOT NULL UNIQUE,
+    refresh_token_hash TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL, -- unix ts
+    token_created_at INTEGER NOT NULL, -- unix ts, the bearer token was issued or last refreshed
+    last_used_at INTEGER NOT NULL, -- unix ts
+    user_agent TEXT NULL,
+    ip TEXT NULL,
+
+    CONSTRAINT fk_username_assoc
+        FOREIGN KEY (username)
+        REFERENCES users (username)
+        ON DELETE CASCADE
+) STRICT;
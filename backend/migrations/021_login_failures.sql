@@ -0,0 +1,10 @@
+-- failed logins per username and per IP address, which back off further attempts
+CREATE TABLE login_failures (
+    kind TEXT NOT NULL, -- 'username' or 'ip'
+    subject TEXT NOT NULL COLLATE NOCASE,
+    failures INTEGER NOT NULL,
+    last_failure_at INTEGER NOT NULL, -- unix ts
+    locked_until INTEGER NULL, -- unix ts
+
+    PRIMARY KEY (kind, subject)
+) STRICT;
@@ -0,0 +1,28 @@
+-- optional TOTP second factor, with one-time recovery codes and the challenges of half finished
+-- logins
+ALTER TABLE users ADD COLUMN totp_secret BLOB NULL; -- set once enrollment was confirmed
+ALTER TABLE users ADD COLUMN totp_pending_secret BLOB NULL; -- waiting for the first code
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER NULL; -- codes can't be used twice
+
+CREATE TABLE recovery_codes (
+    id INTEGER PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL COLLATE NOCASE,
+    code_hash TEXT NOT NULL,
+
+    CONSTRAINT fk_username_assoc
+        FOREIGN KEY (username)
+        REFERENCES users (username)
+        ON DELETE CASCADE
+) STRICT;
+
+CREATE TABLE login_challenges (
+    id INTEGER PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL COLLATE NOCASE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL, -- unix ts
+
+    CONSTRAINT fk_username_assoc
+        FOREIGN KEY (username)
+        REFERENCES users (username)
+        ON DELETE CASCADE
+) STRICT;
@@ -0,0 +1,29 @@
+-- passkeys which log in instead of a password, and the challenges they have to sign
+CREATE TABLE webauthn_credentials (
+    id INTEGER PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL COLLATE NOCASE,
+    credential_id BLOB NOT NULL UNIQUE,
+    public_key BLOB NOT NULL, -- uncompressed P-256 point
+    sign_count INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL, -- unix ts
+    last_used_at INTEGER NULL, -- unix ts
+
+    CONSTRAINT fk_username_assoc
+        FOREIGN KEY (username)
+        REFERENCES users (username)
+        ON DELETE CASCADE
+) STRICT;
+
+CREATE TABLE webauthn_challenges (
+    id INTEGER PRIMARY KEY NOT NULL,
+    challenge BLOB NOT NULL UNIQUE,
+    kind TEXT NOT NULL, -- 'registration' or 'login'
+    username TEXT NULL COLLATE NOCASE, -- who registers a passkey, logins can be anyone's
+    created_at INTEGER NOT NULL, -- unix ts
+
+    CONSTRAINT fk_username_assoc
+        FOREIGN KEY (username)
+        REFERENCES users (username)
+        ON DELETE CASCADE
+) STRICT;
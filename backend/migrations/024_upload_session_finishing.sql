@@ -0,0 +1,3 @@
+-- set while a finish request hands the upload to the pipeline, so concurrent finishes can't both
+-- store it
+ALTER TABLE upload_sessions ADD COLUMN finishing_at INTEGER NULL; -- unix ts
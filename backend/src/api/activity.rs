@@ -11,6 +11,7 @@ use crate::api::{
     comment::Comment,
     image::{
         get_all::{get_albums_containing_image, AllImagesImage},
+        privacy::ImagePrivacy,
         DbImage, Image,
     },
     user::{self, User},
@@ -35,22 +36,40 @@ enum Activity {
     Album(AlbumMetadata),
     Comment(Comment),
     User(User),
-    Image(AllImagesImage),
+    Image(Box<AllImagesImage>),
 }
 
-pub fn get_new_images(conn: &Connection) -> Result<Vec<AllImagesImage>, Error> {
+/// Location and camera fields of stripped images are left out unless the viewer uploaded them.
+pub fn get_new_images(viewer: &str, conn: &Connection) -> Result<Vec<AllImagesImage>, Error> {
     let mut query = conn
         .prepare(
-            "SELECT i.*, aia.created_at as published_at FROM images i \
+            "SELECT \
+                i.*, \
+                aia.created_at as published_at, \
+                COALESCE(i.strip_metadata, u.strip_metadata) AS strips_metadata \
+            FROM images i \
             INNER JOIN album_image_associations aia ON i.key = aia.image_key \
             INNER JOIN albums a ON a.key = aia.album_key \
-            WHERE a.published_at < aia.created_at",
+            INNER JOIN users u ON u.username = i.uploader \
+            WHERE a.published_at < aia.created_at \
+            AND i.deleted_at IS NULL AND a.deleted_at IS NULL",
         )
         .context("Failed to prepare statement for images query")?;
 
     let images = query
         .query_map(params![], |row| {
-            Ok(Image::from_db(from_row::<DbImage>(row).unwrap()))
+            let mut image = Image::from_db(from_row::<DbImage>(row).unwrap());
+
+            let privacy = ImagePrivacy {
+                uploader: image.uploader.clone(),
+                strip_metadata: row.get("strips_metadata")?,
+            };
+            if privacy.strips_for(Some(viewer)) {
+                image.metadata.remove_location();
+                image.metadata.remove_camera();
+            }
+
+            Ok(image)
         })
         .context("Failed to query user images")?
         .collect::<Result<Vec<_>, _>>()
@@ -78,7 +97,7 @@ async fn get_activities(
                 draft: false,
                 ..Default::default()
             };
-            let albums = album::get_all::get_albums(username, filters, conn)?
+            let albums = album::get_all::get_albums(username.clone(), filters, conn)?
                 .into_iter()
                 .map(Activity::Album);
 
@@ -86,7 +105,9 @@ async fn get_activities(
 
             let comments = comment::get_all(conn)?.into_iter().map(Activity::Comment);
 
-            let images = get_new_images(conn)?.into_iter().map(Activity::Image);
+            let images = get_new_images(&username, conn)?
+                .into_iter()
+                .map(|i| Activity::Image(Box::new(i)));
 
             let mut activities: Vec<Activity> =
                 albums.chain(users).chain(comments).chain(images).collect();
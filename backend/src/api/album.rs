@@ -13,6 +13,7 @@ use crate::api::error::Error;
 use crate::util::comma_string;
 
 use super::{image, user};
+use crate::api::image::privacy::ImagePrivacy;
 use crate::api::image::{DbImage, Image};
 
 mod create;
@@ -22,6 +23,7 @@ pub(super) mod get_all;
 mod get_by_key;
 mod get_by_share_token;
 mod get_filters;
+mod get_similar;
 pub(super) mod update;
 
 const MAXIMUM_TITLE_LENGTH: u64 = 96;
@@ -33,6 +35,7 @@ pub fn api_route() -> Router {
         .route("/", get(get_all::get))
         .route("/filters", get(get_filters::get))
         .route("/:key", get(get_by_key::get))
+        .route("/:key/similar", get(get_similar::get))
         .route("/:key", put(update::put))
         .route("/:key", delete(delete_album::delete))
 }
@@ -145,7 +148,7 @@ pub struct InsertShareToken<'a> {
 
 pub fn is_owner(album_key: &str, user: &str, conn: &Connection) -> Result<bool, Error> {
     let result = conn.query_row(
-        "SELECT author FROM albums WHERE key = ?1",
+        "SELECT author FROM albums WHERE key = ?1 AND deleted_at IS NULL",
         params![album_key],
         |row| row.get::<_, String>(0),
     );
@@ -159,7 +162,12 @@ pub fn is_owner(album_key: &str, user: &str, conn: &Connection) -> Result<bool,
     }
 }
 
-pub(super) fn get_album(album_key: &str, conn: &Connection) -> anyhow::Result<Option<Album>> {
+/// Location and camera fields of stripped images are left out unless the viewer uploaded them.
+pub(super) fn get_album(
+    album_key: &str,
+    viewer: &str,
+    conn: &Connection,
+) -> anyhow::Result<Option<Album>> {
     let result = conn
         .query_row(
             "SELECT \
@@ -173,7 +181,7 @@ pub(super) fn get_album(album_key: &str, conn: &Connection) -> anyhow::Result<Op
                 timeframe_to, \
                 published_at \
             FROM albums \
-            WHERE key=?1",
+            WHERE key=?1 AND deleted_at IS NULL",
             params![album_key],
             |row| Ok(from_row::<DbAlbum>(row).unwrap()),
         )
@@ -188,9 +196,13 @@ pub(super) fn get_album(album_key: &str, conn: &Connection) -> anyhow::Result<Op
                     i.description, \
                     i.uploader, \
                     i.uploaded_at, \
+                    i.kind, \
+                    i.duration, \
                     i.file_name, \
                     i.size_bytes, \
                     i.taken_at, \
+                    i.taken_at_local, \
+                    i.taken_at_offset, \
                     i.location_latitude, \
                     i.location_longitude, \
                     i.camera_brand, \
@@ -198,18 +210,44 @@ pub(super) fn get_album(album_key: &str, conn: &Connection) -> anyhow::Result<Op
                     i.exposure_time, \
                     i.f_number, \
                     i.focal_length, \
+                    i.iso, \
+                    i.lens_model, \
+                    i.flash, \
+                    i.white_balance, \
+                    i.altitude, \
+                    i.gps_direction, \
+                    i.width, \
+                    i.height, \
+                    i.blurhash, \
+                    i.dominant_color, \
+                    i.version, \
+                    i.title, \
+                    i.keywords, \
+                    COALESCE(i.strip_metadata, u.strip_metadata) AS strips_metadata, \
                     COUNT(c.id) AS comment_count \
                 FROM images i \
                 INNER JOIN album_image_associations aia ON aia.image_key=i.key \
-                LEFT JOIN comments c ON c.image_key=i.key \
-                WHERE aia.album_key=?1 \
+                INNER JOIN users u ON u.username=i.uploader \
+                LEFT JOIN comments c ON c.image_key=i.key AND c.deleted_at IS NULL \
+                WHERE aia.album_key=?1 AND i.deleted_at IS NULL \
                 GROUP BY i.key \
                 ORDER BY aia.idx",
             )
             .context("Failed to prepare statement for image query")?;
         let image_iter = stmt
             .query_map(params![db_album.key], |row| {
-                Ok(AlbumImage::from(from_row::<DbAlbumImage>(row).unwrap()))
+                let mut image = AlbumImage::from(from_row::<DbAlbumImage>(row).unwrap());
+
+                let privacy = ImagePrivacy {
+                    uploader: image.image.uploader.clone(),
+                    strip_metadata: row.get("strips_metadata")?,
+                };
+                if privacy.strips_for(Some(viewer)) {
+                    image.image.metadata.remove_location();
+                    image.image.metadata.remove_camera();
+                }
+
+                Ok(image)
             })
             .context("Failed to query images for album")?;
 
@@ -283,7 +321,7 @@ pub fn insert_album(album: InsertAlbum, conn: &Connection) -> Result<(), Error>
 
         conn.execute(
             "INSERT INTO album_image_associations (album_key, idx, image_key, created_at) \
-            SELECT ?1, ?2, key, ?4 FROM images WHERE key = ?3",
+            SELECT ?1, ?2, key, ?4 FROM images WHERE key = ?3 AND deleted_at IS NULL",
             params![album.key, idx, image_key, album.published_at],
         )
         .context("Failed to insert album image associations")?;
@@ -358,6 +396,7 @@ fn apply_filters(
     }
 
     filter_queries.push(draft_filter_query(parameters, filters.draft, username));
+    filter_queries.push(String::from("deleted_at IS NULL"));
 
     if !filter_queries.is_empty() {
         write!(query, " WHERE {}", filter_queries.join(" AND ")).unwrap();
@@ -1,12 +1,12 @@
-use anyhow::Context;
 use axum::{extract::Path, Extension, Json};
-use rusqlite::params;
 use tracing::*;
 
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use crate::api::auth::Authorize;
 use crate::api::error::Error;
+use crate::api::trash;
 use crate::AppState;
 
 pub(super) async fn delete(
@@ -14,13 +14,13 @@ pub(super) async fn delete(
     Authorize(user): Authorize,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Result<Json<()>, Error> {
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
     state
         .db
         .call(move |conn| {
             if super::is_owner(&album_key, &user, conn)? {
-                info!("Deleting album {album_key}");
-                conn.execute("DELETE FROM albums WHERE key = ?1", params![album_key])
-                    .context("Failed to delete album")?;
+                info!("Moving album {album_key} to the trash");
+                trash::add_album(&album_key, now, conn)?;
 
                 Ok(Json(()))
             } else {
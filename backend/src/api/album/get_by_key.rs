@@ -10,14 +10,15 @@ use super::Album;
 
 pub(super) async fn get(
     Path(album_key): Path<String>,
-    Authorize(_): Authorize,
+    Authorize(user): Authorize,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Result<Json<Album>, Error> {
     state
         .db
-        .call(move |conn| match super::get_album(&album_key, conn)? {
-            Some(album) => Ok(Json(album)),
-            None => Err(Error::NotFound),
+        .call(move |conn| {
+            let album = super::get_album(&album_key, &user, conn)?;
+
+            album.map(Json).ok_or(Error::NotFound)
         })
         .await
 }
@@ -109,4 +110,57 @@ mod test {
             assert_eq!(album.tagged_users, users);
         });
     }
+
+    #[tokio::test]
+    async fn get_album_strips_metadata_for_others() {
+        let state = AppState::in_memory_db().await;
+
+        let album = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                insert_user("friend", conn);
+                let image = insert_image(&user, conn);
+                conn.execute(
+                    "UPDATE images SET \
+                        location_latitude = '52.5', \
+                        location_longitude = '13.4', \
+                        camera_brand = 'Brand', \
+                        strip_metadata = 1 \
+                    WHERE key = ?1",
+                    rusqlite::params![image],
+                )
+                .unwrap();
+
+                insert_album(
+                    InsertAlbum {
+                        cover_key: &image,
+                        image_keys: &[image.clone()],
+                        author: &user,
+                        ..Default::default()
+                    },
+                    conn,
+                )
+            })
+            .await;
+
+        let result = get(
+            Path(album.clone()),
+            Authorize("friend".into()),
+            Extension(state.clone()),
+        )
+        .await;
+        assert_matches!(result, Ok(Json(album)) => {
+            let image = serde_json::to_value(&album.images[0]).unwrap();
+            assert!(image["location"].is_null());
+            assert!(image["cameraBrand"].is_null());
+        });
+
+        let result = get(Path(album), Authorize("test".into()), Extension(state)).await;
+        assert_matches!(result, Ok(Json(album)) => {
+            let image = serde_json::to_value(&album.images[0]).unwrap();
+            assert!(!image["location"].is_null());
+            assert_eq!(image["cameraBrand"], "Brand");
+        });
+    }
 }
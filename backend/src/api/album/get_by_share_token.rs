@@ -49,7 +49,7 @@ pub(super) async fn get(
                     a.timeframe_to, \
                     a.published_at \
                 FROM albums a \
-                WHERE a.key=?1",
+                WHERE a.key=?1 AND a.deleted_at IS NULL",
                     params![album_key],
                     |row| Ok(from_row::<DbAlbum>(row).unwrap()),
                 )
@@ -63,24 +63,51 @@ pub(super) async fn get(
                         i.key, \
                         i.uploader, \
                         i.uploaded_at, \
+                        i.kind, \
+                        i.duration, \
                         i.file_name, \
                         i.size_bytes, \
                         i.taken_at, \
+                        i.taken_at_local, \
+                        i.taken_at_offset, \
                         i.location_latitude, \
                         i.location_longitude, \
                         i.camera_brand, \
                         i.camera_model, \
                         i.exposure_time, \
                         i.f_number, \
-                        i.focal_length \
+                        i.focal_length, \
+                        i.iso, \
+                        i.lens_model, \
+                        i.flash, \
+                        i.white_balance, \
+                        i.altitude, \
+                        i.gps_direction, \
+                        i.width, \
+                        i.height, \
+                        i.blurhash, \
+                        i.dominant_color, \
+                        i.version, \
+                        i.title, \
+                        i.keywords, \
+                        COALESCE(i.strip_metadata, u.strip_metadata) AS strips_metadata \
                     FROM images i \
                     INNER JOIN album_image_associations aia ON aia.image_key=i.key \
-                    WHERE aia.album_key=?1",
+                    INNER JOIN users u ON u.username=i.uploader \
+                    WHERE aia.album_key=?1 AND i.deleted_at IS NULL",
                     )
                     .context("Failed to prepare statement for image query")?;
                 let image_iter = stmt
                     .query_map(params![db_album.key], |row| {
-                        Ok(Image::from_db(from_row::<DbImage>(row).unwrap()))
+                        let mut image = Image::from_db(from_row::<DbImage>(row).unwrap());
+
+                        // Coordinates are never shared through links, the camera unless allowed
+                        image.metadata.remove_location();
+                        if row.get::<_, bool>("strips_metadata")? {
+                            image.metadata.remove_camera();
+                        }
+
+                        Ok(image)
                     })
                     .context("Failed to query images for album")?;
 
@@ -0,0 +1,26 @@
+use axum::{extract::Path, Extension, Json};
+
+use std::sync::Arc;
+
+use crate::api::auth::Authorize;
+use crate::api::error::Error;
+use crate::api::image::similar;
+use crate::AppState;
+
+/// Groups of visually similar images in the album, so the editor can suggest which to drop.
+pub(super) async fn get(
+    Path(album_key): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<Vec<String>>>, Error> {
+    state
+        .db
+        .call(move |conn| {
+            if super::get_album(&album_key, &user, conn)?.is_none() {
+                return Err(Error::NotFound);
+            }
+
+            Ok(Json(similar::album_clusters(&album_key, conn)?))
+        })
+        .await
+}
@@ -108,7 +108,8 @@ pub async fn put(
                              image_key, \
                              created_at \
                         ) \
-                        SELECT ?1, ?2, key, ?4 FROM images WHERE key = ?3",
+                        SELECT ?1, ?2, key, ?4 FROM images \
+                        WHERE key = ?3 AND deleted_at IS NULL",
                         params![album_key, idx, image_key, now],
                     )
                     .context("Failed to insert album image associations")?;
@@ -307,7 +308,7 @@ mod test {
         put(
             Ok(Json(request)),
             Path(key.clone()),
-            Authorize(user),
+            Authorize(user.clone()),
             Extension(state.clone()),
         )
         .await
@@ -315,7 +316,7 @@ mod test {
 
         let album = state
             .db
-            .call(move |conn| get_album(&key, conn))
+            .call(move |conn| get_album(&key, &user, conn))
             .await
             .unwrap()
             .unwrap();
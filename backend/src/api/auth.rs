@@ -1,37 +1,97 @@
+use anyhow::Context;
 use axum::{
     async_trait,
     extract::{
         rejection::{ExtensionRejection, TypedHeaderRejection},
-        FromRequest, RequestParts,
+        FromRequest, Path, RequestParts,
     },
     http::StatusCode,
     response::{IntoResponse, Response},
-    routing::{get, Router},
+    routing::{delete, get, post, Router},
     Extension, Json, TypedHeader,
 };
 use headers::{authorization::Bearer, Authorization};
-use rusqlite::{params, OptionalExtension};
-use serde::Deserialize;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use serde_rusqlite::from_row;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tracing::error;
 
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::SystemTime;
 
+use crate::api::error::Error;
 use crate::AppState;
 
+/// Recording every request would mean a write for each of them, a minute is precise enough.
+const LAST_USED_PRECISION: u64 = 60;
+
 pub struct Authorize(pub String);
 
+#[async_trait]
+impl<B> FromRequest<B> for Authorize
+where
+    B: Send,
+{
+    type Rejection = AuthorizationRejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request(req).await?;
+
+        Ok(Authorize(session.username))
+    }
+}
+
+/// Like [`Authorize`] but also tells which of the user's sessions made the request.
+pub struct Session {
+    pub id: i64,
+    pub username: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct DbSession {
+    id: i64,
     username: String,
-    created_at: u64,
+    token_created_at: u64,
+    last_used_at: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Expiry {
+    Valid,
+    /// The bearer token has to be refreshed.
+    Token,
+    /// Not used for too long, the user has to login again.
+    Session,
+}
+
+impl DbSession {
+    fn expiry(&self, now: u64) -> Expiry {
+        if session_expired(self.last_used_at, now) {
+            Expiry::Session
+        } else if now >= self.token_created_at + crate::ACCESS_TOKEN_SECONDS {
+            Expiry::Token
+        } else {
+            Expiry::Valid
+        }
+    }
+}
+
+/// Only hashes of the bearer and refresh tokens are stored, a leaked database does not grant
+/// access to any account.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Whether a session last used at `last_used_at` was idle for too long.
+pub fn session_expired(last_used_at: u64, now: u64) -> bool {
+    now >= last_used_at + crate::SESSION_IDLE_SECONDS
 }
 
 #[async_trait]
-impl<B> FromRequest<B> for Authorize
+impl<B> FromRequest<B> for Session
 where
     B: Send,
 {
@@ -42,13 +102,14 @@ where
             TypedHeader::<Authorization<Bearer>>::from_request(req).await?;
         let Extension(state) = Extension::<Arc<AppState>>::from_request(req).await?;
 
-        let bearer_token = bearer.token().to_owned();
+        let token_hash = hash_token(bearer.token());
         let db_session = state
             .db
             .call(move |conn| {
                 conn.query_row(
-                    r"SELECT username, created_at FROM auth_sessions WHERE token=?1",
-                    params![bearer.token()],
+                    "SELECT id, username, token_created_at, last_used_at \
+                    FROM auth_sessions WHERE token_hash=?1",
+                    params![token_hash],
                     |row| Ok(from_row::<DbSession>(row).unwrap()),
                 )
                 .optional()
@@ -56,29 +117,44 @@ where
             .await
             .map_err(anyhow::Error::new)?;
 
-        if let Some(session) = db_session {
-            let created_at = Duration::from_secs(session.created_at);
-            let now = SystemTime::UNIX_EPOCH.elapsed().unwrap();
+        let session = db_session.ok_or(AuthorizationRejection::InvalidToken)?;
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let id = session.id;
+
+        match session.expiry(now) {
+            Expiry::Valid => {
+                state
+                    .db
+                    .call(move |conn| {
+                        conn.execute(
+                            "UPDATE auth_sessions SET last_used_at = ?1 \
+                            WHERE id = ?2 AND last_used_at <= ?3",
+                            params![now, id, now.saturating_sub(LAST_USED_PRECISION)],
+                        )
+                    })
+                    .await
+                    .map_err(anyhow::Error::new)?;
 
-            if now < created_at + Duration::from_secs(crate::AUTH_TIME_SECONDS) {
-                Ok(Authorize(session.username))
-            } else {
+                Ok(Session {
+                    id,
+                    username: session.username,
+                })
+            }
+            Expiry::Token => Err(AuthorizationRejection::ExpiredAccessToken),
+            Expiry::Session => {
                 state
                     .db
                     .call(move |conn| {
-                        if let Err(e) = conn.execute(
-                            "DELETE FROM auth_sessions WHERE token=?1",
-                            params![bearer_token],
-                        ) {
-                            error!("Failed to delete auth token: {}", e);
+                        if let Err(e) =
+                            conn.execute("DELETE FROM auth_sessions WHERE id=?1", params![id])
+                        {
+                            error!("Failed to delete auth session: {}", e);
                         }
                     })
                     .await;
 
                 Err(AuthorizationRejection::ExpiredToken)
             }
-        } else {
-            Err(AuthorizationRejection::InvalidToken)
         }
     }
 }
@@ -93,6 +169,8 @@ pub enum AuthorizationRejection {
     InvalidToken,
     #[error("Your session has expired, please login again")]
     ExpiredToken,
+    #[error("Your access token has expired, please refresh it")]
+    ExpiredAccessToken,
     #[error("{0}")]
     Generic(#[from] anyhow::Error),
 }
@@ -103,9 +181,9 @@ impl IntoResponse for AuthorizationRejection {
             AuthorizationRejection::Extension(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AuthorizationRejection::Generic(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AuthorizationRejection::Headers(_) => StatusCode::BAD_REQUEST,
-            AuthorizationRejection::InvalidToken | AuthorizationRejection::ExpiredToken => {
-                StatusCode::UNAUTHORIZED
-            }
+            AuthorizationRejection::InvalidToken
+            | AuthorizationRejection::ExpiredToken
+            | AuthorizationRejection::ExpiredAccessToken => StatusCode::UNAUTHORIZED,
         };
 
         let body = Json(json!({
@@ -118,7 +196,12 @@ impl IntoResponse for AuthorizationRejection {
 }
 
 pub fn api_route() -> Router {
-    Router::new().route("/", get(get_auth_state))
+    Router::new()
+        .route("/", get(get_auth_state))
+        .route("/logout", post(post_logout))
+        .route("/sessions", get(get_sessions))
+        .route("/sessions", delete(delete_all_sessions))
+        .route("/sessions/:id", delete(delete_session))
 }
 
 pub async fn get_auth_state(Authorize(username): Authorize) -> Json<Value> {
@@ -126,3 +209,219 @@ pub async fn get_auth_state(Authorize(username): Authorize) -> Json<Value> {
         "username": username,
     }))
 }
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub id: i64,
+    pub created_at: u64,
+    pub last_used_at: u64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Whether this is the session which asked for the list.
+    pub current: bool,
+}
+
+/// Sessions of `username`, most recently used first.
+pub fn select_sessions(
+    username: &str,
+    current: i64,
+    conn: &Connection,
+) -> anyhow::Result<Vec<SessionInfo>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, created_at, last_used_at, user_agent, ip FROM auth_sessions \
+            WHERE username = ?1 \
+            ORDER BY last_used_at DESC, id DESC",
+        )
+        .context("Failed to prepare statement for sessions query")?;
+
+    let sessions = stmt
+        .query_map(params![username], |row| {
+            let id = row.get(0)?;
+
+            Ok(SessionInfo {
+                id,
+                created_at: row.get(1)?,
+                last_used_at: row.get(2)?,
+                user_agent: row.get(3)?,
+                ip: row.get(4)?,
+                current: id == current,
+            })
+        })
+        .context("Failed to query sessions")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect sessions")?;
+
+    Ok(sessions)
+}
+
+/// Returns whether `username` had a session with the id.
+pub fn revoke_session(id: i64, username: &str, conn: &Connection) -> anyhow::Result<bool> {
+    let deleted = conn
+        .execute(
+            "DELETE FROM auth_sessions WHERE id = ?1 AND username = ?2",
+            params![id, username],
+        )
+        .context("Failed to delete session")?;
+
+    Ok(deleted > 0)
+}
+
+/// Logs `username` out everywhere, except for the session `keep` if given. Returns how many
+/// sessions were revoked.
+pub fn revoke_sessions(
+    username: &str,
+    keep: Option<i64>,
+    conn: &Connection,
+) -> anyhow::Result<usize> {
+    let deleted = conn
+        .execute(
+            "DELETE FROM auth_sessions WHERE username = ?1 AND id IS NOT ?2",
+            params![username, keep],
+        )
+        .context("Failed to delete sessions")?;
+
+    Ok(deleted)
+}
+
+async fn post_logout(
+    session: Session,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<()>, Error> {
+    state
+        .db
+        .call(move |conn| revoke_session(session.id, &session.username, conn))
+        .await?;
+
+    Ok(Json(()))
+}
+
+async fn get_sessions(
+    session: Session,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<SessionInfo>>, Error> {
+    let sessions = state
+        .db
+        .call(move |conn| select_sessions(&session.username, session.id, conn))
+        .await?;
+
+    Ok(Json(sessions))
+}
+
+async fn delete_session(
+    Path(id): Path<i64>,
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<()>, Error> {
+    let revoked = state
+        .db
+        .call(move |conn| revoke_session(id, &username, conn))
+        .await?;
+
+    if revoked {
+        Ok(Json(()))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+async fn delete_all_sessions(
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<()>, Error> {
+    state
+        .db
+        .call(move |conn| revoke_sessions(&username, None, conn))
+        .await?;
+
+    Ok(Json(()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::insert_user;
+
+    fn insert_session(username: &str, token: &str, last_used_at: u64, conn: &Connection) -> i64 {
+        conn.execute(
+            "INSERT INTO auth_sessions \
+            (username, token_hash, refresh_token_hash, created_at, token_created_at, last_used_at) \
+            VALUES (?1, ?2, ?3, 0, 0, ?4)",
+            params![
+                username,
+                hash_token(token),
+                hash_token(&format!("refresh-{token}")),
+                last_used_at
+            ],
+        )
+        .unwrap();
+
+        conn.last_insert_rowid()
+    }
+
+    #[tokio::test]
+    async fn list_and_revoke_sessions() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let other = insert_user("other", conn);
+                let old = insert_session(&user, "old", 10, conn);
+                let current = insert_session(&user, "current", 20, conn);
+                let newest = insert_session(&user, "newest", 30, conn);
+                let foreign = insert_session(&other, "foreign", 40, conn);
+
+                let sessions = select_sessions(&user, current, conn).unwrap();
+                let ids: Vec<_> = sessions.iter().map(|s| s.id).collect();
+                assert_eq!(ids, vec![newest, current, old]);
+                assert!(sessions[1].current);
+                assert!(!sessions[0].current && !sessions[2].current);
+
+                // Sessions of other users can't be revoked
+                assert!(!revoke_session(foreign, &user, conn).unwrap());
+                assert!(revoke_session(old, &user, conn).unwrap());
+
+                assert_eq!(revoke_sessions(&user, Some(current), conn).unwrap(), 1);
+                let ids: Vec<_> = select_sessions(&user, current, conn)
+                    .unwrap()
+                    .iter()
+                    .map(|s| s.id)
+                    .collect();
+                assert_eq!(ids, vec![current]);
+
+                assert_eq!(revoke_sessions(&user, None, conn).unwrap(), 1);
+                assert!(select_sessions(&user, current, conn).unwrap().is_empty());
+                assert_eq!(select_sessions(&other, 0, conn).unwrap().len(), 1);
+            })
+            .await;
+    }
+
+    #[test]
+    fn session_expiry() {
+        let session = DbSession {
+            id: 1,
+            username: "test".into(),
+            token_created_at: 1000,
+            last_used_at: 2000,
+        };
+
+        assert_eq!(session.expiry(1000), Expiry::Valid);
+        assert_eq!(
+            session.expiry(1000 + crate::ACCESS_TOKEN_SECONDS),
+            Expiry::Token
+        );
+
+        // The bearer token can be refreshed until the session was idle for too long
+        assert_eq!(
+            session.expiry(2000 + crate::SESSION_IDLE_SECONDS - 1),
+            Expiry::Token
+        );
+        assert_eq!(
+            session.expiry(2000 + crate::SESSION_IDLE_SECONDS),
+            Expiry::Session
+        );
+    }
+}
@@ -65,7 +65,8 @@ pub fn insert_comment(
 
 pub fn get_comment(id: i64, conn: &Connection) -> anyhow::Result<Option<Comment>> {
     let result = conn.query_row(
-        "SELECT author, image_key, album_key, created_at, text FROM comments WHERE id = ?1",
+        "SELECT author, image_key, album_key, created_at, text FROM comments \
+        WHERE id = ?1 AND deleted_at IS NULL",
         params![id],
         |row| {
             Ok(Comment {
@@ -93,7 +94,9 @@ pub fn get_all(conn: &Connection) -> Result<Vec<Comment>, Error> {
         .prepare(
             "SELECT c.id, c.text, c.author, c.image_key, c.album_key, c.created_at \
                 FROM comments c \
-                INNER JOIN images i ON c.image_key = i.key",
+                INNER JOIN images i ON c.image_key = i.key \
+                INNER JOIN albums a ON c.album_key = a.key \
+                WHERE c.deleted_at IS NULL AND i.deleted_at IS NULL AND a.deleted_at IS NULL",
         )
         .context("Failed to prepare statement for comment query")?;
 
@@ -1,13 +1,13 @@
-use anyhow::Context;
 use axum::{extract::Path, Extension, Json};
-use rusqlite::params;
 use tracing::*;
 
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use crate::api::album;
 use crate::api::auth::Authorize;
 use crate::api::error::Error;
+use crate::api::trash;
 use crate::AppState;
 
 use super::Comment;
@@ -23,6 +23,7 @@ pub(super) async fn delete(
     Authorize(user): Authorize,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Result<Json<Comment>, Error> {
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
     state
         .db
         .call(move |conn| match super::get_comment(comment_id, conn)? {
@@ -31,9 +32,8 @@ pub(super) async fn delete(
                     return Err(Error::Unathorized);
                 }
 
-                info!("Deleting comment {comment_id}");
-                conn.execute("DELETE FROM comments WHERE id = ?1", params![comment_id])
-                    .context("Failed to delete comment")?;
+                info!("Moving comment {comment_id} to the trash");
+                trash::add_comment(comment_id, now, conn)?;
 
                 Ok(Json(comment))
             }
@@ -27,7 +27,8 @@ pub(super) async fn get(
                 .prepare(
                     "SELECT c.id, c.text, c.author, c.created_at FROM comments c \
                 INNER JOIN images i ON c.image_key=i.key \
-                WHERE i.key=?1",
+                INNER JOIN albums a ON c.album_key=a.key \
+                WHERE i.key=?1 AND c.deleted_at IS NULL AND a.deleted_at IS NULL",
                 )
                 .context("Failed to prepare statement for comment query")?;
 
@@ -3,7 +3,7 @@ use axum::{
         multipart::MultipartRejection,
         rejection::{ContentLengthLimitRejection, JsonRejection},
     },
-    http::StatusCode,
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -11,6 +11,8 @@ use serde_json::json;
 use thiserror::Error;
 use tracing::error;
 
+use crate::api::image::ImageUsage;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Not Found")]
@@ -22,6 +24,24 @@ pub enum Error {
     #[error("Missing image data in multipart message")]
     NoImage,
 
+    #[error("File should not be larger than {0} MB")]
+    FileTooLarge(u64),
+
+    #[error("Expected chunk {expected} of the upload")]
+    UnexpectedChunk { expected: u64 },
+
+    #[error("Upload is missing {missing_bytes} bytes")]
+    IncompleteUpload { missing_bytes: u64 },
+
+    #[error("Upload is already being finished")]
+    UploadFinishing,
+
+    #[error("Image was already uploaded as {key}")]
+    DuplicateImage { key: String },
+
+    #[error("Image is still used as album cover or in a profile")]
+    ImageInUse(ImageUsage),
+
     #[error("Failed to process image: {0}")]
     ImageError(#[from] image::ImageError),
 
@@ -37,6 +57,18 @@ pub enum Error {
     #[error("Invalid password")]
     InvalidPassword,
 
+    #[error("Invalid or expired refresh token, please login again")]
+    InvalidRefreshToken,
+
+    #[error("Invalid two-factor code")]
+    InvalidTwoFactorCode,
+
+    #[error("Login took too long, please login again")]
+    InvalidChallenge,
+
+    #[error("Too many failed logins, try again in {retry_after} seconds")]
+    TooManyLoginAttempts { retry_after: u64 },
+
     #[error("timeframe contains an invalid range")]
     InvalidTimeframe,
 
@@ -71,10 +103,17 @@ pub enum Error {
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         let status = match &self {
-            Error::InvalidLogin | Error::InvalidPassword | Error::Unathorized => {
-                StatusCode::UNAUTHORIZED
-            }
+            Error::InvalidLogin
+            | Error::InvalidPassword
+            | Error::InvalidRefreshToken
+            | Error::InvalidChallenge
+            | Error::Unathorized => StatusCode::UNAUTHORIZED,
             Error::NotFound => StatusCode::NOT_FOUND,
+            Error::UnexpectedChunk { .. }
+            | Error::UploadFinishing
+            | Error::DuplicateImage { .. }
+            | Error::ImageInUse(_) => StatusCode::CONFLICT,
+            Error::TooManyLoginAttempts { .. } => StatusCode::TOO_MANY_REQUESTS,
             Error::InternalError(e) => {
                 let err = e
                     .chain()
@@ -88,9 +127,12 @@ impl IntoResponse for Error {
             | Error::InvalidKey
             | Error::AlreadyPublished
             | Error::NoImage
+            | Error::FileTooLarge(_)
+            | Error::IncompleteUpload { .. }
             | Error::ImageError(_)
             | Error::InvalidTimeframe
             | Error::InvalidUsername
+            | Error::InvalidTwoFactorCode
             | Error::WrongImage
             | Error::TooManyCharacters { .. }
             | Error::JsonRejection(_)
@@ -98,6 +140,18 @@ impl IntoResponse for Error {
             | Error::InvalidArguments(_) => StatusCode::BAD_REQUEST,
         };
 
+        // Tells the client what to change before trying again
+        let used_by = if let Error::ImageInUse(usage) = &self {
+            Some(json!(usage))
+        } else {
+            None
+        };
+        let retry_after = if let Error::TooManyLoginAttempts { retry_after } = &self {
+            Some(*retry_after)
+        } else {
+            None
+        };
+
         let message = if let Error::JsonRejection(rej) = self {
             use std::error::Error;
             match rej {
@@ -109,9 +163,19 @@ impl IntoResponse for Error {
             self.to_string()
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "message": message,
-        }));
-        (status, body).into_response()
+        });
+        if let Some(used_by) = used_by {
+            body["usedBy"] = used_by;
+        }
+
+        let mut response = (status, Json(body)).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
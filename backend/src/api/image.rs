@@ -6,22 +6,49 @@ use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_rusqlite::{from_row, to_params_named};
 
+pub mod dedup;
 mod delete_image;
+pub mod dimensions;
+pub mod format;
+pub mod fsck;
 pub mod get_all;
 mod get_by_key;
+mod get_exif;
+mod get_variants;
+pub mod media;
 pub mod orientation;
+pub mod placeholder;
+pub mod privacy;
+pub mod processing;
+pub mod signature;
+pub mod similar;
+pub mod size;
+mod timezone;
+pub mod tombstone;
+pub mod transform;
 mod update_metadata;
 pub mod upload;
+pub mod video;
+mod xmp;
+
+pub use delete_image::ImageUsage;
 
 use crate::api::error::Error;
 
-const MAXIMUM_FILE_NAME_LENGTH: u64 = 96;
-const MAXIMUM_DESCRIPTION_LENGTH: u64 = 256;
+pub(crate) const MAXIMUM_FILE_NAME_LENGTH: u64 = 96;
+pub(crate) const MAXIMUM_DESCRIPTION_LENGTH: u64 = 256;
 
 pub fn api_route() -> Router {
     Router::new()
         .route("/", post(upload::post))
+        .route("/similar", get(similar::get))
+        .route("/media-token", get(signature::get))
         .route("/:key", get(get_by_key::get))
+        .route("/:key/variants", get(get_variants::get))
+        .route("/:key/exif", get(get_exif::get))
+        .route("/:key/transform", get(transform::get))
+        .route("/:key/transform", put(transform::put))
+        .route("/:key/transform", delete(transform::delete))
         .route("/:key", put(update_metadata::put))
         .route("/:key", delete(delete_image::delete))
         .route("/", get(get_all::get))
@@ -30,9 +57,13 @@ pub fn api_route() -> Router {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ImageMetadata {
+    kind: MediaKind,
+    duration: Option<f64>,
     file_name: String,
     size_bytes: u64,
     taken_at: Option<i64>,
+    taken_at_local: Option<String>,
+    taken_at_offset: Option<i32>,
     #[serde(default, deserialize_with = "non_empty_location")]
     location: Option<Location>,
     camera_brand: Option<String>,
@@ -40,6 +71,36 @@ pub struct ImageMetadata {
     exposure_time: Option<String>,
     f_number: Option<String>,
     focal_length: Option<String>,
+    iso: Option<u32>,
+    lens_model: Option<String>,
+    flash: Option<String>,
+    white_balance: Option<String>,
+    altitude: Option<f64>,
+    gps_direction: Option<f64>,
+    width: Option<u32>,
+    height: Option<u32>,
+    aspect_ratio: Option<f64>,
+    blurhash: Option<String>,
+    dominant_color: Option<String>,
+    /// Appended to media urls, cached derivatives stay valid as long as it doesn't change.
+    version: u32,
+    title: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+}
+
+impl ImageMetadata {
+    pub fn remove_location(&mut self) {
+        self.location = None;
+        self.altitude = None;
+        self.gps_direction = None;
+    }
+
+    pub fn remove_camera(&mut self) {
+        self.camera_brand = None;
+        self.camera_model = None;
+        self.lens_model = None;
+    }
 }
 
 pub(super) fn non_empty_location<'de, D: Deserializer<'de>>(
@@ -57,11 +118,45 @@ pub struct Image {
     pub uploader: String,
     pub uploaded_at: u64,
     pub published_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processing: Option<processing::ImageJob>,
+    /// Overrides the setting of the uploader, only included for the image itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_metadata: Option<bool>,
 
     #[serde(flatten)]
     pub metadata: ImageMetadata,
 }
 
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MediaKind {
+    #[default]
+    Image,
+    Video,
+}
+
+impl TryFrom<String> for MediaKind {
+    type Error = String;
+
+    fn try_from(kind: String) -> Result<Self, Self::Error> {
+        match kind.as_str() {
+            "image" => Ok(MediaKind::Image),
+            "video" => Ok(MediaKind::Video),
+            _ => Err(format!("unknown media kind {kind}")),
+        }
+    }
+}
+
+impl From<MediaKind> for String {
+    fn from(kind: MediaKind) -> Self {
+        match kind {
+            MediaKind::Image => "image".into(),
+            MediaKind::Video => "video".into(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(super) struct Location {
@@ -77,6 +172,8 @@ impl Image {
             uploader: db_metadata.uploader,
             uploaded_at: db_metadata.uploaded_at,
             published_at: db_metadata.published_at,
+            processing: None,
+            strip_metadata: db_metadata.strip_metadata,
             metadata: db_metadata.metadata.into(),
         }
     }
@@ -84,9 +181,18 @@ impl Image {
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct DbImageMetadata {
+    #[serde(default)]
+    pub kind: MediaKind,
+    /// Seconds, only set for videos.
+    #[serde(default)]
+    pub duration: Option<f64>,
     pub file_name: String,
     pub size_bytes: u64,
     pub taken_at: Option<i64>,
+    #[serde(default)]
+    pub taken_at_local: Option<String>,
+    #[serde(default)]
+    pub taken_at_offset: Option<i32>,
     pub location_latitude: Option<String>,
     pub location_longitude: Option<String>,
     pub camera_brand: Option<String>,
@@ -94,14 +200,46 @@ pub struct DbImageMetadata {
     pub exposure_time: Option<String>,
     pub f_number: Option<String>,
     pub focal_length: Option<String>,
+    #[serde(default)]
+    pub iso: Option<u32>,
+    #[serde(default)]
+    pub lens_model: Option<String>,
+    #[serde(default)]
+    pub flash: Option<String>,
+    #[serde(default)]
+    pub white_balance: Option<String>,
+    #[serde(default)]
+    pub altitude: Option<f64>,
+    #[serde(default)]
+    pub gps_direction: Option<f64>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub blurhash: Option<String>,
+    #[serde(default)]
+    pub dominant_color: Option<String>,
+    /// Only changed by `bump_version`.
+    #[serde(default, skip_serializing)]
+    pub version: u32,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// JSON array
+    #[serde(default)]
+    pub keywords: Option<String>,
 }
 
 impl From<DbImageMetadata> for ImageMetadata {
     fn from(meta: DbImageMetadata) -> Self {
         ImageMetadata {
+            kind: meta.kind,
+            duration: meta.duration,
             file_name: meta.file_name,
             size_bytes: meta.size_bytes,
             taken_at: meta.taken_at,
+            taken_at_local: meta.taken_at_local,
+            taken_at_offset: meta.taken_at_offset,
             location: if let (Some(latitude), Some(longitude)) =
                 (meta.location_latitude, meta.location_longitude)
             {
@@ -117,6 +255,26 @@ impl From<DbImageMetadata> for ImageMetadata {
             exposure_time: meta.exposure_time,
             f_number: meta.f_number,
             focal_length: meta.focal_length,
+            iso: meta.iso,
+            lens_model: meta.lens_model,
+            flash: meta.flash,
+            white_balance: meta.white_balance,
+            altitude: meta.altitude,
+            gps_direction: meta.gps_direction,
+            width: meta.width,
+            height: meta.height,
+            aspect_ratio: match (meta.width, meta.height) {
+                (Some(width), Some(height)) if height > 0 => Some(width as f64 / height as f64),
+                _ => None,
+            },
+            blurhash: meta.blurhash,
+            dominant_color: meta.dominant_color,
+            version: meta.version,
+            title: meta.title,
+            keywords: meta
+                .keywords
+                .and_then(|k| serde_json::from_str(&k).ok())
+                .unwrap_or_default(),
         }
     }
 }
@@ -129,15 +287,29 @@ pub struct DbImage {
     pub uploaded_at: u64,
     #[serde(skip_serializing)]
     pub published_at: Option<u64>,
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub perceptual_hash: Option<i64>,
+    #[serde(default, skip_serializing)]
+    pub strip_metadata: Option<bool>,
 
     #[serde(flatten)]
     pub metadata: DbImageMetadata,
 }
 
+/// Where the original of an image is kept in the storage.
+pub fn original_path(key: &str, file_name: &str) -> String {
+    crate::storage::path(&[key, "original", file_name])
+}
+
+/// Images in the trash don't exist for anything but restoring them.
 pub fn image_exists(key: &str, conn: &Connection) -> anyhow::Result<bool> {
-    let result = conn.query_row("SELECT 1 FROM images WHERE key = ?1", params![key], |_| {
-        Ok(())
-    });
+    let result = conn.query_row(
+        "SELECT 1 FROM images WHERE key = ?1 AND deleted_at IS NULL",
+        params![key],
+        |_| Ok(()),
+    );
 
     if matches!(result, Err(rusqlite::Error::QueryReturnedNoRows)) {
         Ok(false)
@@ -149,9 +321,11 @@ pub fn image_exists(key: &str, conn: &Connection) -> anyhow::Result<bool> {
 }
 
 pub fn comment_exists(id: i64, conn: &Connection) -> anyhow::Result<bool> {
-    let result = conn.query_row("SELECT 1 FROM comments WHERE id = ?1", params![id], |_| {
-        Ok(())
-    });
+    let result = conn.query_row(
+        "SELECT 1 FROM comments WHERE id = ?1 AND deleted_at IS NULL",
+        params![id],
+        |_| Ok(()),
+    );
 
     if matches!(result, Err(rusqlite::Error::QueryReturnedNoRows)) {
         Ok(false)
@@ -167,9 +341,13 @@ pub fn insert(metadata: &DbImage, conn: &Connection) -> anyhow::Result<()> {
         "INSERT INTO images ( \
             key, \
             uploader, \
+            kind, \
+            duration, \
             file_name, \
             size_bytes, \
             taken_at, \
+            taken_at_local, \
+            taken_at_offset, \
             location_latitude, \
             location_longitude, \
             camera_brand, \
@@ -178,13 +356,30 @@ pub fn insert(metadata: &DbImage, conn: &Connection) -> anyhow::Result<()> {
             f_number, \
             focal_length, \
             description, \
-            uploaded_at \
+            uploaded_at, \
+            content_hash, \
+            iso, \
+            lens_model, \
+            flash, \
+            white_balance, \
+            altitude, \
+            gps_direction, \
+            width, \
+            height, \
+            blurhash, \
+            dominant_color, \
+            title, \
+            keywords \
         ) VALUES ( \
             :key, \
             :uploader, \
+            :kind, \
+            :duration, \
             :file_name, \
             :size_bytes, \
             :taken_at, \
+            :taken_at_local, \
+            :taken_at_offset, \
             :location_latitude, \
             :location_longitude, \
             :camera_brand, \
@@ -193,7 +388,20 @@ pub fn insert(metadata: &DbImage, conn: &Connection) -> anyhow::Result<()> {
             :f_number, \
             :focal_length, \
             :description, \
-            :uploaded_at \
+            :uploaded_at, \
+            :content_hash, \
+            :iso, \
+            :lens_model, \
+            :flash, \
+            :white_balance, \
+            :altitude, \
+            :gps_direction, \
+            :width, \
+            :height, \
+            :blurhash, \
+            :dominant_color, \
+            :title, \
+            :keywords \
         )",
         to_params_named(metadata).unwrap().to_slice().as_slice(),
     )?;
@@ -201,9 +409,20 @@ pub fn insert(metadata: &DbImage, conn: &Connection) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Called whenever the derivatives were written again.
+pub fn bump_version(key: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE images SET version = version + 1 WHERE key = ?1",
+        params![key],
+    )
+    .context("Failed to update image version")?;
+
+    Ok(())
+}
+
 pub fn is_owner(image_key: &str, user: &str, conn: &Connection) -> Result<bool, Error> {
     let result = conn.query_row(
-        "SELECT uploader FROM images WHERE key = ?1",
+        "SELECT uploader FROM images WHERE key = ?1 AND deleted_at IS NULL",
         params![image_key],
         |row| row.get::<_, String>(0),
     );
@@ -218,15 +437,31 @@ pub fn is_owner(image_key: &str, user: &str, conn: &Connection) -> Result<bool,
 }
 
 pub fn select_image(key: &str, conn: &Connection) -> anyhow::Result<Option<DbImage>> {
+    select(key, false, conn)
+}
+
+/// Also finds images in the trash, their derivatives are still generated while they are there.
+pub fn select_image_including_trash(
+    key: &str,
+    conn: &Connection,
+) -> anyhow::Result<Option<DbImage>> {
+    select(key, true, conn)
+}
+
+fn select(key: &str, including_trash: bool, conn: &Connection) -> anyhow::Result<Option<DbImage>> {
     Ok(conn
         .query_row(
             "SELECT \
             key, \
             uploader, \
             uploaded_at, \
+            kind, \
+            duration, \
             file_name, \
             size_bytes, \
             taken_at, \
+            taken_at_local, \
+            taken_at_offset, \
             location_latitude, \
             location_longitude, \
             camera_brand, \
@@ -234,10 +469,24 @@ pub fn select_image(key: &str, conn: &Connection) -> anyhow::Result<Option<DbIma
             exposure_time, \
             f_number, \
             focal_length, \
-            description \
+            iso, \
+            lens_model, \
+            flash, \
+            white_balance, \
+            altitude, \
+            gps_direction, \
+            width, \
+            height, \
+            blurhash, \
+            dominant_color, \
+            version, \
+            title, \
+            keywords, \
+            description, \
+            strip_metadata \
         FROM images \
-        WHERE key = ?1",
-            params![key],
+        WHERE key = ?1 AND (deleted_at IS NULL OR ?2)",
+            params![key, including_trash],
             |row| Ok(from_row::<DbImage>(row).unwrap()),
         )
         .optional()?)
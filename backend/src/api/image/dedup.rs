@@ -0,0 +1,273 @@
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_rusqlite::from_row;
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Key of an image with the same content uploaded by `uploader` before.
+pub fn find_duplicate(
+    uploader: &str,
+    content_hash: &str,
+    conn: &Connection,
+) -> anyhow::Result<Option<String>> {
+    conn.query_row(
+        "SELECT key FROM images \
+        WHERE uploader = ?1 AND content_hash = ?2 AND deleted_at IS NULL \
+        ORDER BY uploaded_at \
+        LIMIT 1",
+        params![uploader, content_hash],
+        |row| Ok(from_row::<String>(row).unwrap()),
+    )
+    .optional()
+    .context("Failed to query duplicate image")
+}
+
+pub fn set_content_hash(key: &str, content_hash: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE images SET content_hash = ?1 WHERE key = ?2",
+        params![content_hash, key],
+    )
+    .context("Failed to update content hash")?;
+
+    Ok(())
+}
+
+/// Images of the same uploader sharing a content hash, the oldest upload first in every group.
+/// Copies of other users are left alone, they are theirs.
+pub fn duplicate_groups(conn: &Connection) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT content_hash, key, uploader FROM images \
+            WHERE deleted_at IS NULL AND (content_hash, uploader) IN ( \
+                SELECT content_hash, uploader FROM images \
+                WHERE content_hash IS NOT NULL AND deleted_at IS NULL \
+                GROUP BY content_hash, uploader \
+                HAVING COUNT(*) > 1 \
+            ) \
+            ORDER BY content_hash, uploader, uploaded_at, key",
+        )
+        .context("Failed to prepare statement for duplicates query")?;
+
+    let rows = stmt
+        .query_map(params![], |row| {
+            Ok(from_row::<(String, String, String)>(row).unwrap())
+        })
+        .context("Failed to query duplicates")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect duplicates")?;
+
+    let mut groups: BTreeMap<(String, String), Vec<(String, String)>> = BTreeMap::new();
+    for (hash, key, uploader) in rows {
+        groups
+            .entry((hash, uploader.clone()))
+            .or_default()
+            .push((key, uploader));
+    }
+
+    Ok(groups.into_values().collect())
+}
+
+/// Merges every image of a group from `duplicate_groups` into its oldest one.
+pub fn merge_group(group: &[(String, String)], now: u64, conn: &Connection) -> anyhow::Result<()> {
+    if let Some(((keep, _), duplicates)) = group.split_first() {
+        for (duplicate, _) in duplicates {
+            merge(keep, duplicate, now, conn)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Points everything referencing `duplicate` to `keep` and deletes the duplicate image, its files
+/// are left to the reaper.
+pub fn merge(keep: &str, duplicate: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    // An album containing both would show the same photo twice
+    conn.execute(
+        "DELETE FROM album_image_associations \
+        WHERE image_key = ?1 AND album_key IN ( \
+            SELECT album_key FROM album_image_associations WHERE image_key = ?2 \
+        )",
+        params![duplicate, keep],
+    )
+    .context("Failed to remove duplicate album associations")?;
+
+    for (statement, what) in [
+        (
+            "UPDATE album_image_associations SET image_key = ?1 WHERE image_key = ?2",
+            "album associations",
+        ),
+        (
+            "UPDATE albums SET cover_key = ?1 WHERE cover_key = ?2",
+            "album covers",
+        ),
+        (
+            "UPDATE comments SET image_key = ?1 WHERE image_key = ?2",
+            "comments",
+        ),
+        (
+            "UPDATE users SET avatar_key = ?1 WHERE avatar_key = ?2",
+            "avatars",
+        ),
+        (
+            "UPDATE users SET banner_key = ?1 WHERE banner_key = ?2",
+            "banners",
+        ),
+    ] {
+        conn.execute(statement, params![keep, duplicate])
+            .with_context(|| format!("Failed to update {what}"))?;
+    }
+
+    super::tombstone::delete(duplicate, now, conn).context("Failed to delete duplicate image")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::album::InsertAlbum;
+    use crate::util::test::{insert_album, insert_comment, insert_image, insert_user};
+    use crate::AppState;
+
+    #[tokio::test]
+    async fn merge_copies_of_each_uploader() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let first = insert_user("first", conn);
+                let second = insert_user("second", conn);
+                let original = insert_image(&first, conn);
+                let copy = insert_image(&second, conn);
+                let second_copy = insert_image(&second, conn);
+
+                for (i, key) in [&original, &copy, &second_copy].into_iter().enumerate() {
+                    set_content_hash(key, "hash", conn).unwrap();
+                    conn.execute(
+                        "UPDATE images SET uploaded_at = ?1 WHERE key = ?2",
+                        params![i, key],
+                    )
+                    .unwrap();
+                }
+
+                // The oldest copy belongs to someone else, the second user's copies still merge
+                let groups = duplicate_groups(conn).unwrap();
+                assert_eq!(
+                    groups,
+                    vec![vec![
+                        (copy.clone(), second.clone()),
+                        (second_copy.clone(), second.clone())
+                    ]]
+                );
+                for group in &groups {
+                    merge_group(group, 0, conn).unwrap();
+                }
+
+                let images_of = |uploader: &str| -> u64 {
+                    conn.query_row(
+                        "SELECT COUNT(*) FROM images WHERE uploader = ?1 AND deleted_at IS NULL",
+                        params![uploader],
+                        |row| row.get(0),
+                    )
+                    .unwrap()
+                };
+                assert_eq!(images_of(&first), 1);
+                assert_eq!(images_of(&second), 1);
+                assert!(crate::api::image::image_exists(&copy, conn).unwrap());
+                assert!(duplicate_groups(conn).unwrap().is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn merge_rewrites_references() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let keep = insert_image(&user, conn);
+                let duplicate = insert_image(&user, conn);
+                let other = insert_image(&user, conn);
+
+                for key in [&keep, &duplicate] {
+                    set_content_hash(key, "hash", conn).unwrap();
+                }
+                conn.execute(
+                    "UPDATE images SET uploaded_at = 1 WHERE key = ?1",
+                    params![duplicate],
+                )
+                .unwrap();
+                assert_eq!(
+                    duplicate_groups(conn).unwrap(),
+                    vec![vec![
+                        (keep.clone(), user.clone()),
+                        (duplicate.clone(), user.clone())
+                    ]]
+                );
+
+                let both = insert_album(
+                    InsertAlbum {
+                        cover_key: &duplicate,
+                        author: &user,
+                        image_keys: &[keep.clone(), duplicate.clone()],
+                        ..Default::default()
+                    },
+                    conn,
+                );
+                let single = insert_album(
+                    InsertAlbum {
+                        cover_key: &other,
+                        author: &user,
+                        image_keys: &[duplicate.clone(), other.clone()],
+                        ..Default::default()
+                    },
+                    conn,
+                );
+                insert_comment(&user, &duplicate, &single, "foo", conn);
+
+                merge(&keep, &duplicate, 0, conn).unwrap();
+
+                let images_of = |album: &str| {
+                    let mut stmt = conn
+                        .prepare(
+                            "SELECT image_key FROM album_image_associations \
+                            WHERE album_key = ?1 ORDER BY idx",
+                        )
+                        .unwrap();
+                    stmt.query_map(params![album], |row| row.get::<_, String>(0))
+                        .unwrap()
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap()
+                };
+
+                assert_eq!(images_of(&both), vec![keep.clone()]);
+                assert_eq!(images_of(&single), vec![keep.clone(), other.clone()]);
+
+                let cover: String = conn
+                    .query_row(
+                        "SELECT cover_key FROM albums WHERE key = ?1",
+                        params![both],
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+                assert_eq!(cover, keep);
+
+                let comment_image: String = conn
+                    .query_row("SELECT image_key FROM comments", params![], |row| {
+                        row.get(0)
+                    })
+                    .unwrap();
+                assert_eq!(comment_image, keep);
+
+                assert!(!crate::api::image::image_exists(&duplicate, conn).unwrap());
+                assert!(duplicate_groups(conn).unwrap().is_empty());
+            })
+            .await;
+    }
+}
@@ -1,17 +1,133 @@
 use anyhow::Context;
-use axum::{extract::Path, Extension, Json};
-use rusqlite::params;
-use std::fs;
+use axum::{
+    extract::{Path, Query},
+    Extension, Json,
+};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_rusqlite::from_row;
 use tracing::*;
 
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use crate::api::auth::Authorize;
 use crate::api::error::Error;
+use crate::api::trash;
 use crate::AppState;
 
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DeleteOptions {
+    /// Cover albums with another of their images and remove the image from profiles instead of
+    /// reporting where it is used.
+    #[serde(default)]
+    reassign: bool,
+}
+
+/// Albums and profiles which show an image and keep it from being deleted.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageUsage {
+    pub album_covers: Vec<AlbumCover>,
+    pub profiles: Vec<ProfileImage>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlbumCover {
+    pub key: String,
+    pub title: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileImage {
+    pub username: String,
+    /// Either `avatar` or `banner`.
+    pub field: String,
+}
+
+impl ImageUsage {
+    pub fn is_empty(&self) -> bool {
+        self.album_covers.is_empty() && self.profiles.is_empty()
+    }
+}
+
+fn usage(key: &str, conn: &Connection) -> anyhow::Result<ImageUsage> {
+    let mut query = conn
+        .prepare(
+            "SELECT key, title FROM albums \
+            WHERE cover_key = ?1 AND deleted_at IS NULL \
+            ORDER BY key",
+        )
+        .context("Failed to prepare statement for album cover query")?;
+    let album_covers = query
+        .query_map(params![key], |row| Ok(from_row::<AlbumCover>(row).unwrap()))
+        .context("Failed to query album covers")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect album covers")?;
+
+    let mut query = conn
+        .prepare(
+            "SELECT username, 'avatar' AS field FROM users WHERE avatar_key = ?1 \
+            UNION ALL \
+            SELECT username, 'banner' AS field FROM users WHERE banner_key = ?1 \
+            ORDER BY username, field",
+        )
+        .context("Failed to prepare statement for profile image query")?;
+    let profiles = query
+        .query_map(params![key], |row| {
+            Ok(from_row::<ProfileImage>(row).unwrap())
+        })
+        .context("Failed to query profile images")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect profile images")?;
+
+    Ok(ImageUsage {
+        album_covers,
+        profiles,
+    })
+}
+
+/// Covers albums with the image following the deleted one, or the first image if it was the last,
+/// and clears avatars and banners. Albums without another image keep their cover.
+fn reassign(key: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE albums SET cover_key = ( \
+            SELECT a.image_key FROM album_image_associations a \
+            INNER JOIN images i ON i.key = a.image_key \
+            WHERE a.album_key = albums.key AND a.image_key != ?1 AND i.deleted_at IS NULL \
+            ORDER BY a.idx > COALESCE(( \
+                SELECT MIN(idx) FROM album_image_associations \
+                WHERE album_key = albums.key AND image_key = ?1 \
+            ), -1) DESC, a.idx \
+            LIMIT 1 \
+        ) \
+        WHERE cover_key = ?1 AND EXISTS ( \
+            SELECT 1 FROM album_image_associations a \
+            INNER JOIN images i ON i.key = a.image_key \
+            WHERE a.album_key = albums.key AND a.image_key != ?1 AND i.deleted_at IS NULL \
+        )",
+        params![key],
+    )
+    .context("Failed to reassign album covers")?;
+
+    conn.execute(
+        "UPDATE users SET avatar_key = NULL WHERE avatar_key = ?1",
+        params![key],
+    )
+    .context("Failed to clear avatars")?;
+    conn.execute(
+        "UPDATE users SET banner_key = NULL WHERE banner_key = ?1",
+        params![key],
+    )
+    .context("Failed to clear banners")?;
+
+    Ok(())
+}
+
 pub(super) async fn delete(
     Path(image_key): Path<String>,
+    Query(options): Query<DeleteOptions>,
     Authorize(user): Authorize,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Result<Json<()>, Error> {
@@ -19,26 +135,182 @@ pub(super) async fn delete(
     // something bad just in case.
     blob_uuid::to_uuid(&image_key).map_err(|_| Error::NotFound)?;
 
-    let mut image_path = state.data_path.clone();
-    image_path.push(&image_key);
-
+    info!("Moving image {image_key} to the trash");
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
     state
         .db
         .call(move |conn| {
-            if super::is_owner(&image_key, &user, conn)? {
-                info!("Deleting image {image_key}");
+            let tx = conn.transaction().context("Failed to create transaction")?;
+            if !super::is_owner(&image_key, &user, &tx)? {
+                return Err(Error::Unathorized);
+            }
 
-                // TODO This could use await but not within the db closure.
-                // Please be careful when messing with this. :)
-                fs::remove_dir_all(image_path).context("Failed to remove image directory")?;
+            if options.reassign {
+                reassign(&image_key, &tx)?;
+            }
+            let usage = usage(&image_key, &tx)?;
+            if !usage.is_empty() {
+                return Err(Error::ImageInUse(usage));
+            }
 
-                conn.execute("DELETE FROM images WHERE key = ?1", params![image_key])
-                    .context("Failed to delete image")?;
+            trash::add_image(&image_key, now, &tx)?;
+            tx.commit().context("Failed to commit transaction")?;
 
-                Ok(Json(()))
-            } else {
-                Err(Error::Unathorized)
-            }
+            Ok(Json(()))
         })
         .await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::album::InsertAlbum;
+    use crate::util::test::{insert_album, insert_image, insert_user};
+    use assert_matches::assert_matches;
+
+    #[tokio::test]
+    async fn delete_image_in_use() {
+        let state = AppState::in_memory_db().await;
+
+        let (user, image, album) = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let image = insert_image(&user, conn);
+                let album = insert_album(
+                    InsertAlbum {
+                        title: "Holiday",
+                        cover_key: &image,
+                        image_keys: &[image.clone()],
+                        author: &user,
+                        ..Default::default()
+                    },
+                    conn,
+                );
+                conn.execute(
+                    "UPDATE users SET banner_key = ?1 WHERE username = ?2",
+                    params![image, user],
+                )
+                .unwrap();
+
+                (user, image, album)
+            })
+            .await;
+
+        let result = delete(
+            Path(image.clone()),
+            Query(DeleteOptions::default()),
+            Authorize(user.clone()),
+            Extension(state.clone()),
+        )
+        .await;
+
+        let cover = AlbumCover {
+            key: album,
+            title: "Holiday".into(),
+        };
+        assert_matches!(result, Err(Error::ImageInUse(usage)) => {
+            assert_eq!(
+                usage,
+                ImageUsage {
+                    album_covers: vec![cover],
+                    profiles: vec![ProfileImage {
+                        username: user.clone(),
+                        field: "banner".into(),
+                    }],
+                }
+            );
+        });
+
+        // The album has no other image to be covered with
+        let result = delete(
+            Path(image.clone()),
+            Query(DeleteOptions { reassign: true }),
+            Authorize(user),
+            Extension(state.clone()),
+        )
+        .await;
+
+        assert_matches!(result, Err(Error::ImageInUse(usage)) => {
+            assert_eq!(usage.album_covers.len(), 1);
+            assert!(usage.profiles.is_empty());
+        });
+        assert!(state
+            .db
+            .call(move |conn| super::super::image_exists(&image, conn))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn delete_image_reassign() {
+        let state = AppState::in_memory_db().await;
+
+        let (user, images, album) = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let images: Vec<_> = (0..3).map(|_| insert_image(&user, conn)).collect();
+                let album = insert_album(
+                    InsertAlbum {
+                        cover_key: &images[1],
+                        image_keys: &images,
+                        author: &user,
+                        ..Default::default()
+                    },
+                    conn,
+                );
+                conn.execute(
+                    "UPDATE users SET avatar_key = ?1 WHERE username = ?2",
+                    params![images[1], user],
+                )
+                .unwrap();
+
+                (user, images, album)
+            })
+            .await;
+
+        let result = delete(
+            Path(images[1].clone()),
+            Query(DeleteOptions::default()),
+            Authorize(user.clone()),
+            Extension(state.clone()),
+        )
+        .await;
+        assert_matches!(result, Err(Error::ImageInUse(_)));
+
+        let result = delete(
+            Path(images[1].clone()),
+            Query(DeleteOptions { reassign: true }),
+            Authorize(user.clone()),
+            Extension(state.clone()),
+        )
+        .await;
+        assert_matches!(result, Ok(_));
+
+        let (cover, avatar) = state
+            .db
+            .call(move |conn| {
+                let cover: String = conn
+                    .query_row(
+                        "SELECT cover_key FROM albums WHERE key = ?1",
+                        params![album],
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+                let avatar: Option<String> = conn
+                    .query_row(
+                        "SELECT avatar_key FROM users WHERE username = ?1",
+                        params![user],
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+
+                (cover, avatar)
+            })
+            .await;
+
+        assert_eq!(cover, images[2]);
+        assert_eq!(avatar, None);
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_rusqlite::from_row;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct VariantDimensions {
+    pub file_name: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Stores the oriented dimensions of the original and replaces those of its derivatives.
+pub fn store(
+    key: &str,
+    width: u32,
+    height: u32,
+    variants: &[VariantDimensions],
+    conn: &Connection,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE images SET width = ?1, height = ?2 WHERE key = ?3",
+        params![width, height, key],
+    )
+    .context("Failed to update image dimensions")?;
+
+    for variant in variants {
+        conn.execute(
+            "INSERT OR REPLACE INTO image_variants (image_key, file_name, width, height) \
+            VALUES (?1, ?2, ?3, ?4)",
+            params![key, variant.file_name, variant.width, variant.height],
+        )
+        .context("Failed to insert variant dimensions")?;
+    }
+
+    Ok(())
+}
+
+pub fn select_variants(key: &str, conn: &Connection) -> anyhow::Result<Vec<VariantDimensions>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT file_name, width, height FROM image_variants \
+            WHERE image_key = ?1",
+        )
+        .context("Failed to prepare statement for variants query")?;
+
+    let variants = stmt
+        .query_map(params![key], |row| {
+            Ok(from_row::<VariantDimensions>(row).unwrap())
+        })
+        .context("Failed to query variants")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect variants")?;
+
+    Ok(variants)
+}
+
+/// Keys of images which were processed before dimensions were stored. Videos are left out since
+/// their dimensions are read from the container when uploading.
+pub fn missing(conn: &Connection) -> anyhow::Result<Vec<(String, String)>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT key, file_name FROM images i \
+            WHERE i.kind = 'image' \
+            AND NOT EXISTS (SELECT 1 FROM image_variants v WHERE v.image_key = i.key) \
+            AND NOT EXISTS (SELECT 1 FROM image_jobs j WHERE j.image_key = i.key)",
+        )
+        .context("Failed to prepare statement for images query")?;
+
+    let images = stmt
+        .query_map(params![], |row| {
+            Ok(from_row::<(String, String)>(row).unwrap())
+        })
+        .context("Failed to query images")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect images")?;
+
+    Ok(images)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::{insert_image, insert_user};
+    use crate::AppState;
+
+    #[tokio::test]
+    async fn store_replaces_variants() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let key = insert_image(&user, conn);
+                assert_eq!(missing(conn).unwrap(), vec![(key.clone(), String::new())]);
+
+                let tiny = |width| VariantDimensions {
+                    file_name: "tiny.jpg".into(),
+                    width,
+                    height: 200,
+                };
+
+                store(&key, 800, 600, &[tiny(300)], conn).unwrap();
+                store(&key, 800, 600, &[tiny(360)], conn).unwrap();
+
+                assert_eq!(select_variants(&key, conn).unwrap(), vec![tiny(360)]);
+                assert!(missing(conn).unwrap().is_empty());
+
+                let image = crate::api::image::select_image(&key, conn)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(image.metadata.width, Some(800));
+                assert_eq!(image.metadata.height, Some(600));
+            })
+            .await;
+    }
+}
@@ -0,0 +1,139 @@
+use image::{codecs::webp::WebPEncoder, codecs::webp::WebPQuality, DynamicImage};
+
+use std::io::Cursor;
+
+use crate::api::error::Error;
+
+/// Encodings every derivative is stored in, the first one is what the frontend requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jpeg,
+    WebP,
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+pub const FORMATS: &[Format] = &[
+    Format::Jpeg,
+    Format::WebP,
+    #[cfg(feature = "avif")]
+    Format::Avif,
+];
+
+/// ravif trades a lot of encoding time for little size at lower speeds.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::WebP => "webp",
+            #[cfg(feature = "avif")]
+            Format::Avif => "avif",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::WebP => "image/webp",
+            #[cfg(feature = "avif")]
+            Format::Avif => "image/avif",
+        }
+    }
+
+    pub fn encode(&self, image: &DynamicImage, quality: u8) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+
+        match self {
+            Format::Jpeg => {
+                let mut cursor = Cursor::new(&mut buffer);
+                image.write_to(&mut cursor, image::ImageOutputFormat::Jpeg(quality))?;
+            }
+            Format::WebP => {
+                let encoder =
+                    WebPEncoder::new_with_quality(&mut buffer, WebPQuality::lossy(quality));
+                if image.color().has_alpha() {
+                    let image = image.to_rgba8();
+                    encoder.encode(
+                        &image,
+                        image.width(),
+                        image.height(),
+                        image::ColorType::Rgba8,
+                    )?;
+                } else {
+                    let image = image.to_rgb8();
+                    encoder.encode(
+                        &image,
+                        image.width(),
+                        image.height(),
+                        image::ColorType::Rgb8,
+                    )?;
+                }
+            }
+            #[cfg(feature = "avif")]
+            Format::Avif => {
+                let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                    &mut buffer,
+                    AVIF_SPEED,
+                    quality,
+                );
+                image.write_with_encoder(encoder)?;
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    /// Picks the smallest format the client accepts explicitly, wildcards are ignored since
+    /// browsers send them even if they can't display the newer formats.
+    pub fn negotiate(accept: &str) -> Option<Format> {
+        FORMATS
+            .iter()
+            .rev()
+            .filter(|f| **f != Format::Jpeg)
+            .find(|f| accepts(accept, f.mime()))
+            .copied()
+    }
+}
+
+fn accepts(accept: &str, mime: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';').map(str::trim);
+
+        let matches = parts
+            .next()
+            .map(|m| m.eq_ignore_ascii_case(mime))
+            .unwrap_or(false);
+
+        let quality = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        matches && quality > 0.0
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("image/webp,*/*", Some(Format::WebP))]
+    #[test_case("image/webp;q=0, image/png", None)]
+    #[test_case("image/*,*/*;q=0.8", None)]
+    #[test_case("", None)]
+    #[test_case("IMAGE/WEBP;q=0.5", Some(Format::WebP))]
+    fn negotiate_webp(accept: &str, expected: Option<Format>) {
+        assert_eq!(Format::negotiate(accept), expected);
+    }
+
+    #[cfg(feature = "avif")]
+    #[test_case("image/avif,image/webp,*/*", Some(Format::Avif))]
+    #[test_case("image/avif;q=0,image/webp", Some(Format::WebP))]
+    fn negotiate_avif(accept: &str, expected: Option<Format>) {
+        assert_eq!(Format::negotiate(accept), expected);
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use serde_rusqlite::from_row;
+use tracing::*;
+
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use super::processing::{self, JobState};
+use super::size::ImageConfig;
+use super::{original_path, tombstone, DbImage, MediaKind};
+use crate::storage::{self, Storage};
+
+/// Directories changed more recently might belong to an upload which is still being stored.
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(3600);
+
+/// Where the images in the database and the directories in the storage disagree.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Images whose original is gone, nothing can be done about them.
+    pub missing_originals: Vec<String>,
+    /// Images which are not being processed but lack some of their derivatives.
+    pub missing_derivatives: Vec<String>,
+    /// Deleted images whose files were not removed yet.
+    pub tombstones: Vec<String>,
+    /// Directories which belong to no image.
+    pub orphans: Vec<String>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        *self == Report::default()
+    }
+}
+
+/// Images with whether they are waiting for or being processed right now.
+fn select_images(conn: &Connection) -> anyhow::Result<Vec<(DbImage, bool)>> {
+    let mut query = conn
+        .prepare("SELECT * FROM images ORDER BY key")
+        .context("Failed to prepare statement for images query")?;
+
+    let images = query
+        .query_map(params![], |row| Ok(from_row::<DbImage>(row).unwrap()))
+        .context("Failed to query images")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect images")?;
+
+    images
+        .into_iter()
+        .map(|image| {
+            let job = processing::select_job(&image.key, conn)?;
+            let in_progress = job.map_or(false, |j| j.state != JobState::Failed);
+
+            Ok((image, in_progress))
+        })
+        .collect()
+}
+
+pub async fn check(
+    storage: &dyn Storage,
+    db: &tokio_rusqlite::Connection,
+    config: &ImageConfig,
+    now: SystemTime,
+) -> anyhow::Result<Report> {
+    let (images, tombstones) = db
+        .call(|conn| Ok::<_, anyhow::Error>((select_images(conn)?, tombstone::pending(conn)?)))
+        .await?;
+
+    // Unfinished uploads and scratch files live in directories starting with a dot
+    let mut directories: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+    for path in storage.list("").await? {
+        if let Some((key, _)) = path.split_once('/') {
+            if !key.starts_with('.') {
+                directories.entry(key.to_owned()).or_default().insert(path);
+            }
+        }
+    }
+
+    let mut report = Report::default();
+
+    for (image, in_progress) in &images {
+        let files = directories.remove(&image.key).unwrap_or_default();
+
+        if !files.contains(&original_path(&image.key, &image.metadata.file_name)) {
+            report.missing_originals.push(image.key.clone());
+            continue;
+        }
+
+        // Without a poster command videos have no derivatives at all
+        if *in_progress
+            || (image.metadata.kind == MediaKind::Video && config.poster_command.is_none())
+        {
+            continue;
+        }
+
+        if config
+            .variants()
+            .iter()
+            .any(|v| !files.contains(&storage::path(&[&image.key, &v.file_name()])))
+        {
+            report.missing_derivatives.push(image.key.clone());
+        }
+    }
+
+    for key in &tombstones {
+        directories.remove(key);
+    }
+    report.tombstones = tombstones;
+
+    for (key, files) in directories {
+        let mut newest = SystemTime::UNIX_EPOCH;
+        for path in &files {
+            if let Some(info) = storage.info(path).await? {
+                newest = newest.max(info.modified);
+            }
+        }
+
+        if now.duration_since(newest).unwrap_or_default() >= ORPHAN_GRACE_PERIOD {
+            report.orphans.push(key);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Queues the images with missing derivatives for processing, which happens once the server
+/// runs, and removes the files of deleted images and orphaned directories.
+pub async fn repair(
+    report: &Report,
+    storage: &dyn Storage,
+    db: &tokio_rusqlite::Connection,
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let keys = report.missing_derivatives.clone();
+    let now = now.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    db.call(move |conn| {
+        for key in keys {
+            processing::requeue_job(&key, now, conn)?;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await?;
+
+    tombstone::reap(storage, db).await?;
+
+    for key in &report.orphans {
+        info!("Removing orphaned directory {key}");
+        storage.remove_all(key).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::{insert_image, insert_user};
+    use crate::AppState;
+
+    #[tokio::test]
+    async fn report_and_repair() {
+        let state = AppState::in_memory_db().await;
+        let config = ImageConfig::default();
+
+        let (complete, incomplete, missing, deleted) = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let keys: Vec<_> = (0..4).map(|_| insert_image(&user, conn)).collect();
+                conn.execute("UPDATE images SET file_name = 'photo.jpg'", params![])
+                    .unwrap();
+                tombstone::delete(&keys[3], 0, conn).unwrap();
+
+                (
+                    keys[0].clone(),
+                    keys[1].clone(),
+                    keys[2].clone(),
+                    keys[3].clone(),
+                )
+            })
+            .await;
+
+        let storage = &*state.storage;
+        for key in [&*complete, &*incomplete, &*deleted, "orphan"] {
+            storage
+                .write(&original_path(key, "photo.jpg"), b"original".to_vec())
+                .await
+                .unwrap();
+        }
+        for variant in config.variants() {
+            let path = storage::path(&[&complete, &variant.file_name()]);
+            storage.write(&path, b"variant".to_vec()).await.unwrap();
+        }
+        storage
+            .write(".uploads/unfinished", b"upload".to_vec())
+            .await
+            .unwrap();
+
+        // The orphan could still be an upload in progress
+        let report = check(storage, &state.db, &config, SystemTime::now())
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            Report {
+                missing_originals: vec![missing.clone()],
+                missing_derivatives: vec![incomplete.clone()],
+                tombstones: vec![deleted.clone()],
+                orphans: vec![],
+            }
+        );
+
+        let later = SystemTime::now() + ORPHAN_GRACE_PERIOD;
+        let report = check(storage, &state.db, &config, later).await.unwrap();
+        assert_eq!(report.orphans, vec!["orphan"]);
+
+        repair(&report, storage, &state.db, later).await.unwrap();
+
+        let report = check(storage, &state.db, &config, later).await.unwrap();
+        assert_eq!(
+            report,
+            Report {
+                missing_originals: vec![missing],
+                ..Default::default()
+            }
+        );
+        assert!(storage.list(&deleted).await.unwrap().is_empty());
+        assert!(storage.info(".uploads/unfinished").await.unwrap().is_some());
+    }
+}
@@ -31,7 +31,7 @@ pub(super) async fn get(
         .db
         .call(move |conn| {
             let mut query = conn
-                .prepare("SELECT * FROM images WHERE uploader=?")
+                .prepare("SELECT * FROM images WHERE uploader=? AND deleted_at IS NULL")
                 .context("Failed to prepare statement for images query")?;
 
             let images = query
@@ -60,7 +60,11 @@ pub fn get_albums_containing_image(
     conn: &rusqlite::Connection,
 ) -> anyhow::Result<Vec<String>> {
     let mut query = conn
-        .prepare("SELECT album_key FROM album_image_associations WHERE image_key=?")
+        .prepare(
+            "SELECT aia.album_key FROM album_image_associations aia \
+            INNER JOIN albums a ON a.key = aia.album_key \
+            WHERE aia.image_key=? AND a.deleted_at IS NULL",
+        )
         .context("Failed to prepare statement for image albums query")?;
 
     let albums = query
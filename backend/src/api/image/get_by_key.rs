@@ -3,24 +3,99 @@ use axum::{extract::Path, Extension, Json};
 
 use std::sync::Arc;
 
-use super::Image;
+use super::{privacy, processing, Image};
 use crate::{api::auth::Authorize, api::error::Error, AppState};
 
+/// Location and camera fields are left out for others if the image is stripped.
 pub(super) async fn get(
     Path(key): Path<String>,
-    Authorize(_): Authorize,
+    Authorize(user): Authorize,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Result<Json<Image>, Error> {
     let ckey = key.clone();
     let result = state
         .db
-        .call(move |conn| super::select_image(&ckey, conn))
+        .call(move |conn| {
+            let image = super::select_image(&ckey, conn)?;
+            let privacy = privacy::select_privacy(&ckey, conn)?;
+            let job = processing::select_job(&ckey, conn)?;
+
+            Ok::<_, anyhow::Error>(
+                image
+                    .zip(privacy)
+                    .map(|(image, privacy)| (image, privacy, job)),
+            )
+        })
         .await
         .context("Failed to query image metadata")?;
 
-    if let Some(image_metadata) = result {
-        Ok(Json(Image::from_db(image_metadata)))
+    if let Some((image_metadata, privacy, job)) = result {
+        let mut image = Image::from_db(image_metadata);
+        image.processing = job;
+
+        if privacy.strips_for(Some(user.as_str())) {
+            image.metadata.remove_location();
+            image.metadata.remove_camera();
+        }
+
+        Ok(Json(image))
     } else {
         Err(Error::NotFound)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::{insert_image, insert_user};
+    use assert_matches::assert_matches;
+
+    #[tokio::test]
+    async fn strip_metadata_for_others() {
+        let state = AppState::in_memory_db().await;
+
+        let key = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                insert_user("friend", conn);
+                let key = insert_image(&user, conn);
+                conn.execute(
+                    "UPDATE images SET \
+                        location_latitude = '52.5', \
+                        location_longitude = '13.4', \
+                        lens_model = 'Lens' \
+                    WHERE key = ?1",
+                    rusqlite::params![key],
+                )
+                .unwrap();
+                conn.execute(
+                    "UPDATE users SET strip_metadata = 1 WHERE username = ?1",
+                    rusqlite::params![user],
+                )
+                .unwrap();
+
+                key
+            })
+            .await;
+
+        let result = get(
+            Path(key.clone()),
+            Authorize("friend".into()),
+            Extension(state.clone()),
+        )
+        .await;
+        assert_matches!(result, Ok(Json(image)) => {
+            let image = serde_json::to_value(&image).unwrap();
+            assert!(image["location"].is_null());
+            assert!(image["lensModel"].is_null());
+        });
+
+        let result = get(Path(key), Authorize("test".into()), Extension(state)).await;
+        assert_matches!(result, Ok(Json(image)) => {
+            let image = serde_json::to_value(&image).unwrap();
+            assert!(!image["location"].is_null());
+            assert_eq!(image["lensModel"], "Lens");
+        });
+    }
+}
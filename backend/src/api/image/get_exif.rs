@@ -0,0 +1,75 @@
+use anyhow::Context;
+use axum::{extract::Path, Extension, Json};
+use serde::Serialize;
+
+use std::sync::Arc;
+
+use super::upload::read_exif;
+use super::{privacy, xmp};
+use crate::{api::auth::Authorize, api::error::Error, AppState};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExifField {
+    tag: String,
+    ifd: String,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RawMetadata {
+    exif: Vec<ExifField>,
+    xmp: Option<String>,
+}
+
+/// Every EXIF tag and the XMP packet of the stored original. Location and camera tags as well as
+/// the XMP packet are left out for others if the image is stripped.
+pub(super) async fn get(
+    Path(key): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<RawMetadata>, Error> {
+    let ckey = key.clone();
+    let (image, privacy) = state
+        .db
+        .call(move |conn| {
+            Ok::<_, anyhow::Error>((
+                super::select_image(&ckey, conn)?,
+                privacy::select_privacy(&ckey, conn)?,
+            ))
+        })
+        .await
+        .context("Failed to query image metadata")?;
+    let (image, privacy) = image.zip(privacy).ok_or(Error::NotFound)?;
+    let strip = privacy.strips_for(Some(user.as_str()));
+
+    let data = state
+        .storage
+        .read(&super::original_path(&key, &image.metadata.file_name))
+        .await
+        .context("Failed to read original file")?;
+
+    let exif = read_exif(&data)
+        .map(|exif| {
+            exif.fields()
+                .filter(|f| {
+                    !strip
+                        || !(f.tag.context() == exif::Context::Gps || privacy::is_camera_tag(f.tag))
+                })
+                .map(|f| ExifField {
+                    tag: f.tag.to_string(),
+                    ifd: f.ifd_num.to_string(),
+                    value: f.display_value().with_unit(&exif).to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Json(RawMetadata {
+        exif,
+        xmp: xmp::find_packet(&data)
+            .filter(|_| !strip)
+            .map(str::to_owned),
+    }))
+}
@@ -0,0 +1,69 @@
+use anyhow::Context;
+use axum::{extract::Path, Extension, Json};
+use serde::Serialize;
+
+use std::sync::Arc;
+
+use super::size::ImageSize;
+use crate::{api::auth::Authorize, api::error::Error, storage, AppState};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VariantInfo {
+    file_name: String,
+    format: &'static str,
+    mime: &'static str,
+    width: Option<u32>,
+    height: Option<u32>,
+
+    #[serde(flatten)]
+    size: ImageSize,
+}
+
+/// Lists the derivatives of an image which were generated already.
+pub(super) async fn get(
+    Path(key): Path<String>,
+    Authorize(_): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<VariantInfo>>, Error> {
+    let ckey = key.clone();
+    let (image, dimensions) = state
+        .db
+        .call(move |conn| {
+            Ok::<_, anyhow::Error>((
+                super::select_image(&ckey, conn)?,
+                super::dimensions::select_variants(&ckey, conn)?,
+            ))
+        })
+        .await
+        .context("Failed to query image metadata")?;
+
+    if image.is_none() {
+        return Err(Error::NotFound);
+    }
+
+    let files = state
+        .storage
+        .list(&key)
+        .await
+        .context("Failed to list image files")?;
+
+    let mut variants = Vec::new();
+    for variant in state.image_config.variants() {
+        let file_name = variant.file_name();
+
+        if files.contains(&storage::path(&[&key, &file_name])) {
+            let stored = dimensions.iter().find(|d| d.file_name == file_name);
+            variants.push(VariantInfo {
+                width: stored.map(|d| d.width),
+                height: stored.map(|d| d.height),
+                file_name,
+                format: variant.format.extension(),
+                mime: variant.format.mime(),
+                size: variant.size,
+            });
+        }
+    }
+
+    Ok(Json(variants))
+}
@@ -0,0 +1,652 @@
+use anyhow::Context;
+use axum::{
+    body::{self, Body, Bytes},
+    extract::{FromRequest, Query, RequestParts},
+    http::{
+        header::{
+            ACCEPT, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+            IF_RANGE, RANGE,
+        },
+        HeaderMap, HeaderValue, Method, Request, StatusCode, Uri,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use headers::{ETag, HeaderMapExt, IfNoneMatch, LastModified};
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::*;
+
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::format::Format;
+use super::privacy::{self, STRIPPED_DIRECTORY};
+use super::signature::{self, MediaQuery};
+use super::size::FULL_SIZE;
+use crate::api::{auth::Authorize, public_auth};
+use crate::storage::{self, FileInfo, Storage};
+use crate::util::percent_encode;
+use crate::AppState;
+
+/// Larger files are streamed in chunks of this size instead of being read at once.
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// Start and exclusive end.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Serves the files of the storage, with single byte ranges so videos can be seeked.
+pub async fn serve(
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Response {
+    let path = match path_segments(uri.path()) {
+        Some(segments) if !segments.is_empty() && !segments.iter().any(|s| s.starts_with('.')) => {
+            segments.join("/")
+        }
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let info = match state.storage.info(&path).await {
+        Ok(Some(info)) => info,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Failed to read metadata of {path}: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Ranges of a file which changed in the meantime would be mixed up, the whole file is sent
+    // instead of comparing validators
+    let range = if headers.contains_key(IF_RANGE) {
+        None
+    } else {
+        headers.get(RANGE).and_then(|r| r.to_str().ok())
+    };
+    let (status, start, end) = match byte_range(range, info.len) {
+        ByteRange::Full => (StatusCode::OK, 0, info.len),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end),
+        ByteRange::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, format!("bytes */{}", info.len))],
+            )
+                .into_response()
+        }
+    };
+
+    let body = if method == Method::HEAD {
+        Body::empty()
+    } else if end - start <= CHUNK_SIZE {
+        match state.storage.read_range(&path, start, end).await {
+            Ok(data) => Body::from(data),
+            Err(e) => {
+                error!("Failed to read {path}: {:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    } else {
+        stream(state.storage.clone(), path.clone(), start, end)
+    };
+
+    let mut response = Response::new(body::boxed(body));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(&path)));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.typed_insert(LastModified::from(info.modified));
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = HeaderValue::try_from(format!("bytes {start}-{}/{}", end - 1, info.len))
+        {
+            headers.insert(CONTENT_RANGE, value);
+        }
+    }
+
+    response
+}
+
+fn stream(storage: Arc<dyn Storage>, path: String, start: u64, end: u64) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut offset = start;
+        while offset < end {
+            let chunk_end = (offset + CHUNK_SIZE).min(end);
+            match storage.read_range(&path, offset, chunk_end).await {
+                Ok(data) => {
+                    // The client went away
+                    if sender.send_data(Bytes::from(data)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to read {path}: {:?}", e);
+                    sender.abort();
+                    return;
+                }
+            }
+
+            offset = chunk_end;
+        }
+    });
+
+    body
+}
+
+/// Parses `bytes=<start>-<end>`, `bytes=<start>-` and `bytes=-<suffix length>`. Anything else,
+/// including multiple ranges, is ignored and the whole file is served.
+fn byte_range(header: Option<&str>, len: u64) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+
+    let range = match (start, end) {
+        ("", suffix) => suffix
+            .parse::<u64>()
+            .ok()
+            .map(|suffix| (len.saturating_sub(suffix), len)),
+        (start, "") => start.parse().ok().map(|start| (start, len)),
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => Some((start, end.saturating_add(1).min(len))),
+            _ => None,
+        },
+    };
+
+    match range {
+        None => ByteRange::Full,
+        Some((start, end)) if start >= len || start >= end => ByteRange::Unsatisfiable,
+        Some((start, end)) => ByteRange::Partial(start, end),
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("gif") => "image/gif",
+        Some("heic") => "image/heic",
+        Some("tif" | "tiff") => "image/tiff",
+        Some("mp4") => "video/mp4",
+        Some("mov") => "video/quicktime",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serves `/<key>/<size>.jpg` as WebP or AVIF instead if the client accepts it and the derivative
+/// was generated already. The Vary header is set by the cors layer.
+pub async fn negotiate_format<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let derivative = derivative_path(req.uri().path()).map(|(k, n)| (k.to_owned(), n.to_owned()));
+
+    if let Some((key, name)) = derivative {
+        let format = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|a| a.to_str().ok())
+            .and_then(Format::negotiate);
+        let state = req.extensions().get::<Arc<AppState>>().cloned();
+
+        if let (Some(format), Some(state)) = (format, state) {
+            let file_name = format!("{name}.{}", format.extension());
+
+            if let Ok(Some(_)) = state
+                .storage
+                .info(&storage::path(&[&key, &file_name]))
+                .await
+            {
+                let path_and_query = match req.uri().query() {
+                    Some(query) => format!("/{key}/{file_name}?{query}"),
+                    None => format!("/{key}/{file_name}"),
+                };
+
+                match path_and_query.parse::<Uri>() {
+                    Ok(uri) => *req.uri_mut() = uri,
+                    Err(e) => warn!("Failed to rewrite uri to {path_and_query}: {e}"),
+                }
+            }
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Derivatives requested with the version of the image, `?v=<version>`, never change and are
+/// cached for a year.
+const IMMUTABLE: &str = "private, max-age=31536000, immutable";
+/// Everything else is revalidated with the ETag on every use.
+const REVALIDATE: &str = "private, no-cache";
+
+/// Set by `strip_original` when an original is served from another file.
+#[derive(Debug, Clone, Copy)]
+struct RewrittenOriginal;
+
+/// Adds strong ETags and caching headers to media and answers `If-None-Match` with 304. This has to
+/// be the innermost layer so it sees the file that is actually served.
+pub async fn cache<B>(req: Request<B>, next: Next<B>) -> Response {
+    let state = match req.extensions().get::<Arc<AppState>>() {
+        Some(state) => state.clone(),
+        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let path = path_segments(req.uri().path())
+        .unwrap_or_default()
+        .join("/");
+    let info = match state.storage.info(&path).await {
+        Ok(Some(info)) => info,
+        _ => return next.run(req).await,
+    };
+
+    let etag = entity_tag(&info);
+    let cache_control = if is_versioned_derivative(req.uri())
+        && req.extensions().get::<RewrittenOriginal>().is_none()
+    {
+        IMMUTABLE
+    } else {
+        REVALIDATE
+    };
+
+    let unchanged = match (&etag, req.headers().typed_get::<IfNoneMatch>()) {
+        (Some(etag), Some(if_none_match)) => !if_none_match.precondition_passes(etag),
+        _ => false,
+    };
+
+    let mut response = if unchanged {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        next.run(req).await
+    };
+
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        let headers = response.headers_mut();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        if let Some(etag) = etag {
+            headers.typed_insert(etag);
+        }
+    }
+
+    response
+}
+
+/// Strong ETag from the size and modification time of the file, files are only ever replaced as a
+/// whole.
+fn entity_tag(info: &FileInfo) -> Option<ETag> {
+    let modified = info.modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+
+    format!("\"{:x}-{:x}\"", info.len, modified.as_nanos())
+        .parse()
+        .ok()
+}
+
+fn is_versioned_derivative(uri: &Uri) -> bool {
+    let versioned = uri
+        .query()
+        .map_or(false, |query| query.split('&').any(|p| p.starts_with("v=")));
+
+    versioned && matches!(path_segments(uri.path()).as_deref(), Some([_, _]))
+}
+
+/// Who media is served to, determined by `authorize`.
+#[derive(Debug, Clone)]
+pub enum MediaViewer {
+    User(String),
+    /// Anyone with the share token of an album containing the image.
+    Shared,
+}
+
+/// Media is only served to users with a session or a signed query from `/api/images/media-token`,
+/// and to viewers with the share token of an album containing the image.
+pub async fn authorize<B: Send>(req: Request<B>, next: Next<B>) -> Response {
+    let key = match path_segments(req.uri().path()).and_then(|s| s.into_iter().next()) {
+        Some(key) => key,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let state = match req.extensions().get::<Arc<AppState>>() {
+        Some(state) => state.clone(),
+        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let mut parts = RequestParts::new(req);
+    let query = Query::<MediaQuery>::from_request(&mut parts)
+        .await
+        .map(|Query(query)| query)
+        .unwrap_or_default();
+
+    let viewer = match media_viewer(&state, &key, query, &mut parts).await {
+        Ok(Some(viewer)) => viewer,
+        Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
+        Err(e) => {
+            error!("Failed to authorize media of image {key}: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut req = match parts.try_into_request() {
+        Ok(req) => req,
+        Err(e) => {
+            error!("Failed to restore request: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    req.extensions_mut().insert(viewer);
+
+    next.run(req).await
+}
+
+async fn media_viewer<B: Send>(
+    state: &AppState,
+    key: &str,
+    query: MediaQuery,
+    parts: &mut RequestParts<B>,
+) -> anyhow::Result<Option<MediaViewer>> {
+    if let (Some(user), Some(session), Some(expires), Some(signature)) =
+        (query.user, query.session, query.expires, query.signature)
+    {
+        if !signature::verify(state, &user, session, expires, &signature).await? {
+            return Ok(None);
+        }
+
+        return user_viewer(state, key, user).await;
+    }
+
+    if let (Some(album), Some(token)) = (query.album, query.token) {
+        let key = key.to_owned();
+        let shared = state
+            .db
+            .call(move |conn| shared_image(&album, &token, &key, conn))
+            .await?;
+
+        return Ok(shared.then_some(MediaViewer::Shared));
+    }
+
+    match Option::<Authorize>::from_request(parts).await {
+        Ok(Some(Authorize(user))) => user_viewer(state, key, user).await,
+        _ => Ok(None),
+    }
+}
+
+async fn user_viewer(
+    state: &AppState,
+    key: &str,
+    user: String,
+) -> anyhow::Result<Option<MediaViewer>> {
+    let (key, cuser) = (key.to_owned(), user.clone());
+    let viewable = state
+        .db
+        .call(move |conn| viewable_by(&key, &cuser, conn))
+        .await?;
+
+    Ok(viewable.then_some(MediaViewer::User(user)))
+}
+
+/// Images in the trash are only served to their uploader, who might want to look at them before
+/// restoring them.
+pub(crate) fn viewable_by(key: &str, user: &str, conn: &Connection) -> anyhow::Result<bool> {
+    let image = conn
+        .query_row(
+            "SELECT uploader, deleted_at IS NOT NULL FROM images WHERE key = ?1",
+            params![key],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+        )
+        .optional()
+        .context("Failed to query image")?;
+
+    Ok(matches!(image, Some((uploader, trashed)) if !trashed || uploader == user))
+}
+
+/// Whether the share token belongs to the album and the album contains the image.
+pub(crate) fn shared_image(
+    album: &str,
+    token: &str,
+    key: &str,
+    conn: &Connection,
+) -> anyhow::Result<bool> {
+    if public_auth::album_of_share_token(token, conn)?.as_deref() != Some(album) {
+        return Ok(false);
+    }
+
+    let contained = conn
+        .query_row(
+            "SELECT 1 FROM album_image_associations aia \
+            INNER JOIN images i ON i.key = aia.image_key \
+            WHERE aia.album_key = ?1 AND aia.image_key = ?2 AND i.deleted_at IS NULL",
+            params![album, key],
+            |_| Ok(()),
+        )
+        .optional()
+        .context("Failed to query album images")?;
+
+    Ok(contained.is_some())
+}
+
+/// Serves `/<key>/original/<file_name>` without location and camera metadata to anyone but the
+/// uploader if the image or its uploader asks for it, and always to viewers of shared albums.
+/// Originals which can't be stripped are replaced by the full size derivative.
+pub async fn strip_original<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let (key, file_name) = match original_path(req.uri().path()) {
+        Some(path) => path,
+        None => return next.run(req).await,
+    };
+
+    let viewer = match req.extensions().get::<MediaViewer>() {
+        Some(MediaViewer::User(user)) => Some(user.clone()),
+        _ => None,
+    };
+
+    let state = match req.extensions().get::<Arc<AppState>>() {
+        Some(state) => state.clone(),
+        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let ckey = key.clone();
+    let privacy = match state
+        .db
+        .call(move |conn| privacy::select_privacy(&ckey, conn))
+        .await
+    {
+        Ok(Some(privacy)) => privacy,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Failed to query privacy of image {key}: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if !privacy.strips_for(viewer.as_deref()) {
+        return next.run(req).await;
+    }
+
+    match state
+        .storage
+        .info(&super::original_path(&key, &file_name))
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!(
+                "Failed to read metadata of original of image {key}: {:?}",
+                e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    let path = match privacy::store_stripped(&*state.storage, &key, &file_name).await {
+        Ok(true) => format!("/{key}/{STRIPPED_DIRECTORY}/{}", percent_encode(&file_name)),
+        Ok(false) => format!("/{key}/{FULL_SIZE}.jpg"),
+        Err(e) => {
+            error!("Failed to strip metadata of image {key}: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    };
+
+    match path_and_query.parse::<Uri>() {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(e) => {
+            error!("Failed to rewrite uri to {path_and_query}: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    req.extensions_mut().insert(RewrittenOriginal);
+
+    next.run(req).await
+}
+
+/// Unfinished uploads are stored in the data directory too, but must not be served.
+pub async fn hide_internal<B>(req: Request<B>, next: Next<B>) -> Response {
+    if req.uri().path().starts_with("/.") {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Splits the path of a jpg derivative into the image key and the size name.
+fn derivative_path(path: &str) -> Option<(&str, &str)> {
+    let (key, file_name) = path.strip_prefix('/')?.split_once('/')?;
+    let name = file_name.strip_suffix(".jpg")?;
+
+    let valid = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    (valid(key) && valid(name)).then_some((key, name))
+}
+
+/// Splits the path of an original into the image key and file name.
+fn original_path(path: &str) -> Option<(String, String)> {
+    match path_segments(path)?.as_slice() {
+        [key, original, file_name] if original == "original" => {
+            Some((key.clone(), file_name.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// The path is decoded and normalized into the segments `serve` reads, so encoded or redundant
+/// segments can't bypass any checks.
+fn path_segments(path: &str) -> Option<Vec<String>> {
+    let decoded = percent_decode(path)?;
+
+    Some(
+        Path::new(&decoded)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(segment) => segment.to_str().map(str::to_owned),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// Invalid escapes are kept as they are.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit) => {
+                std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            }
+            _ => None,
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("/abc-_1/medium.jpg", Some(("abc-_1", "medium")))]
+    #[test_case("/abc/original/photo.jpg", None)]
+    #[test_case("/../medium.jpg", None)]
+    #[test_case("/abc/medium.webp", None)]
+    #[test_case("/abc/.jpg", None)]
+    fn parse_derivative_path(path: &str, expected: Option<(&str, &str)>) {
+        assert_eq!(derivative_path(path), expected);
+    }
+
+    #[test_case("/abc/original/photo.jpg", Some(("abc", "photo.jpg")))]
+    #[test_case("/abc/original/my%20photo.jpg", Some(("abc", "my photo.jpg")))]
+    #[test_case("//abc/./%6Friginal//photo.jpg", Some(("abc", "photo.jpg")))]
+    #[test_case("/abc/original/100%.jpg", Some(("abc", "100%.jpg")))]
+    #[test_case("/abc/stripped/photo.jpg", None)]
+    #[test_case("/abc/medium.jpg", None)]
+    fn parse_original_path(path: &str, expected: Option<(&str, &str)>) {
+        let expected = expected.map(|(k, f)| (k.to_owned(), f.to_owned()));
+        assert_eq!(original_path(path), expected);
+    }
+
+    #[test_case("/abc/medium.jpg?v=2", true)]
+    #[test_case("/abc/medium.avif?user=test&v=0", true)]
+    #[test_case("/abc/medium.jpg", false)]
+    #[test_case("/abc/medium.jpg?version=2", false)]
+    #[test_case("/abc/original/photo.jpg?v=2", false)]
+    fn versioned_derivative(uri: &str, expected: bool) {
+        assert_eq!(is_versioned_derivative(&uri.parse().unwrap()), expected);
+    }
+
+    #[test_case(None, ByteRange::Full)]
+    #[test_case(Some("bytes=0-9"), ByteRange::Partial(0, 10))]
+    #[test_case(Some("bytes=90-"), ByteRange::Partial(90, 100))]
+    #[test_case(Some("bytes=-10"), ByteRange::Partial(90, 100))]
+    #[test_case(Some("bytes=-200"), ByteRange::Partial(0, 100))]
+    #[test_case(Some("bytes=50-200"), ByteRange::Partial(50, 100))]
+    #[test_case(Some("bytes=100-"), ByteRange::Unsatisfiable)]
+    #[test_case(Some("bytes=-0"), ByteRange::Unsatisfiable)]
+    #[test_case(Some("bytes=9-0"), ByteRange::Full)]
+    #[test_case(Some("bytes=0-1,5-9"), ByteRange::Full)]
+    #[test_case(Some("lines=0-9"), ByteRange::Full)]
+    fn parse_byte_range(header: Option<&str>, expected: ByteRange) {
+        assert_eq!(byte_range(header, 100), expected);
+    }
+
+    #[test]
+    fn encode_file_name() {
+        assert_eq!(percent_encode("my photo?.jpg"), "my%20photo%3F.jpg");
+    }
+}
@@ -45,4 +45,13 @@ impl ExifOrientation {
             RotatedRight => image.rotate270(),
         }
     }
+
+    /// Dimensions of an image after `apply_to_image`.
+    pub fn apply_to_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        use ExifOrientation::*;
+        match self {
+            Normal | FlippedNormal | UpsideDown | FlippedUpsideDown => (width, height),
+            _ => (height, width),
+        }
+    }
 }
@@ -0,0 +1,189 @@
+use anyhow::Context;
+use image::{imageops::FilterType, DynamicImage, RgbImage};
+use rusqlite::{params, Connection};
+
+use std::collections::HashMap;
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Placeholders only need a handful of pixels, larger thumbnails just cost time.
+const THUMBNAIL_SIZE: u32 = 32;
+
+/// Stand-ins the frontend can render before any derivative is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placeholder {
+    pub blurhash: String,
+    /// Hex color such as `#a0b1c2`.
+    pub dominant_color: String,
+}
+
+pub fn placeholder(image: &DynamicImage) -> Placeholder {
+    let thumbnail = image
+        .resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+        .to_rgb8();
+
+    // More components along the longer side
+    let (x_components, y_components) = if thumbnail.width() >= thumbnail.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+
+    Placeholder {
+        blurhash: blurhash(&thumbnail, x_components, y_components),
+        dominant_color: dominant_color(&thumbnail),
+    }
+}
+
+pub fn set_placeholder(
+    key: &str,
+    placeholder: &Placeholder,
+    conn: &Connection,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE images SET blurhash = ?1, dominant_color = ?2 WHERE key = ?3",
+        params![placeholder.blurhash, placeholder.dominant_color, key],
+    )
+    .context("Failed to update placeholder")?;
+
+    Ok(())
+}
+
+/// Encodes the image as described in https://github.com/woltapp/blurhash.
+fn blurhash(image: &RgbImage, x_components: u32, y_components: u32) -> String {
+    let (width, height) = image.dimensions();
+
+    let mut factors = Vec::new();
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let basis = normalisation
+                    * (std::f64::consts::PI * i as f64 * x as f64 / width as f64).cos()
+                    * (std::f64::consts::PI * j as f64 * y as f64 / height as f64).cos();
+
+                for (f, channel) in factor.iter_mut().zip(pixel.0) {
+                    *f += basis * srgb_to_linear(channel);
+                }
+            }
+
+            let scale = 1.0 / (width * height) as f64;
+            factors.push(factor.map(|f| f * scale));
+        }
+    }
+
+    let (dc, ac) = factors.split_first().unwrap();
+
+    let mut hash = String::new();
+    encode83((x_components - 1) + (y_components - 1) * 9, 1, &mut hash);
+
+    let maximum = if ac.is_empty() {
+        encode83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual = ac.iter().flatten().fold(0.0f64, |max, f| max.max(f.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        encode83(quantised, 1, &mut hash);
+        (quantised + 1) as f64 / 166.0
+    };
+
+    let dc = (linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]);
+    encode83(dc, 4, &mut hash);
+
+    for factor in ac {
+        let quantise = |value: f64| {
+            let value = value / maximum;
+            let value = value.signum() * value.abs().sqrt();
+            (value * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+        };
+
+        let value = quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]);
+        encode83(value, 2, &mut hash);
+    }
+
+    hash
+}
+
+/// Average of the most common color after reducing every channel to 4 bits.
+fn dominant_color(image: &RgbImage) -> String {
+    let mut buckets: HashMap<[u8; 3], (u32, [u32; 3])> = HashMap::new();
+    for pixel in image.pixels() {
+        let (count, sum) = buckets.entry(pixel.0.map(|c| c >> 4)).or_default();
+        *count += 1;
+        for (s, channel) in sum.iter_mut().zip(pixel.0) {
+            *s += channel as u32;
+        }
+    }
+
+    // Ties are broken by the bucket so the result doesn't depend on the iteration order
+    let (count, sum) = buckets
+        .into_iter()
+        .max_by_key(|(bucket, (count, _))| (*count, *bucket))
+        .map(|(_, value)| value)
+        .unwrap_or((1, [0; 3]));
+
+    let [r, g, b] = sum.map(|s| s / count);
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+fn encode83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        hash.push(BASE83[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let value = value as f64 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        (value * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn solid_color() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 48, Rgb([255, 0, 0])));
+        let placeholder = placeholder(&image);
+
+        // 4x3 components with a pure red average
+        assert!(placeholder.blurhash.starts_with('L'));
+        assert_eq!(&placeholder.blurhash[2..6], "TI:j");
+        assert_eq!(placeholder.blurhash.len(), 2 + 4 + 2 * 11);
+        assert_eq!(placeholder.dominant_color, "#ff0000");
+    }
+
+    #[test]
+    fn portrait_uses_more_vertical_components() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(30, 60, |_, y| {
+            if y < 30 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([250, 250, 250])
+            }
+        }));
+        let placeholder = placeholder(&image);
+
+        // 3x4 components
+        assert!(placeholder.blurhash.starts_with('T'));
+        assert_eq!(placeholder.blurhash.len(), 2 + 4 + 2 * 11);
+    }
+}
@@ -0,0 +1,413 @@
+use anyhow::Context;
+use exif::{In, Tag};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use serde_rusqlite::from_row;
+
+use super::upload::read_exif;
+use super::video;
+use crate::storage::{self, Storage};
+
+/// Stripped copies of originals are cached next to them in this directory.
+pub const STRIPPED_DIRECTORY: &str = "stripped";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+const JPEG_APP0: u8 = 0xe0;
+const JPEG_APP1: u8 = 0xe1;
+const JPEG_APP2: u8 = 0xe2;
+const JPEG_APP14: u8 = 0xee;
+const JPEG_APP15: u8 = 0xef;
+const JPEG_COM: u8 = 0xfe;
+const JPEG_SOS: u8 = 0xda;
+const JPEG_EOI: u8 = 0xd9;
+
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+#[derive(Debug, Deserialize)]
+pub struct ImagePrivacy {
+    pub uploader: String,
+    /// The setting of the image, or the default of the uploader if it has none.
+    pub strip_metadata: bool,
+}
+
+impl ImagePrivacy {
+    /// Viewers without a session, e.g. through a share link, never get the metadata.
+    pub fn strips_for(&self, viewer: Option<&str>) -> bool {
+        match viewer {
+            Some(viewer) => viewer != self.uploader && self.strip_metadata,
+            None => true,
+        }
+    }
+}
+
+pub fn select_privacy(key: &str, conn: &Connection) -> anyhow::Result<Option<ImagePrivacy>> {
+    conn.query_row(
+        "SELECT \
+            i.uploader, \
+            COALESCE(i.strip_metadata, u.strip_metadata) AS strip_metadata \
+        FROM images i \
+        INNER JOIN users u ON u.username = i.uploader \
+        WHERE i.key = ?1",
+        params![key],
+        |row| Ok(from_row::<ImagePrivacy>(row).unwrap()),
+    )
+    .optional()
+    .context("Failed to query image privacy")
+}
+
+/// EXIF tags which are hidden along with the location.
+pub fn is_camera_tag(tag: Tag) -> bool {
+    [
+        Tag::Make,
+        Tag::Model,
+        Tag::BodySerialNumber,
+        Tag::LensMake,
+        Tag::LensModel,
+        Tag::LensSerialNumber,
+        Tag::CameraOwnerName,
+        Tag::MakerNote,
+    ]
+    .contains(&tag)
+}
+
+/// Writes a copy of the original without location and camera metadata unless there is one
+/// already. Returns false if the format of the original isn't supported.
+pub async fn store_stripped(
+    storage: &dyn Storage,
+    key: &str,
+    file_name: &str,
+) -> anyhow::Result<bool> {
+    let path = storage::path(&[key, STRIPPED_DIRECTORY, file_name]);
+    if storage.info(&path).await?.is_some() {
+        return Ok(true);
+    }
+
+    let data = storage
+        .read(&super::original_path(key, file_name))
+        .await
+        .context("Failed to read original file")?;
+    let stripped = match strip(&data) {
+        Some(stripped) => stripped,
+        None => return Ok(false),
+    };
+
+    storage
+        .write(&path, stripped)
+        .await
+        .context("Failed to write stripped file")?;
+
+    Ok(true)
+}
+
+/// Removes the cached copies, e.g. after the original was renamed.
+pub async fn remove_stripped(storage: &dyn Storage, key: &str) -> anyhow::Result<()> {
+    storage
+        .remove_all(&storage::path(&[key, STRIPPED_DIRECTORY]))
+        .await
+        .context("Failed to remove stripped directory")
+}
+
+/// Removes location and camera metadata from JPEG, PNG, WebP, MP4 and MOV files, anything else
+/// returns `None`.
+pub fn strip(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xff, 0xd8]) {
+        strip_jpeg(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        strip_png(data)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WEBP"[..]) {
+        strip_webp(data)
+    } else if video::probe(data).is_some() {
+        let mut stripped = data.to_vec();
+        free_metadata_boxes(&mut stripped);
+        Some(stripped)
+    } else {
+        None
+    }
+}
+
+/// Drops EXIF, XMP, IPTC and comment segments as well as anything following the image, such as
+/// the additional images of MPF files. The orientation is kept in a minimal EXIF segment.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let orientation = read_exif(data)
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        .filter(|&orientation| orientation != 1);
+
+    let mut stripped = vec![0xff, 0xd8];
+    if let Some(orientation) = orientation {
+        stripped.extend_from_slice(&orientation_segment(orientation as u16));
+    }
+
+    let mut rest = &data[2..];
+    loop {
+        if *rest.first()? != 0xff {
+            return None;
+        }
+
+        let marker = *rest.get(1)?;
+        match marker {
+            // Fill byte before a marker
+            0xff => {
+                rest = &rest[1..];
+                continue;
+            }
+            JPEG_EOI => {
+                stripped.extend_from_slice(&rest[..2]);
+                return Some(stripped);
+            }
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes(rest.get(2..4)?.try_into().ok()?) as usize;
+        let mut segment = rest.get(..length + 2)?;
+
+        // The entropy coded data of a scan follows its header without a length
+        if marker == JPEG_SOS {
+            let scan_length = entropy_coded_length(&rest[segment.len()..]);
+            segment = &rest[..segment.len() + scan_length];
+        }
+
+        if keep_jpeg_segment(marker, segment.get(4..)?) {
+            stripped.extend_from_slice(segment);
+        }
+
+        rest = &rest[segment.len()..];
+    }
+}
+
+/// Image data, JFIF, color profiles and the Adobe segment needed to decode CMYK images are kept.
+fn keep_jpeg_segment(marker: u8, contents: &[u8]) -> bool {
+    match marker {
+        JPEG_APP0 => true,
+        JPEG_APP2 => contents.starts_with(b"ICC_PROFILE\0"),
+        JPEG_APP14 => contents.starts_with(b"Adobe"),
+        JPEG_APP1..=JPEG_APP15 | JPEG_COM => false,
+        _ => true,
+    }
+}
+
+/// Length up to the next marker, which isn't an escaped 0xff or a restart marker.
+fn entropy_coded_length(data: &[u8]) -> usize {
+    data.windows(2)
+        .position(|w| w[0] == 0xff && w[1] != 0 && !(0xd0..=0xd7).contains(&w[1]))
+        .unwrap_or(data.len())
+}
+
+/// APP1 segment with an EXIF block that only holds the orientation.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut segment = vec![0xff, JPEG_APP1, 0, 34];
+    segment.extend_from_slice(b"Exif\0\0");
+    // Big endian TIFF header with the first IFD right after it
+    segment.extend_from_slice(b"MM\0\x2a\0\0\0\x08");
+    // One entry of type SHORT, its value padded to four bytes, and no further IFD
+    segment.extend_from_slice(&1u16.to_be_bytes());
+    segment.extend_from_slice(&0x0112u16.to_be_bytes());
+    segment.extend_from_slice(&3u16.to_be_bytes());
+    segment.extend_from_slice(&1u32.to_be_bytes());
+    segment.extend_from_slice(&orientation.to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(&0u32.to_be_bytes());
+    segment
+}
+
+/// Drops the EXIF, text and modification time chunks.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = PNG_SIGNATURE.to_vec();
+
+    let mut rest = &data[PNG_SIGNATURE.len()..];
+    while !rest.is_empty() {
+        let length = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        // Length, type and CRC surround the data
+        let chunk = rest.get(..length.checked_add(12)?)?;
+
+        if !matches!(
+            &chunk[4..8],
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME"
+        ) {
+            stripped.extend_from_slice(chunk);
+        }
+
+        rest = &rest[chunk.len()..];
+    }
+
+    Some(stripped)
+}
+
+/// Drops the EXIF and XMP chunks and clears their flags in the extended header.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    let riff_size = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+
+    let mut chunks = Vec::new();
+    let mut rest = data.get(12..riff_size.checked_add(8)?)?;
+    while !rest.is_empty() {
+        let size = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?) as usize;
+        // Chunks are padded to an even size
+        let chunk = rest.get(..size.checked_add(8 + size % 2)?)?;
+
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let mut header = chunk.to_vec();
+                *header.get_mut(8)? &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+                chunks.extend(header);
+            }
+            _ => chunks.extend_from_slice(chunk),
+        }
+
+        rest = &rest[chunk.len()..];
+    }
+
+    let mut stripped = b"RIFF".to_vec();
+    stripped.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    stripped.extend_from_slice(b"WEBP");
+    stripped.extend(chunks);
+
+    Some(stripped)
+}
+
+/// Turns the user data and metadata boxes, which hold the location and camera model, into free
+/// space in place so the offsets of the media data stay valid.
+fn free_metadata_boxes(data: &mut [u8]) {
+    let mut offset = 0;
+
+    while let Some((kind, header, size)) = video::box_header(&data[offset..]) {
+        match &kind {
+            b"udta" | b"meta" | b"uuid" | b"XMP_" => {
+                data[offset + 4..offset + 8].copy_from_slice(b"free");
+            }
+            b"moov" | b"trak" => free_metadata_boxes(&mut data[offset + header..offset + size]),
+            _ => {}
+        }
+
+        offset += size;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::{insert_image, insert_user};
+    use crate::AppState;
+
+    #[test]
+    fn strip_jpeg_location_and_camera() {
+        let data = include_bytes!("../../../tests/exif.jpg");
+        let exif = read_exif(data).unwrap();
+        assert!(exif.fields().any(|f| f.tag == Tag::GPSLatitude));
+
+        let stripped = strip(data).unwrap();
+
+        if let Some(exif) = read_exif(&stripped) {
+            assert!(exif
+                .fields()
+                .all(|f| f.tag == Tag::Orientation && f.ifd_num == In::PRIMARY));
+        }
+        assert_eq!(super::super::xmp::find_packet(&stripped), None);
+
+        let original = image::load_from_memory(data).unwrap();
+        let image = image::load_from_memory(&stripped).unwrap();
+        assert_eq!(original.to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn keep_jpeg_orientation() {
+        let image = image::DynamicImage::new_rgb8(4, 2);
+        let mut data = Vec::new();
+        image
+            .write_to(
+                &mut std::io::Cursor::new(&mut data),
+                image::ImageOutputFormat::Jpeg(90),
+            )
+            .unwrap();
+
+        // Pretend the camera was rotated and recorded a comment
+        let mut rotated = data[..2].to_vec();
+        rotated.extend_from_slice(&orientation_segment(6));
+        rotated.extend_from_slice(&[0xff, JPEG_COM, 0, 6, b'n', b'i', b'c', b'e']);
+        rotated.extend_from_slice(&data[2..]);
+
+        let stripped = strip(&rotated).unwrap();
+
+        let exif = read_exif(&stripped).unwrap();
+        let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(6));
+        assert!(!stripped.windows(4).any(|w| w == b"nice"));
+        assert_eq!(stripped.len(), rotated.len() - 8);
+    }
+
+    #[test]
+    fn strip_png_text() {
+        let data = include_bytes!("../../../tests/testimage.png");
+
+        let mut text = b"tEXtComment\0secret".to_vec();
+        let mut chunk = (text.len() as u32 - 4).to_be_bytes().to_vec();
+        chunk.append(&mut text);
+        // The CRC isn't checked
+        chunk.extend_from_slice(&[0; 4]);
+
+        // Right after the header chunk
+        let header_end = PNG_SIGNATURE.len() + 25;
+        let with_text = [&data[..header_end], &chunk, &data[header_end..]].concat();
+
+        assert_eq!(strip(&with_text).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn free_video_user_data() {
+        let mut udta = 17u32.to_be_bytes().to_vec();
+        udta.extend_from_slice(b"udta\xa9xyz+45.0");
+        let mut moov = (udta.len() as u32 + 8).to_be_bytes().to_vec();
+        moov.extend_from_slice(b"moov");
+        moov.extend_from_slice(&udta);
+
+        free_metadata_boxes(&mut moov);
+
+        assert_eq!(&moov[12..16], b"free");
+        assert_eq!(&moov[4..8], b"moov");
+    }
+
+    #[test]
+    fn unsupported_formats() {
+        assert_eq!(strip(b"GIF89a"), None);
+    }
+
+    #[tokio::test]
+    async fn image_overrides_uploader_setting() {
+        let state = AppState::in_memory_db().await;
+
+        let (default, overridden) = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let default = insert_image(&user, conn);
+                let overridden = insert_image(&user, conn);
+
+                conn.execute(
+                    "UPDATE users SET strip_metadata = 1 WHERE username = ?1",
+                    params![user],
+                )
+                .unwrap();
+                conn.execute(
+                    "UPDATE images SET strip_metadata = 0 WHERE key = ?1",
+                    params![overridden],
+                )
+                .unwrap();
+
+                (
+                    select_privacy(&default, conn).unwrap().unwrap(),
+                    select_privacy(&overridden, conn).unwrap().unwrap(),
+                )
+            })
+            .await;
+
+        assert!(default.strips_for(Some("friend")));
+        assert!(!default.strips_for(Some("test")));
+        assert!(!overridden.strips_for(Some("friend")));
+        assert!(overridden.strips_for(None));
+    }
+}
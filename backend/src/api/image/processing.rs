@@ -0,0 +1,442 @@
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_rusqlite::from_row;
+use tokio::fs;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::*;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::orientation::ExifOrientation;
+use super::size::{ImageConfig, Variant};
+use super::transform::{self, Operation};
+use super::upload::{orientation_from_exif, read_exif, store_image, ImageAnalysis};
+use super::{video, DbImage, MediaKind};
+use crate::api::error::Error;
+use crate::storage::Storage;
+use crate::AppState;
+
+/// Below the data directory, for files external commands work on.
+const SCRATCH_DIRECTORY: &str = ".processing";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    Queued,
+    Processing,
+    Failed,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Processing => "processing",
+            JobState::Failed => "failed",
+        }
+    }
+
+    fn from_db(state: &str) -> Self {
+        match state {
+            "processing" => JobState::Processing,
+            "failed" => JobState::Failed,
+            _ => JobState::Queued,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageJob {
+    pub state: JobState,
+    pub error: Option<String>,
+}
+
+/// Hands the keys of freshly stored originals to the processing worker.
+#[derive(Clone)]
+pub struct Queue(UnboundedSender<String>);
+
+impl Queue {
+    pub fn new() -> (Self, UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        (Queue(sender), receiver)
+    }
+
+    pub fn push(&self, key: String) {
+        if let Err(e) = self.0.send(key) {
+            warn!(
+                "Processing worker is not running, image {} will be processed after a restart",
+                e.0
+            );
+        }
+    }
+}
+
+pub fn insert_job(key: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO image_jobs (image_key, state, created_at, updated_at) \
+        VALUES (?1, ?2, ?3, ?3)",
+        params![key, JobState::Queued.as_str(), now],
+    )
+    .context("Failed to insert image job")?;
+
+    Ok(())
+}
+
+/// Queues an image whose derivatives have to be generated again, e.g. after it was edited.
+pub fn requeue_job(key: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO image_jobs (image_key, state, created_at, updated_at) \
+        VALUES (?1, ?2, ?3, ?3) \
+        ON CONFLICT (image_key) DO UPDATE SET state = ?2, error = NULL, updated_at = ?3",
+        params![key, JobState::Queued.as_str(), now],
+    )
+    .context("Failed to requeue image job")?;
+
+    Ok(())
+}
+
+pub fn select_job(key: &str, conn: &Connection) -> anyhow::Result<Option<ImageJob>> {
+    let result = conn
+        .query_row(
+            "SELECT state, error FROM image_jobs WHERE image_key = ?1",
+            params![key],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+        )
+        .optional()
+        .context("Failed to query image job")?;
+
+    Ok(result.map(|(state, error)| ImageJob {
+        state: JobState::from_db(&state),
+        error,
+    }))
+}
+
+/// Jobs that were queued or interrupted while processing, oldest first.
+fn unfinished_jobs(conn: &Connection) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT image_key FROM image_jobs \
+            WHERE state != ?1 \
+            ORDER BY created_at",
+        )
+        .context("Failed to prepare statement for image jobs query")?;
+
+    let keys = stmt
+        .query_map(params![JobState::Failed.as_str()], |row| {
+            Ok(from_row::<String>(row).unwrap())
+        })
+        .context("Failed to query image jobs")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect image jobs")?;
+
+    Ok(keys)
+}
+
+fn start_job(key: &str, now: u64, conn: &Connection) -> anyhow::Result<Option<DbImage>> {
+    let updated = conn
+        .execute(
+            "UPDATE image_jobs SET state = ?1, updated_at = ?2 \
+            WHERE image_key = ?3 AND state != ?4",
+            params![
+                JobState::Processing.as_str(),
+                now,
+                key,
+                JobState::Failed.as_str()
+            ],
+        )
+        .context("Failed to start image job")?;
+
+    // The job was already finished or failed, or the image has been deleted in the meantime.
+    if updated == 0 {
+        return Ok(None);
+    }
+
+    super::select_image_including_trash(key, conn)
+}
+
+/// Jobs which were queued again while processing are kept to be started once more.
+fn finish_job(key: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "DELETE FROM image_jobs WHERE image_key = ?1 AND state = ?2",
+        params![key, JobState::Processing.as_str()],
+    )
+    .context("Failed to finish image job")?;
+
+    Ok(())
+}
+
+/// Stores what was learned from decoding the image, the version is bumped as the derivatives were
+/// just written again.
+pub fn store_analysis(
+    key: &str,
+    analysis: &ImageAnalysis,
+    conn: &Connection,
+) -> anyhow::Result<()> {
+    super::similar::set_perceptual_hash(key, analysis.perceptual_hash, conn)?;
+    super::placeholder::set_placeholder(key, &analysis.placeholder, conn)?;
+    super::dimensions::store(
+        key,
+        analysis.width,
+        analysis.height,
+        &analysis.variants,
+        conn,
+    )?;
+    super::bump_version(key, conn)
+}
+
+fn fail_job(key: &str, error: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE image_jobs SET state = ?1, error = ?2, updated_at = ?3 \
+        WHERE image_key = ?4 AND state = ?5",
+        params![
+            JobState::Failed.as_str(),
+            error,
+            now,
+            key,
+            JobState::Processing.as_str()
+        ],
+    )
+    .context("Failed to mark image job as failed")?;
+
+    Ok(())
+}
+
+/// Generates the derivatives of queued images one at a time, starting with the jobs that were left
+/// over from a previous run.
+pub async fn run(state: Arc<AppState>, mut receiver: UnboundedReceiver<String>) {
+    match state.db.call(|conn| unfinished_jobs(conn)).await {
+        Ok(keys) => {
+            for key in keys {
+                info!("Resuming processing of image {key}");
+                process(&state, key).await;
+            }
+        }
+        Err(e) => error!("Failed to query unfinished image jobs: {:?}", e),
+    }
+
+    while let Some(key) = receiver.recv().await {
+        process(&state, key).await;
+    }
+}
+
+async fn process(state: &AppState, key: String) {
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let ckey = key.clone();
+    let started = state
+        .db
+        .call(move |conn| match start_job(&ckey, now, conn)? {
+            Some(image) => Ok(Some((image, transform::current_operations(&ckey, conn)?))),
+            None => Ok::<_, anyhow::Error>(None),
+        })
+        .await;
+    let (image, operations) = match started {
+        Ok(Some(started)) => started,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Failed to start processing image {key}: {:?}", e);
+            return;
+        }
+    };
+
+    info!("Processing image {key}");
+    let result = generate_derivatives(
+        &*state.storage,
+        state.data_path.clone(),
+        &state.image_config,
+        &image,
+        &operations,
+        &state.image_config.variants(),
+    )
+    .await;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let ckey = key.clone();
+    let result = state
+        .db
+        .call(move |conn| match result {
+            Ok(Some(analysis)) => {
+                store_analysis(&ckey, &analysis, conn)?;
+                finish_job(&ckey, conn)
+            }
+            Ok(None) => finish_job(&ckey, conn),
+            Err(e) => {
+                error!("Failed to process image {ckey}: {:?}", e);
+                fail_job(&ckey, &e.to_string(), now, conn)
+            }
+        })
+        .await;
+
+    if let Err(e) = result {
+        error!("Failed to update job of image {key}: {:?}", e);
+    }
+}
+
+/// Generates the given variants from the original, or from the poster frame for videos. Nothing is
+/// generated for videos if no poster command is configured.
+pub async fn generate_derivatives(
+    storage: &dyn Storage,
+    data_path: PathBuf,
+    config: &ImageConfig,
+    image: &DbImage,
+    operations: &[Operation],
+    variants: &[Variant],
+) -> Result<Option<ImageAnalysis>, Error> {
+    let original_path = super::original_path(&image.key, &image.metadata.file_name);
+
+    if image.metadata.kind == MediaKind::Video {
+        let command = match &config.poster_command {
+            Some(command) => command,
+            None => {
+                info!("No poster command configured, skipping video {}", image.key);
+                return Ok(None);
+            }
+        };
+
+        let scratch_path = data_path.join(SCRATCH_DIRECTORY);
+        fs::create_dir_all(&scratch_path)
+            .await
+            .context("Failed to create scratch directory")?;
+        let poster_path = scratch_path.join(format!("{}.png", image.key));
+
+        // The command needs a file on disk, which only the local storage has
+        let poster = match storage.local_path(&original_path) {
+            Some(input) => video::extract_poster(command, &input, &poster_path).await,
+            None => {
+                let input =
+                    scratch_path.join(format!("{}-{}", image.key, image.metadata.file_name));
+                fs::write(&input, storage.read(&original_path).await?)
+                    .await
+                    .context("Failed to write copy of original")?;
+
+                let poster = video::extract_poster(command, &input, &poster_path).await;
+                if let Err(e) = fs::remove_file(&input).await {
+                    warn!("Failed to remove copy of original {}: {e}", input.display());
+                }
+                poster
+            }
+        }?;
+
+        let mut analysis = store_image(
+            storage,
+            &image.key,
+            poster,
+            ExifOrientation::Normal,
+            &[],
+            config,
+            variants,
+        )
+        .await?;
+
+        // The poster might have been scaled, the dimensions of the clip are kept
+        analysis.width = image.metadata.width.unwrap_or(analysis.width);
+        analysis.height = image.metadata.height.unwrap_or(analysis.height);
+
+        return Ok(Some(analysis));
+    }
+
+    let data = storage
+        .read(&original_path)
+        .await
+        .context("Failed to read original file")?;
+
+    let orientation = read_exif(&data)
+        .and_then(|exif| orientation_from_exif(&exif))
+        .unwrap_or(ExifOrientation::Normal);
+
+    store_image(
+        storage,
+        &image.key,
+        data,
+        orientation,
+        operations,
+        config,
+        variants,
+    )
+    .await
+    .map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::{insert_image, insert_user};
+
+    #[tokio::test]
+    async fn unfinished_jobs_skip_failed() {
+        let state = AppState::in_memory_db().await;
+
+        let (queued, processing) = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                let queued = insert_image(&user, conn);
+                let processing = insert_image(&user, conn);
+                let failed = insert_image(&user, conn);
+
+                insert_job(&queued, 0, conn).unwrap();
+                insert_job(&processing, 1, conn).unwrap();
+                insert_job(&failed, 2, conn).unwrap();
+
+                start_job(&processing, 3, conn).unwrap();
+                fail_job(&failed, "broken", 3, conn).unwrap();
+
+                (queued, processing)
+            })
+            .await;
+
+        let keys = state.db.call(|conn| unfinished_jobs(conn)).await.unwrap();
+
+        assert_eq!(keys, vec![queued, processing]);
+    }
+
+    #[tokio::test]
+    async fn finished_job_is_not_started_again() {
+        let state = AppState::in_memory_db().await;
+
+        let (started, restarted) = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                let image = insert_image(&user, conn);
+
+                insert_job(&image, 0, conn).unwrap();
+                let started = start_job(&image, 1, conn).unwrap();
+                finish_job(&image, conn).unwrap();
+                let restarted = start_job(&image, 2, conn).unwrap();
+
+                (started.map(|i| i.key), restarted)
+            })
+            .await;
+
+        assert!(started.is_some());
+        assert!(restarted.is_none());
+    }
+
+    #[tokio::test]
+    async fn job_requeued_while_processing_is_kept() {
+        let state = AppState::in_memory_db().await;
+
+        let (image, restarted) = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                let image = insert_image(&user, conn);
+
+                insert_job(&image, 0, conn).unwrap();
+                start_job(&image, 1, conn).unwrap();
+                requeue_job(&image, 2, conn).unwrap();
+                finish_job(&image, conn).unwrap();
+                let restarted = start_job(&image, 3, conn).unwrap();
+
+                (image, restarted.map(|i| i.key))
+            })
+            .await;
+
+        assert_eq!(restarted, Some(image));
+    }
+}
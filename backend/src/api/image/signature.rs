@@ -0,0 +1,275 @@
+use anyhow::Context;
+use axum::{Extension, Json};
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::api::{
+    auth::{self, Session},
+    error::Error,
+};
+use crate::util::{hmac_sha256, percent_encode};
+use crate::AppState;
+
+const SECRET_NAME: &str = "media_signature";
+
+const EXPIRY_SECONDS: u64 = 3600 * 24;
+/// Signed queries stay the same for an hour so browsers can cache the media.
+const EXPIRY_GRANULARITY_SECONDS: u64 = 3600;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct MediaToken {
+    /// Appended to media urls, e.g. `/data/image/<key>/medium.jpg?<query>`.
+    query: String,
+    expires_at: u64,
+}
+
+/// Query of a media url, either signed for a session of a user or with the share token of an
+/// album.
+#[derive(Debug, Default, Deserialize)]
+pub struct MediaQuery {
+    pub user: Option<String>,
+    pub session: Option<i64>,
+    pub expires: Option<u64>,
+    pub signature: Option<String>,
+    pub album: Option<String>,
+    pub token: Option<String>,
+}
+
+/// A signed query for the media of every image, `<img>` tags can't send the session. The query is
+/// bound to the session and stops working when it is logged out or revoked.
+pub(super) async fn get(
+    Session { id, username: user }: Session,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<MediaToken>, Error> {
+    let now = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+    let expires_at =
+        (now / EXPIRY_GRANULARITY_SECONDS + 1) * EXPIRY_GRANULARITY_SECONDS + EXPIRY_SECONDS;
+
+    let signature = sign(secret(&state).await?, &user, id, expires_at);
+
+    Ok(Json(MediaToken {
+        query: format!(
+            "user={}&session={id}&expires={expires_at}&signature={signature}",
+            percent_encode(&user)
+        ),
+        expires_at,
+    }))
+}
+
+/// Whether the signature was created by `get` for the session of the user, and neither the
+/// signature nor the session have expired yet.
+pub async fn verify(
+    state: &AppState,
+    user: &str,
+    session: i64,
+    expires: u64,
+    signature: &str,
+) -> anyhow::Result<bool> {
+    let now = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+    if expires < now {
+        return Ok(false);
+    }
+
+    let expected = sign(secret(state).await?, user, session, expires);
+    if !constant_time_eq(expected.as_bytes(), signature.as_bytes()) {
+        return Ok(false);
+    }
+
+    let user = user.to_owned();
+    state
+        .db
+        .call(move |conn| session_active(&user, session, now, conn))
+        .await
+}
+
+fn session_active(user: &str, session: i64, now: u64, conn: &Connection) -> anyhow::Result<bool> {
+    let last_used_at: Option<u64> = conn
+        .query_row(
+            "SELECT last_used_at FROM auth_sessions WHERE id = ?1 AND username = ?2",
+            params![session, user],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Failed to query auth session")?;
+
+    Ok(last_used_at.map_or(false, |last_used_at| {
+        !auth::session_expired(last_used_at, now)
+    }))
+}
+
+async fn secret(state: &AppState) -> anyhow::Result<&[u8]> {
+    state
+        .media_secret
+        .get_or_try_init(|| state.db.call(|conn| load_secret(SECRET_NAME, conn)))
+        .await
+        .map(Vec::as_slice)
+}
+
+/// Generates the secret the first time it is needed, it is kept across restarts so signed urls
+/// stay valid.
+fn load_secret(name: &str, conn: &Connection) -> anyhow::Result<Vec<u8>> {
+    let mut secret = vec![0; 32];
+    OsRng.fill_bytes(&mut secret);
+
+    conn.execute(
+        "INSERT OR IGNORE INTO server_secrets (name, value) VALUES (?1, ?2)",
+        params![name, secret],
+    )
+    .context("Failed to insert server secret")?;
+
+    conn.query_row(
+        "SELECT value FROM server_secrets WHERE name = ?1",
+        params![name],
+        |row| row.get(0),
+    )
+    .context("Failed to query server secret")
+}
+
+fn sign(secret: &[u8], user: &str, session: i64, expires: u64) -> String {
+    format!(
+        "{:x}",
+        hmac_sha256(secret, format!("{user}\n{session}\n{expires}").as_bytes())
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::insert_user;
+
+    fn insert_session(username: &str, last_used_at: u64, conn: &Connection) -> i64 {
+        conn.execute(
+            "INSERT INTO auth_sessions \
+            (username, token_hash, refresh_token_hash, created_at, token_created_at, last_used_at) \
+            VALUES (?1, 'token', 'refresh', 0, 0, ?2)",
+            params![username, last_used_at],
+        )
+        .unwrap();
+
+        conn.last_insert_rowid()
+    }
+
+    #[test]
+    fn hmac_test_vector() {
+        // Test case 2 of RFC 4231
+        assert_eq!(
+            format!(
+                "{:x}",
+                hmac_sha256(b"Jefe", b"what do ya want for nothing?")
+            ),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_signature() {
+        let state = AppState::in_memory_db().await;
+
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let session = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                insert_session(&user, now, conn)
+            })
+            .await;
+
+        let Json(token) = get(
+            Session {
+                id: session,
+                username: "test".into(),
+            },
+            Extension(state.clone()),
+        )
+        .await
+        .unwrap();
+        let signature = token.query.rsplit_once('=').unwrap().1;
+
+        assert!(verify(&state, "test", session, token.expires_at, signature)
+            .await
+            .unwrap());
+        assert!(
+            !verify(&state, "other", session, token.expires_at, signature)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !verify(&state, "test", session + 1, token.expires_at, signature)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !verify(&state, "test", session, token.expires_at + 1, signature)
+                .await
+                .unwrap()
+        );
+
+        // Expired
+        let signature = sign(secret(&state).await.unwrap(), "test", session, 1);
+        assert!(!verify(&state, "test", session, 1, &signature)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn revoked_session_invalidates_signature() {
+        let state = AppState::in_memory_db().await;
+
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let session = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                insert_session(&user, now, conn)
+            })
+            .await;
+
+        let expires = now + EXPIRY_SECONDS;
+        let signature = sign(secret(&state).await.unwrap(), "test", session, expires);
+        assert!(verify(&state, "test", session, expires, &signature)
+            .await
+            .unwrap());
+
+        state
+            .db
+            .call(move |conn| auth::revoke_session(session, "test", conn))
+            .await
+            .unwrap();
+        assert!(!verify(&state, "test", session, expires, &signature)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn secret_is_kept() {
+        let state = AppState::in_memory_db().await;
+
+        let (first, second) = state
+            .db
+            .call(|conn| {
+                (
+                    load_secret(SECRET_NAME, conn).unwrap(),
+                    load_secret(SECRET_NAME, conn).unwrap(),
+                )
+            })
+            .await;
+
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 32);
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::Context;
+use axum::{Extension, Json};
+use image::{imageops::FilterType, DynamicImage};
+use rusqlite::{params, Connection};
+use serde_rusqlite::from_row;
+
+use std::sync::Arc;
+
+use crate::api::{auth::Authorize, error::Error};
+use crate::AppState;
+
+/// Images whose hashes differ in at most this many bits are considered similar.
+const MAXIMUM_DISTANCE: u32 = 10;
+
+/// Difference hash of the image, every bit tells whether a pixel of a 9x8 grayscale thumbnail is
+/// brighter than its right neighbour.
+pub fn perceptual_hash(image: &DynamicImage) -> i64 {
+    let thumbnail = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = thumbnail.get_pixel(x, y)[0];
+            let right = thumbnail.get_pixel(x + 1, y)[0];
+
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+
+    // SQLite only knows signed integers
+    hash as i64
+}
+
+pub fn distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+pub fn set_perceptual_hash(key: &str, hash: i64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE images SET perceptual_hash = ?1 WHERE key = ?2",
+        params![hash, key],
+    )
+    .context("Failed to update perceptual hash")?;
+
+    Ok(())
+}
+
+/// Groups images which are transitively similar to each other, keeping the given order. Images
+/// without a similar one are left out.
+pub fn clusters(images: &[(String, i64)]) -> Vec<Vec<String>> {
+    let mut cluster_of: Vec<Option<usize>> = vec![None; images.len()];
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+
+    for i in 0..images.len() {
+        for j in 0..i {
+            if distance(images[i].1, images[j].1) > MAXIMUM_DISTANCE {
+                continue;
+            }
+
+            match (cluster_of[i], cluster_of[j]) {
+                (None, Some(c)) => {
+                    cluster_of[i] = Some(c);
+                    clusters[c].push(i);
+                }
+                (Some(a), Some(b)) if a != b => {
+                    let merged = std::mem::take(&mut clusters[b]);
+                    for &k in &merged {
+                        cluster_of[k] = Some(a);
+                    }
+                    clusters[a].extend(merged);
+                }
+                (Some(_), Some(_)) => {}
+                (_, None) => unreachable!("earlier images always have a cluster"),
+            }
+        }
+
+        if cluster_of[i].is_none() {
+            cluster_of[i] = Some(clusters.len());
+            clusters.push(vec![i]);
+        }
+    }
+
+    clusters
+        .into_iter()
+        .filter(|c| c.len() > 1)
+        .map(|mut c| {
+            c.sort_unstable();
+            c.into_iter().map(|i| images[i].0.clone()).collect()
+        })
+        .collect()
+}
+
+pub fn album_clusters(album_key: &str, conn: &Connection) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT i.key, i.perceptual_hash FROM images i \
+            INNER JOIN album_image_associations aia ON aia.image_key = i.key \
+            WHERE aia.album_key = ?1 AND i.perceptual_hash IS NOT NULL AND i.deleted_at IS NULL \
+            ORDER BY aia.idx",
+        )
+        .context("Failed to prepare statement for album images query")?;
+
+    let images = stmt
+        .query_map(params![album_key], |row| {
+            Ok(from_row::<(String, i64)>(row).unwrap())
+        })
+        .context("Failed to query album images")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect album images")?;
+
+    Ok(clusters(&images))
+}
+
+/// Groups of visually similar images of the user, e.g. burst shots.
+pub(super) async fn get(
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<Vec<String>>>, Error> {
+    state
+        .db
+        .call(move |conn| {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT key, perceptual_hash FROM images \
+                    WHERE uploader = ?1 AND perceptual_hash IS NOT NULL AND deleted_at IS NULL \
+                    ORDER BY taken_at, uploaded_at",
+                )
+                .context("Failed to prepare statement for images query")?;
+
+            let images = stmt
+                .query_map(params![username], |row| {
+                    Ok(from_row::<(String, i64)>(row).unwrap())
+                })
+                .context("Failed to query user images")?
+                .collect::<Result<Vec<_>, _>>()
+                .context("Failed to collect user images")?;
+
+            Ok(Json(clusters(&images)))
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn gradient(offset: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(90, 80, |x, y| {
+            let value = ((x * 2) ^ (y * 3)) as u8 % 200 + offset;
+            Rgb([value, value, value])
+        }))
+    }
+
+    #[test]
+    fn similar_images_have_close_hashes() {
+        let original = perceptual_hash(&gradient(0));
+        let brighter = perceptual_hash(&gradient(10));
+        let flipped = perceptual_hash(&gradient(0).fliph());
+
+        assert!(distance(original, brighter) <= MAXIMUM_DISTANCE);
+        assert!(distance(original, flipped) > MAXIMUM_DISTANCE);
+    }
+
+    #[test]
+    fn clusters_are_transitive() {
+        let images = [
+            ("a".to_owned(), 0b0000_0000),
+            ("b".to_owned(), -1),
+            ("c".to_owned(), 0b1111_1111_1111),
+            ("d".to_owned(), 0b1111_1111_1111_1111_1111),
+            ("e".to_owned(), 0b1111),
+        ];
+
+        assert_eq!(clusters(&images), vec![vec!["a", "c", "d", "e"]]);
+    }
+}
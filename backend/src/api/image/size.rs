@@ -0,0 +1,237 @@
+use anyhow::{bail, Context};
+use image::{imageops::FilterType, DynamicImage};
+use serde::Serialize;
+
+use super::format::{Format, FORMATS};
+
+pub const FULL_SIZE: &str = "full";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ResizeMode {
+    /// Scale down to fit into a square of the maximum dimension, keeping the aspect ratio.
+    Fit,
+    /// Scale down and cut the center out to a square of the maximum dimension.
+    Crop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageSize {
+    pub name: String,
+    /// `None` for the full size image.
+    pub max_dimension: Option<u32>,
+    pub quality: u8,
+    pub mode: ResizeMode,
+}
+
+impl ImageSize {
+    fn fit(name: &str, max_dimension: u32, quality: u8) -> Self {
+        ImageSize {
+            name: name.into(),
+            max_dimension: Some(max_dimension),
+            quality,
+            mode: ResizeMode::Fit,
+        }
+    }
+
+    /// Parses `name:max_dimension[:quality][:fit|crop]`.
+    fn parse(s: &str, default_quality: u8) -> anyhow::Result<Self> {
+        let mut parts = s.trim().split(':');
+
+        let name = parts.next().unwrap_or_default().to_owned();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Invalid image size name \"{name}\"");
+        }
+        if name == FULL_SIZE {
+            bail!("The {FULL_SIZE} size is always generated and can't be configured");
+        }
+
+        let max_dimension = parts
+            .next()
+            .context("Missing maximum dimension")?
+            .parse()
+            .with_context(|| format!("Invalid maximum dimension of image size {name}"))?;
+
+        let mut quality = default_quality;
+        let mut mode = ResizeMode::Fit;
+        for part in parts {
+            match part {
+                "fit" => mode = ResizeMode::Fit,
+                "crop" => mode = ResizeMode::Crop,
+                q => {
+                    quality = q
+                        .parse()
+                        .with_context(|| format!("Invalid quality of image size {name}"))?
+                }
+            }
+        }
+
+        Ok(ImageSize {
+            name,
+            max_dimension: Some(max_dimension),
+            quality,
+            mode,
+        })
+    }
+
+    /// Resizes `source`, returns `None` if the full size image can be used as is.
+    ///
+    /// `source` has to be at least as large as this size, crops always use the full size image
+    /// since a smaller source might be cut off already.
+    pub fn resize(&self, source: &DynamicImage, full: &DynamicImage) -> Option<DynamicImage> {
+        let max_dimension = self.max_dimension?;
+        let (width, height) = (full.width(), full.height());
+
+        match self.mode {
+            ResizeMode::Fit => {
+                if width < max_dimension && height < max_dimension {
+                    None
+                } else {
+                    Some(source.thumbnail(max_dimension, max_dimension))
+                }
+            }
+            ResizeMode::Crop => {
+                let side = max_dimension.min(width).min(height);
+                if side == width && side == height {
+                    None
+                } else {
+                    Some(full.resize_to_fill(side, side, FilterType::Lanczos3))
+                }
+            }
+        }
+    }
+}
+
+/// A single file generated for every image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub size: ImageSize,
+    pub format: Format,
+}
+
+impl Variant {
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.size.name, self.format.extension())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageConfig {
+    /// Quality of the full size image and default for the other sizes.
+    pub quality: u8,
+    sizes: Vec<ImageSize>,
+    /// Extracts the poster frame of video clips, see `video::extract_poster`.
+    pub poster_command: Option<String>,
+}
+
+impl ImageConfig {
+    pub fn new(quality: u8, sizes: Option<&str>) -> anyhow::Result<Self> {
+        let mut sizes = match sizes {
+            Some(sizes) => sizes
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| ImageSize::parse(s, quality))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => vec![
+                ImageSize::fit("large", 1280, quality),
+                ImageSize::fit("medium", 800, quality),
+                ImageSize::fit("tiny", 360, quality),
+            ],
+        };
+
+        // Larger sizes are generated first so the smaller ones can be scaled down from them
+        sizes.sort_by_key(|s| std::cmp::Reverse(s.max_dimension));
+
+        for (i, size) in sizes.iter().enumerate() {
+            if sizes[..i].iter().any(|s| s.name == size.name) {
+                bail!("Image size {} is configured more than once", size.name);
+            }
+        }
+
+        Ok(ImageConfig {
+            quality,
+            sizes,
+            poster_command: None,
+        })
+    }
+
+    /// All sizes including the full size image, from large to small.
+    pub fn sizes(&self) -> impl Iterator<Item = ImageSize> + '_ {
+        let full = ImageSize {
+            name: FULL_SIZE.into(),
+            max_dimension: None,
+            quality: self.quality,
+            mode: ResizeMode::Fit,
+        };
+
+        std::iter::once(full).chain(self.sizes.iter().cloned())
+    }
+
+    pub fn variants(&self) -> Vec<Variant> {
+        self.sizes()
+            .flat_map(|size| {
+                FORMATS.iter().map(move |format| Variant {
+                    size: size.clone(),
+                    format: *format,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        ImageConfig::new(75, None).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        let config =
+            ImageConfig::new(60, Some("tiny:360, avatar:256:90:crop,large:1280:fit")).unwrap();
+        let sizes = config.sizes().collect::<Vec<_>>();
+
+        assert_eq!(
+            sizes.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+            vec!["full", "large", "tiny", "avatar"]
+        );
+        assert_eq!(sizes[0].quality, 60);
+        assert_eq!(sizes[2].quality, 60);
+        assert_eq!(sizes[3].quality, 90);
+        assert_eq!(sizes[3].mode, ResizeMode::Crop);
+    }
+
+    #[test]
+    fn parse_invalid_sizes() {
+        assert!(ImageConfig::new(75, Some("full:100")).is_err());
+        assert!(ImageConfig::new(75, Some("tiny")).is_err());
+        assert!(ImageConfig::new(75, Some("tiny:360:best")).is_err());
+        assert!(ImageConfig::new(75, Some("../tiny:360")).is_err());
+        assert!(ImageConfig::new(75, Some("tiny:360,tiny:200")).is_err());
+    }
+
+    #[test]
+    fn resize_modes() {
+        let full = DynamicImage::new_rgb8(400, 200);
+        let crop = ImageSize::parse("avatar:100:crop", 75).unwrap();
+        let fit = ImageSize::parse("tiny:100", 75).unwrap();
+        let large = ImageSize::parse("large:1000", 75).unwrap();
+
+        let cropped = crop.resize(&full, &full).unwrap();
+        assert_eq!((cropped.width(), cropped.height()), (100, 100));
+
+        let fitted = fit.resize(&full, &full).unwrap();
+        assert_eq!((fitted.width(), fitted.height()), (100, 50));
+
+        assert!(large.resize(&full, &full).is_none());
+    }
+}
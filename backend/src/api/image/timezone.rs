@@ -0,0 +1,113 @@
+use time::{format_description, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+use time_tz::PrimitiveDateTimeExt;
+use tracing::warn;
+
+/// When a photo was taken, as shown on the camera's clock and as a point in time.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CaptureTime {
+    pub local: PrimitiveDateTime,
+    /// `None` if neither EXIF nor the location tell the timezone, the local time is then assumed
+    /// to be UTC.
+    pub offset: Option<UtcOffset>,
+}
+
+impl CaptureTime {
+    /// Reads `DateTimeOriginal` and finds its offset from `OffsetTimeOriginal`, or from the
+    /// timezone at the coordinates if the camera didn't record one.
+    pub fn from_exif(exif: &exif::Exif, coordinates: Option<(f64, f64)>) -> Option<Self> {
+        use exif::{In, Tag};
+
+        let format =
+            format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
+        let local = exif
+            .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+            .map(|f| f.display_value().with_unit(exif).to_string())
+            .and_then(|s| PrimitiveDateTime::parse(&s, &format).ok())?;
+
+        let offset = exif
+            .get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
+            .and_then(|f| {
+                if let exif::Value::Ascii(v) = &f.value {
+                    v.first()
+                        .and_then(|v| parse_offset(&String::from_utf8_lossy(v)))
+                } else {
+                    warn!("Unexpected format of offset time exif field");
+                    None
+                }
+            })
+            .or_else(|| {
+                let (latitude, longitude) = coordinates?;
+                offset_at(local, latitude, longitude)
+            });
+
+        Some(CaptureTime { local, offset })
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.local
+            .assume_offset(self.offset.unwrap_or(UtcOffset::UTC))
+            .unix_timestamp()
+    }
+
+    pub fn local_string(&self) -> String {
+        let format =
+            format_description::parse("[year]-[month]-[day]T[hour]:[minute]:[second]").unwrap();
+
+        self.local.format(&format).unwrap()
+    }
+}
+
+/// Parses offsets like `+02:00` as written by cameras.
+fn parse_offset(offset: &str) -> Option<UtcOffset> {
+    let format = format_description::parse("[offset_hour sign:mandatory]:[offset_minute]").unwrap();
+
+    UtcOffset::parse(offset.trim(), &format).ok()
+}
+
+/// Offset of the timezone at the coordinates when it was `local` time there.
+fn offset_at(local: PrimitiveDateTime, latitude: f64, longitude: f64) -> Option<UtcOffset> {
+    let name = tz_search::lookup(latitude, longitude)?;
+    let tz = match time_tz::timezones::get_by_name(&name) {
+        Some(tz) => tz,
+        None => {
+            warn!("Unknown timezone {name}");
+            return None;
+        }
+    };
+
+    // Times skipped by a daylight saving change don't exist, times repeated by one take the
+    // earlier offset.
+    local
+        .assume_timezone(tz)
+        .take_first()
+        .map(|t: OffsetDateTime| t.offset())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+    use time::macros::datetime;
+
+    #[test_case("+02:00", Some(7200))]
+    #[test_case("-05:30", Some(-19800))]
+    #[test_case("   :  ", None)]
+    fn parse_offsets(offset: &str, seconds: Option<i32>) {
+        assert_eq!(parse_offset(offset).map(|o| o.whole_seconds()), seconds);
+    }
+
+    #[test_case(datetime!(2008-10-22 16:28:39), 43.46, 11.88, 2 ; "rome in summer time")]
+    #[test_case(datetime!(2008-12-22 16:28:39), 43.46, 11.88, 1 ; "rome in winter")]
+    #[test_case(datetime!(2022-07-01 12:00:00), 35.68, 139.69, 9 ; "tokyo")]
+    fn offsets_from_location(local: PrimitiveDateTime, latitude: f64, longitude: f64, hours: i8) {
+        assert_eq!(
+            offset_at(local, latitude, longitude),
+            Some(UtcOffset::from_hms(hours, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn no_timezone_in_the_ocean() {
+        assert_eq!(offset_at(datetime!(2022-07-01 12:00:00), 0.0, 0.0), None);
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use tracing::*;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::storage::Storage;
+use crate::AppState;
+
+/// Tombstones left by a crash or a failed removal are retried this often.
+const REAP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Deletes the image from the database and leaves a tombstone for the reaper, which removes its
+/// files afterwards. Once this is committed the image is gone, even if the server stops before
+/// its files are.
+pub fn delete(key: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM images WHERE key = ?1", params![key])
+        .context("Failed to delete image")?;
+    insert(key, now, conn)
+}
+
+pub fn insert(key: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO image_tombstones (image_key, created_at) VALUES (?1, ?2) \
+        ON CONFLICT (image_key) DO NOTHING",
+        params![key, now],
+    )
+    .context("Failed to insert image tombstone")?;
+
+    Ok(())
+}
+
+/// Keys of deleted images whose files might still exist, oldest first.
+pub fn pending(conn: &Connection) -> anyhow::Result<Vec<String>> {
+    let mut query = conn
+        .prepare("SELECT image_key FROM image_tombstones ORDER BY created_at, image_key")
+        .context("Failed to prepare statement for tombstone query")?;
+
+    let keys = query
+        .query_map(params![], |row| row.get(0))
+        .context("Failed to query tombstones")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect tombstones")?;
+
+    Ok(keys)
+}
+
+fn remove(key: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "DELETE FROM image_tombstones WHERE image_key = ?1",
+        params![key],
+    )
+    .context("Failed to remove image tombstone")?;
+
+    Ok(())
+}
+
+/// Removes the files of every deleted image, a tombstone is only removed once its files are.
+/// Returns how many images were reaped.
+pub async fn reap(storage: &dyn Storage, db: &tokio_rusqlite::Connection) -> anyhow::Result<usize> {
+    let keys = db.call(|conn| pending(conn)).await?;
+    let mut reaped = 0;
+
+    for key in keys {
+        info!("Removing files of deleted image {key}");
+        if let Err(e) = storage.remove_all(&key).await {
+            error!("Failed to remove files of deleted image {key}: {:?}", e);
+            continue;
+        }
+
+        db.call(move |conn| remove(&key, conn)).await?;
+        reaped += 1;
+    }
+
+    Ok(reaped)
+}
+
+pub async fn run(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.reaper.notified() => {}
+        }
+
+        if let Err(e) = reap(&*state.storage, &state.db).await {
+            error!("Failed to reap deleted images: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::{insert_image, insert_user};
+
+    #[tokio::test]
+    async fn reap_removes_files_of_deleted_images() {
+        let state = AppState::in_memory_db().await;
+
+        let (deleted, kept) = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let deleted = insert_image(&user, conn);
+                let kept = insert_image(&user, conn);
+
+                delete(&deleted, 0, conn).unwrap();
+
+                (deleted, kept)
+            })
+            .await;
+
+        for key in [&deleted, &kept] {
+            state
+                .storage
+                .write(&format!("{key}/full.jpg"), b"full".to_vec())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(reap(&*state.storage, &state.db).await.unwrap(), 1);
+
+        assert!(state.storage.list(&deleted).await.unwrap().is_empty());
+        assert_eq!(
+            state.storage.list(&kept).await.unwrap(),
+            vec![format!("{kept}/full.jpg")]
+        );
+        assert!(state
+            .db
+            .call(|conn| pending(conn))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,421 @@
+use anyhow::{anyhow, Context};
+use axum::{
+    extract::{rejection::JsonRejection, Path},
+    Extension, Json,
+};
+use image::DynamicImage;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::orientation::ExifOrientation;
+use super::upload::{orientation_from_exif, read_exif};
+use super::{processing, Image, MediaKind};
+use crate::api::{auth::Authorize, error::Error};
+use crate::AppState;
+
+const MAXIMUM_OPERATIONS: usize = 16;
+
+/// A single edit, applied to the original after its EXIF orientation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum Operation {
+    /// Clockwise by 90, 180 or 270 degrees.
+    Rotate {
+        degrees: u32,
+    },
+    FlipHorizontal,
+    FlipVertical,
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl Operation {
+    pub fn apply_to_image(&self, image: DynamicImage) -> DynamicImage {
+        use Operation::*;
+        match *self {
+            Rotate { degrees: 90 } => image.rotate90(),
+            Rotate { degrees: 180 } => image.rotate180(),
+            Rotate { degrees: 270 } => image.rotate270(),
+            Rotate { .. } => image,
+            FlipHorizontal => image.fliph(),
+            FlipVertical => image.flipv(),
+            Crop {
+                x,
+                y,
+                width,
+                height,
+            } => image.crop_imm(x, y, width, height),
+        }
+    }
+
+    /// Dimensions of an image after `apply_to_image`, fails for operations which can't be applied
+    /// to an image of the given size.
+    pub fn apply_to_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32), Error> {
+        use Operation::*;
+        match *self {
+            Rotate { degrees: 90 | 270 } => Ok((height, width)),
+            Rotate { degrees: 180 } | FlipHorizontal | FlipVertical => Ok((width, height)),
+            Rotate { .. } => Err(Error::InvalidArguments(anyhow!(
+                "Images can only be rotated by 90, 180 or 270 degrees"
+            ))),
+            Crop {
+                x,
+                y,
+                width: crop_width,
+                height: crop_height,
+            } => {
+                let inside = |offset: u32, length: u32, maximum: u32| {
+                    length > 0 && offset.checked_add(length).is_some_and(|end| end <= maximum)
+                };
+
+                if inside(x, crop_width, width) && inside(y, crop_height, height) {
+                    Ok((crop_width, crop_height))
+                } else {
+                    Err(Error::InvalidArguments(anyhow!(
+                        "Crop has to be inside the {width}x{height} image"
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Dimensions of an image after applying all operations in order.
+pub fn apply_to_dimensions(
+    operations: &[Operation],
+    width: u32,
+    height: u32,
+) -> Result<(u32, u32), Error> {
+    operations
+        .iter()
+        .try_fold((width, height), |(w, h), op| op.apply_to_dimensions(w, h))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Edit {
+    id: i64,
+    operations: Vec<Operation>,
+    created_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct TransformRequest {
+    /// Relative to the original, replaces the operations of the previous edit.
+    operations: Vec<Operation>,
+}
+
+/// Operations of the latest edit, which the derivatives are generated with.
+pub fn current_operations(key: &str, conn: &Connection) -> anyhow::Result<Vec<Operation>> {
+    let operations = conn
+        .query_row(
+            "SELECT operations FROM image_edits \
+            WHERE image_key = ?1 \
+            ORDER BY id DESC LIMIT 1",
+            params![key],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()
+        .context("Failed to query image edit")?;
+
+    match operations {
+        Some(operations) => {
+            serde_json::from_str(&operations).context("Failed to parse operations of image edit")
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+fn insert_edit(
+    key: &str,
+    operations: &[Operation],
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO image_edits (image_key, operations, created_at) VALUES (?1, ?2, ?3)",
+        params![
+            key,
+            serde_json::to_string(operations).context("Failed to serialize operations")?,
+            now
+        ],
+    )
+    .context("Failed to insert image edit")?;
+
+    Ok(())
+}
+
+/// Removes the latest edit, returns false if the image has never been edited.
+fn delete_latest_edit(key: &str, conn: &Connection) -> anyhow::Result<bool> {
+    let deleted = conn
+        .execute(
+            "DELETE FROM image_edits WHERE id = \
+            (SELECT MAX(id) FROM image_edits WHERE image_key = ?1)",
+            params![key],
+        )
+        .context("Failed to delete image edit")?;
+
+    Ok(deleted > 0)
+}
+
+fn select_edits(key: &str, conn: &Connection) -> anyhow::Result<Vec<Edit>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, operations, created_at FROM image_edits \
+            WHERE image_key = ?1 \
+            ORDER BY id DESC",
+        )
+        .context("Failed to prepare statement for image edits query")?;
+
+    let edits = stmt
+        .query_map(params![key], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u64>(2)?,
+            ))
+        })
+        .context("Failed to query image edits")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect image edits")?;
+
+    edits
+        .into_iter()
+        .map(|(id, operations, created_at)| -> anyhow::Result<Edit> {
+            Ok(Edit {
+                id,
+                operations: serde_json::from_str(&operations)
+                    .context("Failed to parse operations of image edit")?,
+                created_at,
+            })
+        })
+        .collect()
+}
+
+/// Lists the edits of an image, latest first.
+pub(super) async fn get(
+    Path(key): Path<String>,
+    Authorize(_): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<Edit>>, Error> {
+    let ckey = key.clone();
+    let exists = state
+        .db
+        .call(move |conn| super::image_exists(&ckey, conn))
+        .await?;
+
+    if !exists {
+        return Err(Error::NotFound);
+    }
+
+    let edits = state.db.call(move |conn| select_edits(&key, conn)).await?;
+
+    Ok(Json(edits))
+}
+
+/// Records a new edit and regenerates the derivatives with it, the original stays untouched.
+pub(super) async fn put(
+    request: Result<Json<TransformRequest>, JsonRejection>,
+    Path(key): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Image>, Error> {
+    let Json(request) = request?;
+
+    if request.operations.len() > MAXIMUM_OPERATIONS {
+        return Err(Error::InvalidArguments(anyhow!(
+            "At most {MAXIMUM_OPERATIONS} operations can be applied"
+        )));
+    }
+
+    let ckey = key.clone();
+    let image = state
+        .db
+        .call(move |conn| {
+            if !super::is_owner(&ckey, &user, conn)? {
+                return Err(Error::Unathorized);
+            }
+
+            Ok(super::select_image(&ckey, conn)?)
+        })
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    if image.metadata.kind == MediaKind::Video {
+        return Err(Error::InvalidArguments(anyhow!("Videos can't be edited")));
+    }
+
+    // The stored dimensions are the ones of the previous edit
+    let data = state
+        .storage
+        .read(&super::original_path(&key, &image.metadata.file_name))
+        .await
+        .context("Failed to read original file")?;
+    let (width, height) = image::io::Reader::new(Cursor::new(&data))
+        .with_guessed_format()
+        .context("Failed to guess image format")?
+        .into_dimensions()?;
+    let (width, height) = read_exif(&data)
+        .and_then(|exif| orientation_from_exif(&exif))
+        .unwrap_or(ExifOrientation::Normal)
+        .apply_to_dimensions(width, height);
+
+    apply_to_dimensions(&request.operations, width, height)?;
+
+    let now = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+
+    let ckey = key.clone();
+    state
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+
+            insert_edit(&ckey, &request.operations, now, &tx)?;
+            processing::requeue_job(&ckey, now, &tx)?;
+
+            tx.commit().context("Failed to commit transaction")
+        })
+        .await?;
+
+    requeued_image(key, &state).await
+}
+
+/// Reverts the latest edit, going back to the one before or the original.
+pub(super) async fn delete(
+    Path(key): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Image>, Error> {
+    let now = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+
+    let ckey = key.clone();
+    state
+        .db
+        .call(move |conn| {
+            if !super::is_owner(&ckey, &user, conn)? {
+                return Err(Error::Unathorized);
+            }
+
+            let tx = conn.transaction().context("Failed to create transaction")?;
+
+            if !delete_latest_edit(&ckey, &tx)? {
+                return Err(Error::NotFound);
+            }
+            processing::requeue_job(&ckey, now, &tx)?;
+
+            tx.commit().context("Failed to commit transaction")?;
+
+            Ok(())
+        })
+        .await?;
+
+    requeued_image(key, &state).await
+}
+
+async fn requeued_image(key: String, state: &AppState) -> Result<Json<Image>, Error> {
+    state.processing.push(key.clone());
+
+    let (image, job) = state
+        .db
+        .call(move |conn| {
+            Ok::<_, anyhow::Error>((
+                super::select_image(&key, conn)?,
+                processing::select_job(&key, conn)?,
+            ))
+        })
+        .await?;
+
+    let mut image = Image::from_db(image.ok_or(Error::NotFound)?);
+    image.processing = job;
+
+    Ok(Json(image))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::{insert_image, insert_user};
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn dimensions_after_operations() {
+        let operations = [
+            Operation::Rotate { degrees: 90 },
+            Operation::FlipHorizontal,
+            Operation::Crop {
+                x: 100,
+                y: 200,
+                width: 300,
+                height: 400,
+            },
+        ];
+
+        assert_matches!(apply_to_dimensions(&operations, 800, 600), Ok((300, 400)));
+
+        // The crop only fits before rotating
+        assert_matches!(
+            apply_to_dimensions(&operations, 600, 800),
+            Err(Error::InvalidArguments(_))
+        );
+        assert_matches!(
+            apply_to_dimensions(&[Operation::Rotate { degrees: 45 }], 800, 600),
+            Err(Error::InvalidArguments(_))
+        );
+    }
+
+    #[test]
+    fn crop_image() {
+        let image = DynamicImage::new_rgb8(800, 600);
+        let operation = Operation::Crop {
+            x: 700,
+            y: 0,
+            width: 100,
+            height: 600,
+        };
+
+        let cropped = operation.apply_to_image(image);
+
+        assert_eq!((cropped.width(), cropped.height()), (100, 600));
+    }
+
+    #[tokio::test]
+    async fn revert_edits() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let key = insert_image(&user, conn);
+
+                let rotate = vec![Operation::Rotate { degrees: 180 }];
+                let flip = vec![Operation::FlipVertical];
+                insert_edit(&key, &rotate, 0, conn).unwrap();
+                insert_edit(&key, &flip, 1, conn).unwrap();
+                assert_eq!(current_operations(&key, conn).unwrap(), flip);
+
+                assert!(delete_latest_edit(&key, conn).unwrap());
+                assert_eq!(current_operations(&key, conn).unwrap(), rotate);
+
+                assert!(delete_latest_edit(&key, conn).unwrap());
+                assert!(current_operations(&key, conn).unwrap().is_empty());
+                assert!(!delete_latest_edit(&key, conn).unwrap());
+            })
+            .await;
+    }
+}
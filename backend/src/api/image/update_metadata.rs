@@ -5,13 +5,12 @@ use axum::{
 };
 use rusqlite::ToSql;
 use serde::{Deserialize, Serialize};
-use tokio::fs;
 
 use std::sync::Arc;
 
 use crate::api::{auth::Authorize, error::Error};
 use crate::util::{check_length, non_empty_str};
-use crate::AppState;
+use crate::{storage, AppState};
 
 use super::Location;
 
@@ -34,6 +33,9 @@ pub(super) struct PutImageMetadataRequest {
     focal_length: Option<String>,
     #[serde(default, deserialize_with = "non_empty_str")]
     description: Option<String>,
+    /// `null` goes back to the setting of the uploader.
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    strip_metadata: Option<Option<bool>>,
 }
 
 pub(super) async fn put(
@@ -68,38 +70,28 @@ pub(super) async fn put(
     }
 
     if let Some(new_name) = &request.file_name {
-        let mut image_path = state.data_path.clone();
-        image_path.push(&image_key);
-        image_path.push("original");
+        let original_directory = storage::path(&[&image_key, "original"]);
+        let new_path = storage::path(&[&original_directory, new_name]);
 
-        let mut entries = fs::read_dir(&image_path)
+        let originals = state
+            .storage
+            .list(&original_directory)
             .await
             .context("Failed to read original dir")?;
 
-        while let Some(old_file) = entries
-            .next_entry()
-            .await
-            .context("Failed to read entry from original dir")?
-        {
-            if old_file.file_name().to_str() == Some(new_name)
-                || !old_file
-                    .file_type()
-                    .await
-                    .context("Failed to check file type")?
-                    .is_file()
-            {
+        for old_path in originals {
+            if old_path == new_path {
                 continue;
             }
 
-            let mut old_path = image_path.clone();
-            old_path.push(old_file.file_name());
-            let mut new_path = image_path.clone();
-            new_path.push(new_name);
-
-            fs::rename(old_path, new_path)
+            state
+                .storage
+                .rename(&old_path, &new_path)
                 .await
                 .context("Failed to rename original file")?;
         }
+
+        super::privacy::remove_stripped(&*state.storage, &image_key).await?;
     }
 
     let update_str = request.update_str();
@@ -134,6 +126,9 @@ impl PutImageMetadataRequest {
 
         if self.taken_at.is_some() {
             result.push("taken_at = ?");
+            // The time on the camera isn't known for a time set by hand
+            result.push("taken_at_local = NULL");
+            result.push("taken_at_offset = NULL");
         }
 
         if self.location.is_some() {
@@ -161,6 +156,10 @@ impl PutImageMetadataRequest {
             result.push("description = ?");
         }
 
+        if self.strip_metadata.is_some() {
+            result.push("strip_metadata = ?");
+        }
+
         result.join(", ")
     }
 
@@ -204,6 +203,10 @@ impl PutImageMetadataRequest {
             params.push(Box::new(description));
         }
 
+        if let Some(strip_metadata) = self.strip_metadata.take() {
+            params.push(Box::new(strip_metadata));
+        }
+
         params
     }
 }
@@ -250,4 +253,42 @@ mod test {
 
         assert_eq!(metadata.unwrap().description, expected_description);
     }
+
+    #[tokio::test]
+    async fn override_strip_metadata() {
+        let state = AppState::in_memory_db().await;
+
+        let (user, image) = state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                let image = insert_image(&user, conn);
+
+                (user, image)
+            })
+            .await;
+
+        for strip_metadata in [Some(true), None] {
+            let result = put(
+                Ok(Json(PutImageMetadataRequest {
+                    strip_metadata: Some(strip_metadata),
+                    ..Default::default()
+                })),
+                Path(image.clone()),
+                Authorize(user.clone()),
+                Extension(state.clone()),
+            )
+            .await;
+
+            assert_eq!(result.unwrap().0, "Success");
+
+            let key = image.clone();
+            let metadata = state
+                .db
+                .call(move |conn| crate::api::image::select_image(&key, conn).unwrap())
+                .await;
+
+            assert_eq!(metadata.unwrap().strip_metadata, strip_metadata);
+        }
+    }
 }
@@ -2,36 +2,74 @@ use anyhow::Context;
 use axum::{
     extract::{
         multipart::MultipartRejection, rejection::ContentLengthLimitRejection, ContentLengthLimit,
-        Multipart,
+        Multipart, Query,
     },
     Extension, Json,
 };
 use image::DynamicImage;
-use time::{format_description, PrimitiveDateTime};
-use tokio::fs;
-use tracing::warn;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
 
 use std::io::Cursor;
-use std::os::unix::fs::symlink;
-use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::SystemTime;
 
+use super::dimensions::VariantDimensions;
 use super::orientation::ExifOrientation;
-use super::{DbImage, DbImageMetadata, Image};
+use super::processing::{self, ImageJob, JobState};
+use super::size::{ImageConfig, ResizeMode, Variant, FULL_SIZE};
+use super::timezone::CaptureTime;
+use super::transform::Operation;
+use super::{dedup, placeholder, similar, video, xmp};
+use super::{DbImage, DbImageMetadata, Image, MediaKind};
 use crate::api::{auth::Authorize, error::Error};
+use crate::storage::{self, Storage};
+use crate::util::check_length;
 use crate::AppState;
 
-const MB: u64 = 1024 * 1024;
+pub(crate) const MB: u64 = 1024 * 1024;
+const MAXIMUM_FILE_SIZE: u64 = 25 * MB;
+pub(crate) const MAXIMUM_VIDEO_SIZE: u64 = 250 * MB;
+const MAXIMUM_UPLOAD_SIZE: u64 = 1024 * MB;
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum UploadResult {
+    Uploaded(Box<Image>),
+    #[serde(rename_all = "camelCase")]
+    Failed {
+        file_name: String,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        existing_key: Option<String>,
+    },
+}
 
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UploadOptions {
+    /// Store files the uploader has uploaded before again instead of reporting them.
+    #[serde(default)]
+    pub allow_duplicates: bool,
+}
+
+pub(crate) struct UploadedFile {
+    pub file_name: String,
+    pub data: Vec<u8>,
+    pub description: Option<String>,
+    pub allow_duplicate: bool,
+}
+
+/// Accepts any number of files, a `description` field applies to the file before it.
 pub(super) async fn post(
     multipart: Result<
-        ContentLengthLimit<Multipart, { 25 * MB }>,
+        ContentLengthLimit<Multipart, { MAXIMUM_UPLOAD_SIZE }>,
         ContentLengthLimitRejection<MultipartRejection>,
     >,
+    Query(options): Query<UploadOptions>,
     Authorize(uploader): Authorize,
     Extension(state): Extension<Arc<AppState>>,
-) -> Result<Json<Image>, Error> {
+) -> Result<Json<Vec<UploadResult>>, Error> {
     let mut multipart = multipart?.0;
 
     let uploaded_at = SystemTime::UNIX_EPOCH
@@ -39,73 +77,253 @@ pub(super) async fn post(
         .context("Failed to get timestamp")?
         .as_secs();
 
-    let field = multipart
+    let mut results = Vec::new();
+    let mut pending: Option<Result<UploadedFile, (String, Error)>> = None;
+
+    while let Some(mut field) = multipart
         .next_field()
         .await
         .context("Failed to read multipart field")?
-        .ok_or(Error::NoImage)?;
+    {
+        if field.name() == Some("description") {
+            let description = field.text().await.context("Failed to read description")?;
+
+            if let Some(Ok(file)) = &mut pending {
+                file.description = Some(description).filter(|d| !d.is_empty());
+            }
+            continue;
+        }
+
+        let file_name = match field.file_name() {
+            Some(file_name) => file_name.to_owned(),
+            None => continue,
+        };
+
+        if let Some(file) = pending.take() {
+            results.push(upload_file(file, &uploader, uploaded_at, &state).await);
+        }
+
+        let mut data = Vec::new();
+        let mut maximum_size = MAXIMUM_FILE_SIZE;
+        let mut too_large = false;
+        while let Some(chunk) = field.chunk().await.context("Failed to get image data")? {
+            if data.is_empty() {
+                maximum_size = self::maximum_size(&chunk);
+            }
+
+            if (data.len() + chunk.len()) as u64 > maximum_size {
+                // The rest of the field is skipped when reading the next one
+                too_large = true;
+                break;
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        pending = Some(if too_large {
+            Err((file_name, Error::FileTooLarge(maximum_size / MB)))
+        } else {
+            Ok(UploadedFile {
+                file_name,
+                data,
+                description: None,
+                allow_duplicate: options.allow_duplicates,
+            })
+        });
+    }
+
+    match pending {
+        Some(file) => results.push(upload_file(file, &uploader, uploaded_at, &state).await),
+        None if results.is_empty() => return Err(Error::NoImage),
+        None => {}
+    }
+
+    Ok(Json(results))
+}
 
-    let file_name = field
-        .file_name()
-        .map(|s| s.to_owned())
-        .unwrap_or_else(|| String::from("unknown"));
-    let data = field.bytes().await.context("Failed to get image data")?;
+async fn upload_file(
+    file: Result<UploadedFile, (String, Error)>,
+    uploader: &str,
+    uploaded_at: u64,
+    state: &AppState,
+) -> UploadResult {
+    let file = match file {
+        Ok(file) => file,
+        Err((file_name, e)) => return upload_failed(file_name, e),
+    };
+
+    let file_name = file.file_name.clone();
+    match store_file(file, uploader, uploaded_at, state).await {
+        Ok(image) => UploadResult::Uploaded(Box::new(image)),
+        Err(e) => upload_failed(file_name, e),
+    }
+}
+
+fn upload_failed(file_name: String, error: Error) -> UploadResult {
+    if let Error::InternalError(e) = &error {
+        error!("Failed to upload {file_name}: {:?}", e);
+    }
+
+    let existing_key = match &error {
+        Error::DuplicateImage { key } => Some(key.clone()),
+        _ => None,
+    };
+
+    UploadResult::Failed {
+        file_name,
+        message: error.to_string(),
+        existing_key,
+    }
+}
+
+/// Clips may be larger than images, they are told apart by the first few bytes of the file.
+pub(crate) fn maximum_size(head: &[u8]) -> u64 {
+    if video::is_container(head) {
+        MAXIMUM_VIDEO_SIZE
+    } else {
+        MAXIMUM_FILE_SIZE
+    }
+}
+
+/// Stores the original and queues it for processing, shared by multipart and resumable uploads.
+pub(crate) async fn store_file(
+    file: UploadedFile,
+    uploader: &str,
+    uploaded_at: u64,
+    state: &AppState,
+) -> Result<Image, Error> {
+    check_length(
+        "fileName",
+        Some(&file.file_name),
+        super::MAXIMUM_FILE_NAME_LENGTH,
+    )?;
+    check_length(
+        "description",
+        file.description.as_deref(),
+        super::MAXIMUM_DESCRIPTION_LENGTH,
+    )?;
+
+    let data = file.data;
     let size_bytes = data.len() as u64;
+    let content_hash = dedup::content_hash(&data);
+
+    if !file.allow_duplicate {
+        let (cuploader, chash) = (uploader.to_owned(), content_hash.clone());
+        let duplicate = state
+            .db
+            .call(move |conn| dedup::find_duplicate(&cuploader, &chash, conn))
+            .await?;
+
+        if let Some(key) = duplicate {
+            return Err(Error::DuplicateImage { key });
+        }
+    }
 
     let key = blob_uuid::random_blob();
     let image_key = key.clone();
 
     let mut metadata = DbImage {
         key,
-        description: None,
-        uploader,
+        description: file.description,
+        uploader: uploader.to_owned(),
         uploaded_at,
         published_at: None,
+        content_hash: Some(content_hash),
+        perceptual_hash: None,
+        strip_metadata: None,
         metadata: DbImageMetadata {
-            file_name,
+            file_name: file.file_name,
             size_bytes,
-            taken_at: None,
-            location_latitude: None,
-            location_longitude: None,
-            camera_brand: None,
-            camera_model: None,
-            exposure_time: None,
-            f_number: None,
-            focal_length: None,
+            ..Default::default()
         },
     };
 
-    let mut orientation = None;
-
-    let mut bufreader = std::io::BufReader::new(Cursor::new(&*data));
-    let exifreader = exif::Reader::new();
-    match exifreader.read_from_container(&mut bufreader) {
-        Ok(exif) => {
-            populate_metadata_from_exif(&mut metadata.metadata, &exif);
-            orientation = orientation_from_exif(&exif);
-        }
-        Err(e) => {
-            warn!("Failed to read EXIF metadata: {}", e);
+    match video::probe(&data) {
+        Some(info) => {
+            metadata.metadata.kind = MediaKind::Video;
+            metadata.metadata.duration = Some(info.duration);
+            metadata.metadata.width = Some(info.width);
+            metadata.metadata.height = Some(info.height);
+            metadata.metadata.taken_at = info.created_at;
         }
+        None => populate_image_metadata(&mut metadata.metadata, &data)?,
     }
 
-    store_image(
-        state.data_path.clone(),
+    store_original(
+        &*state.storage,
         &image_key,
         &metadata.metadata.file_name,
         &data,
-        &orientation.unwrap_or(ExifOrientation::Normal),
-        state.image_quality,
     )
     .await?;
 
     let cmetadata = metadata.clone();
     state
         .db
-        .call(move |conn| super::insert(&cmetadata, conn))
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+
+            super::insert(&cmetadata, &tx)?;
+            processing::insert_job(&cmetadata.key, uploaded_at, &tx)?;
+
+            tx.commit().context("Failed to commit transaction")
+        })
         .await?;
 
-    Ok(Json(Image::from_db(metadata)))
+    state.processing.push(image_key);
+
+    let mut image = Image::from_db(metadata);
+    image.processing = Some(ImageJob {
+        state: JobState::Queued,
+        error: None,
+    });
+
+    Ok(image)
+}
+
+fn populate_image_metadata(metadata: &mut DbImageMetadata, data: &[u8]) -> Result<(), Error> {
+    // Only the header is decoded here so broken uploads are still rejected right away, the
+    // derivatives are generated in the background by the processing worker.
+    let (width, height) = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context("Failed to guess image format")?
+        .into_dimensions()?;
+
+    let exif = read_exif(data);
+    if let Some(exif) = &exif {
+        populate_metadata_from_exif(metadata, exif);
+    }
+
+    // The processing worker stores the same dimensions again, but galleries can be laid out
+    // before it's done this way.
+    let (width, height) = exif
+        .as_ref()
+        .and_then(orientation_from_exif)
+        .unwrap_or(ExifOrientation::Normal)
+        .apply_to_dimensions(width, height);
+    metadata.width = Some(width);
+    metadata.height = Some(height);
+
+    if let Some(packet) = xmp::find_packet(data) {
+        let xmp = xmp::parse(packet);
+        metadata.title = xmp.title;
+        if !xmp.keywords.is_empty() {
+            metadata.keywords = serde_json::to_string(&xmp.keywords).ok();
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_exif(data: &[u8]) -> Option<exif::Exif> {
+    let mut bufreader = std::io::BufReader::new(Cursor::new(data));
+    let exifreader = exif::Reader::new();
+    match exifreader.read_from_container(&mut bufreader) {
+        Ok(exif) => Some(exif),
+        Err(e) => {
+            warn!("Failed to read EXIF metadata: {}", e);
+            None
+        }
+    }
 }
 
 pub fn orientation_from_exif(exif: &exif::Exif) -> Option<ExifOrientation> {
@@ -120,9 +338,11 @@ pub fn orientation_from_exif(exif: &exif::Exif) -> Option<ExifOrientation> {
         })
 }
 
-fn populate_metadata_from_exif(metadata: &mut DbImageMetadata, exif: &exif::Exif) {
+pub fn populate_metadata_from_exif(metadata: &mut DbImageMetadata, exif: &exif::Exif) {
     use exif::{In, Tag};
 
+    let mut coordinates = None;
+
     let latitude_field = exif.get_field(Tag::GPSLatitude, In::PRIMARY);
     let longitude_field = exif.get_field(Tag::GPSLongitude, In::PRIMARY);
 
@@ -147,37 +367,23 @@ fn populate_metadata_from_exif(metadata: &mut DbImageMetadata, exif: &exif::Exif
             long_deg = long_deg.map(|d| d * -1.0);
         }
 
-        if lat_deg.is_some() && long_deg.is_some() {
-            metadata.location_latitude = lat_deg.map(|d| d.to_string());
-            metadata.location_longitude = long_deg.map(|d| d.to_string());
+        if let (Some(lat_deg), Some(long_deg)) = (lat_deg, long_deg) {
+            metadata.location_latitude = Some(lat_deg.to_string());
+            metadata.location_longitude = Some(long_deg.to_string());
+            coordinates = Some((lat_deg, long_deg));
         }
     };
 
-    let format =
-        format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
-    metadata.taken_at = exif
-        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
-        .map(|f| f.display_value().with_unit(exif).to_string())
-        .and_then(|s| PrimitiveDateTime::parse(&s, &format).ok())
-        .map(|t| t.assume_utc())
-        .map(|t| t.unix_timestamp());
+    let capture_time = CaptureTime::from_exif(exif, coordinates);
+    metadata.taken_at = capture_time.as_ref().map(CaptureTime::timestamp);
+    metadata.taken_at_local = capture_time.as_ref().map(CaptureTime::local_string);
+    metadata.taken_at_offset = capture_time
+        .and_then(|t| t.offset)
+        .map(|o| o.whole_seconds());
 
-    metadata.camera_brand = exif.get_field(Tag::Make, In::PRIMARY).and_then(|f| {
-        if let exif::Value::Ascii(v) = &f.value {
-            Some(String::from_utf8_lossy(&v[0]).to_string())
-        } else {
-            warn!("Unexpected format of camera brand exif field");
-            None
-        }
-    });
-    metadata.camera_model = exif.get_field(Tag::Model, In::PRIMARY).and_then(|f| {
-        if let exif::Value::Ascii(v) = &f.value {
-            Some(String::from_utf8_lossy(&v[0]).to_string())
-        } else {
-            warn!("Unexpected format of camera model exif field");
-            None
-        }
-    });
+    metadata.camera_brand = ascii_field(exif, Tag::Make);
+    metadata.camera_model = ascii_field(exif, Tag::Model);
+    metadata.lens_model = ascii_field(exif, Tag::LensModel);
     metadata.exposure_time = exif
         .get_field(Tag::ExposureTime, In::PRIMARY)
         .map(|f| f.display_value().with_unit(exif).to_string());
@@ -187,6 +393,54 @@ fn populate_metadata_from_exif(metadata: &mut DbImageMetadata, exif: &exif::Exif
     metadata.focal_length = exif
         .get_field(Tag::FocalLength, In::PRIMARY)
         .map(|f| f.display_value().with_unit(exif).to_string());
+
+    metadata.iso = exif
+        .get_field(Tag::PhotographicSensitivity, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0));
+    metadata.flash = exif
+        .get_field(Tag::Flash, In::PRIMARY)
+        .map(|f| f.display_value().to_string());
+    metadata.white_balance = exif
+        .get_field(Tag::WhiteBalance, In::PRIMARY)
+        .map(|f| f.display_value().to_string());
+
+    metadata.altitude = exif
+        .get_field(Tag::GPSAltitude, In::PRIMARY)
+        .and_then(|f| rational_value(&f.value))
+        .map(|altitude| {
+            // 1 means below sea level
+            let below = exif
+                .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+                .and_then(|f| f.value.get_uint(0))
+                == Some(1);
+
+            if below {
+                -altitude
+            } else {
+                altitude
+            }
+        });
+    metadata.gps_direction = exif
+        .get_field(Tag::GPSImgDirection, In::PRIMARY)
+        .and_then(|f| rational_value(&f.value));
+}
+
+fn ascii_field(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+    exif.get_field(tag, exif::In::PRIMARY).and_then(|f| {
+        if let exif::Value::Ascii(v) = &f.value {
+            v.first().map(|v| String::from_utf8_lossy(v).to_string())
+        } else {
+            warn!("Unexpected format of {} exif field", tag);
+            None
+        }
+    })
+}
+
+fn rational_value(value: &exif::Value) -> Option<f64> {
+    match value {
+        exif::Value::Rational(parts) => parts.first().map(|p| p.to_f64()),
+        _ => None,
+    }
 }
 
 fn value_to_deg(value: &exif::Value) -> Option<f64> {
@@ -198,93 +452,145 @@ fn value_to_deg(value: &exif::Value) -> Option<f64> {
     }
 }
 
-enum ImageKind {
-    Generated(Arc<DynamicImage>),
-    Symlink(Arc<DynamicImage>),
-}
+pub async fn store_original(
+    storage: &dyn Storage,
+    key: &str,
+    file_name: &str,
+    data: &[u8],
+) -> Result<(), Error> {
+    storage
+        .write(&super::original_path(key, file_name), data.to_vec())
+        .await
+        .context("Failed to write original file")?;
 
-impl ImageKind {
-    fn image(&self) -> Arc<DynamicImage> {
-        match self {
-            ImageKind::Generated(i) => i.clone(),
-            ImageKind::Symlink(i) => i.clone(),
-        }
-    }
+    Ok(())
 }
 
-fn generate_or_symlink_image(
-    image: &ImageKind,
-    source_width: u32,
-    source_height: u32,
-    target_width: u32,
-    target_height: u32,
-) -> ImageKind {
-    if source_width < target_width && source_height < target_height {
-        ImageKind::Symlink(image.image())
-    } else {
-        ImageKind::Generated(Arc::new(
-            image.image().thumbnail(target_width, target_height),
-        ))
-    }
+enum Derivative {
+    Encoded(Vec<u8>),
+    /// Same as the full size image of the format.
+    Alias,
 }
 
-pub async fn store_image(
-    directory: PathBuf,
-    key: &str,
-    file_name: &str,
+fn encode_derivatives(
     data: &[u8],
-    orientation: &ExifOrientation,
-    quality: u8,
-) -> Result<(), Error> {
+    orientation: ExifOrientation,
+    operations: &[Operation],
+    config: &ImageConfig,
+    variants: &[Variant],
+) -> Result<(Vec<(String, Derivative)>, ImageAnalysis), Error> {
     let image = image::load_from_memory(data).map_err(Error::ImageError)?;
-    let image = orientation.apply_to_image(image);
-    let (width, height) = (image.width(), image.height());
-    let image = ImageKind::Generated(Arc::new(image));
+    let full = operations
+        .iter()
+        .fold(orientation.apply_to_image(image), |image, operation| {
+            operation.apply_to_image(image)
+        });
+    let mut analysis = ImageAnalysis {
+        perceptual_hash: similar::perceptual_hash(&full),
+        placeholder: placeholder::placeholder(&full),
+        width: full.width(),
+        height: full.height(),
+        variants: Vec::new(),
+    };
 
-    let large = generate_or_symlink_image(&image, width, height, 1280, 1280);
-    let medium = generate_or_symlink_image(&large, width, height, 800, 800);
-    let tiny = generate_or_symlink_image(&medium, width, height, 360, 360);
+    let mut derivatives = Vec::new();
+    let mut source: Option<DynamicImage> = None;
 
-    let mut image_dir = directory;
-    image_dir.push(key);
+    for size in config.sizes() {
+        let variants = variants
+            .iter()
+            .filter(|v| v.size.name == size.name)
+            .collect::<Vec<_>>();
 
-    let mut original_path = image_dir.clone();
-    original_path.push("original");
+        if variants.is_empty() {
+            continue;
+        }
 
-    fs::create_dir_all(&original_path)
-        .await
-        .context("Failed to create image directory")?;
-    original_path.push(file_name);
+        let resized = size.resize(source.as_ref().unwrap_or(&full), &full);
+
+        for variant in variants {
+            let (width, height) = match &resized {
+                Some(image) => (image.width(), image.height()),
+                None => (full.width(), full.height()),
+            };
+            analysis.variants.push(VariantDimensions {
+                file_name: variant.file_name(),
+                width,
+                height,
+            });
+
+            let derivative = match &resized {
+                Some(image) => Derivative::Encoded(variant.format.encode(image, size.quality)?),
+                None if size.name != FULL_SIZE && size.quality == config.quality => {
+                    Derivative::Alias
+                }
+                None => Derivative::Encoded(variant.format.encode(&full, size.quality)?),
+            };
+
+            derivatives.push((variant.file_name(), derivative));
+        }
 
-    fs::write(original_path, &data)
-        .await
-        .context("Failed to write original file")?;
+        if size.mode == ResizeMode::Fit {
+            if let Some(resized) = resized {
+                source = Some(resized);
+            }
+        }
+    }
 
-    let mut buffer = Vec::new();
-
-    for (image, name) in &[
-        (image, "full.jpg"),
-        (large, "large.jpg"),
-        (medium, "medium.jpg"),
-        (tiny, "tiny.jpg"),
-    ] {
-        let mut path = image_dir.clone();
-        path.push(name);
-
-        match image {
-            ImageKind::Generated(image) => {
-                buffer.clear();
-                let mut cursor = Cursor::new(&mut buffer);
-                image.write_to(&mut cursor, image::ImageOutputFormat::Jpeg(quality))?;
-                fs::write(path, &buffer)
+    Ok((derivatives, analysis))
+}
+
+/// Properties of the decoded image which are stored alongside the metadata.
+#[derive(Debug, Clone)]
+pub struct ImageAnalysis {
+    pub perceptual_hash: i64,
+    pub placeholder: placeholder::Placeholder,
+    /// Oriented dimensions of the original.
+    pub width: u32,
+    pub height: u32,
+    pub variants: Vec<VariantDimensions>,
+}
+
+/// Generates the given variants of an original which has to be stored already, with the edits
+/// applied after the orientation.
+pub async fn store_image(
+    storage: &dyn Storage,
+    key: &str,
+    data: Vec<u8>,
+    orientation: ExifOrientation,
+    operations: &[Operation],
+    config: &ImageConfig,
+    variants: &[Variant],
+) -> Result<ImageAnalysis, Error> {
+    // Decoding and encoding is CPU bound, keep it away from the async runtime.
+    let config = config.clone();
+    let operations = operations.to_vec();
+    let variants = variants.to_vec();
+    let (derivatives, analysis) = tokio::task::spawn_blocking(move || {
+        encode_derivatives(&data, orientation, &operations, &config, &variants)
+    })
+    .await
+    .context("Failed to join image encoding task")??;
+
+    for (name, derivative) in derivatives {
+        let path = storage::path(&[key, &name]);
+
+        match derivative {
+            Derivative::Encoded(buffer) => {
+                storage
+                    .write(&path, buffer)
                     .await
-                    .context("Failed to write jpg")?;
+                    .with_context(|| format!("Failed to write {name}"))?;
             }
-            ImageKind::Symlink(_) => {
-                symlink("full.jpg", path).context("Failed to create symlink")?;
+            Derivative::Alias => {
+                let extension = name.rsplit('.').next().unwrap_or_default();
+                storage
+                    .alias(&path, &format!("{FULL_SIZE}.{extension}"))
+                    .await
+                    .with_context(|| format!("Failed to write {name}"))?;
             }
         }
     }
 
-    Ok(())
+    Ok(analysis)
 }
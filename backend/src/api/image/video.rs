@@ -0,0 +1,281 @@
+use anyhow::{bail, Context};
+use tokio::fs;
+
+use std::path::Path;
+
+/// Seconds between 1904-01-01, which MP4 and QuickTime count from, and the unix epoch.
+const MP4_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// What is read from the container of an MP4 or MOV clip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoInfo {
+    /// Seconds.
+    pub duration: f64,
+    /// Dimensions of the first video track after its rotation.
+    pub width: u32,
+    pub height: u32,
+    /// Unix timestamp of when the clip was recorded.
+    pub created_at: Option<i64>,
+}
+
+/// Major brands of the `ftyp` box used by MP4 and MOV clips. HEIC and AVIF images share the
+/// container but have brands like `heic` or `avif`.
+const VIDEO_BRANDS: &[&[u8; 4]] = &[
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"mp4v", b"avc1", b"qt  ",
+    b"M4V ", b"M4VH", b"M4VP", b"3gp4", b"3gp5", b"3g2a", b"dash", b"mmp4", b"XAVC", b"MSNV",
+];
+
+/// Whether the data starts like an MP4 or MOV file, which works with only the first few bytes.
+/// Old QuickTime files start without an `ftyp` box.
+pub fn is_container(data: &[u8]) -> bool {
+    match data.get(4..8) {
+        Some(b"ftyp") => data
+            .get(8..12)
+            .map_or(false, |brand| VIDEO_BRANDS.iter().any(|b| &b[..] == brand)),
+        Some(b"wide" | b"moov" | b"mdat" | b"free") => true,
+        _ => false,
+    }
+}
+
+/// Reads the metadata of MP4 and MOV files, anything else including HEIC and AVIF images returns
+/// `None`.
+pub fn probe(data: &[u8]) -> Option<VideoInfo> {
+    if !is_container(data) {
+        return None;
+    }
+
+    let moov = find_box(data, b"moov")?;
+    let mvhd = find_box(moov, b"mvhd")?;
+
+    let (creation_time, timescale, duration) = match *mvhd.first()? {
+        0 => (
+            read_u32(mvhd, 4)? as i64,
+            read_u32(mvhd, 12)?,
+            read_u32(mvhd, 16)? as u64,
+        ),
+        _ => (
+            read_u64(mvhd, 4)? as i64,
+            read_u32(mvhd, 20)?,
+            read_u64(mvhd, 24)?,
+        ),
+    };
+
+    if timescale == 0 {
+        return None;
+    }
+
+    let (width, height) = boxes(moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .find_map(|(_, trak)| video_track_dimensions(trak))?;
+
+    Some(VideoInfo {
+        duration: duration as f64 / timescale as f64,
+        width,
+        height,
+        // Many cameras leave the creation time empty
+        created_at: (creation_time > 0).then_some(creation_time - MP4_EPOCH_OFFSET),
+    })
+}
+
+fn video_track_dimensions(trak: &[u8]) -> Option<(u32, u32)> {
+    let hdlr = find_box(find_box(trak, b"mdia")?, b"hdlr")?;
+    if hdlr.get(8..12)? != b"vide" {
+        return None;
+    }
+
+    let tkhd = find_box(trak, b"tkhd")?;
+    let matrix = match *tkhd.first()? {
+        0 => 40,
+        _ => 52,
+    };
+
+    // Widths and heights are 16.16 fixed point numbers following the transformation matrix
+    let width = read_u32(tkhd, matrix + 36)? >> 16;
+    let height = read_u32(tkhd, matrix + 40)? >> 16;
+
+    // Phones record portrait clips sideways and rotate them by 90 or 270 degrees
+    let a = read_u32(tkhd, matrix)?;
+    let b = read_u32(tkhd, matrix + 4)?;
+    if a == 0 && b != 0 {
+        Some((height, width))
+    } else {
+        Some((width, height))
+    }
+}
+
+/// Iterates over the boxes at the top level of `data`, yielding their type and contents.
+fn boxes(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut rest = data;
+
+    std::iter::from_fn(move || {
+        let (kind, header, size) = box_header(rest)?;
+
+        let contents = &rest[header..size];
+        rest = &rest[size..];
+
+        Some((kind, contents))
+    })
+}
+
+/// Type, header length and total size of the box at the start of `data`.
+pub(super) fn box_header(data: &[u8]) -> Option<([u8; 4], usize, usize)> {
+    let size = read_u32(data, 0)? as u64;
+    let kind: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+
+    let (header, size) = match size {
+        0 => (8, data.len() as u64),
+        1 => (16, read_u64(data, 8)?),
+        size => (8, size),
+    };
+
+    if size < header || size > data.len() as u64 {
+        return None;
+    }
+
+    Some((kind, header as usize, size as usize))
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data)
+        .find(|(k, _)| k == kind)
+        .map(|(_, contents)| contents)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Extracts a still of the clip at `input` with the configured command and returns its contents.
+/// `{input}` and `{output}` in the command are replaced with the respective paths.
+pub async fn extract_poster(command: &str, input: &Path, output: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut args = command.split_whitespace().map(|arg| {
+        arg.replace("{input}", &input.to_string_lossy())
+            .replace("{output}", &output.to_string_lossy())
+    });
+    let program = args.next().context("Poster command is empty")?;
+
+    let result = tokio::process::Command::new(&program)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {program}"))?;
+
+    if !result.status.success() {
+        bail!(
+            "{program} exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+
+    let poster = fs::read(output)
+        .await
+        .context("Failed to read extracted poster")?;
+    fs::remove_file(output)
+        .await
+        .context("Failed to remove extracted poster")?;
+
+    Ok(poster)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(contents.len() as u32 + 8).to_be_bytes());
+        data.extend_from_slice(kind);
+        data.extend_from_slice(contents);
+        data
+    }
+
+    /// Builds the boxes of a clip without any media data.
+    fn mp4(creation_time: u32, seconds: u32, width: u32, height: u32, rotated: bool) -> Vec<u8> {
+        let mut mvhd = vec![0; 100];
+        mvhd[4..8].copy_from_slice(&creation_time.to_be_bytes());
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&(seconds * 1000).to_be_bytes());
+
+        let mut tkhd = vec![0; 84];
+        let (a, b) = if rotated {
+            (0, 0x10000u32)
+        } else {
+            (0x10000, 0)
+        };
+        tkhd[40..44].copy_from_slice(&a.to_be_bytes());
+        tkhd[44..48].copy_from_slice(&b.to_be_bytes());
+        tkhd[76..80].copy_from_slice(&(width << 16).to_be_bytes());
+        tkhd[80..84].copy_from_slice(&(height << 16).to_be_bytes());
+
+        let mut hdlr = vec![0; 24];
+        hdlr[8..12].copy_from_slice(b"vide");
+
+        let trak = [
+            mp4_box(b"tkhd", &tkhd),
+            mp4_box(b"mdia", &mp4_box(b"hdlr", &hdlr)),
+        ]
+        .concat();
+        let moov = [mp4_box(b"mvhd", &mvhd), mp4_box(b"trak", &trak)].concat();
+
+        [
+            mp4_box(b"ftyp", b"isom\0\0\0\0isomiso2mp41"),
+            mp4_box(b"moov", &moov),
+            mp4_box(b"mdat", &[]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn probe_mp4() {
+        // 2022-07-01T12:00:00Z
+        let data = mp4(3_739_521_600, 12, 1920, 1080, false);
+
+        assert_eq!(
+            probe(&data),
+            Some(VideoInfo {
+                duration: 12.0,
+                width: 1920,
+                height: 1080,
+                created_at: Some(1_656_676_800),
+            })
+        );
+    }
+
+    #[test]
+    fn probe_rotated_without_creation_time() {
+        let info = probe(&mp4(0, 3, 1920, 1080, true)).unwrap();
+
+        assert_eq!((info.width, info.height), (1080, 1920));
+        assert_eq!(info.created_at, None);
+    }
+
+    #[test]
+    fn probe_rejects_images() {
+        assert_eq!(probe(include_bytes!("../../../tests/testimage.png")), None);
+
+        // HEIC and AVIF have no moov box
+        assert_eq!(probe(&mp4_box(b"ftyp", b"heic\0\0\0\0mif1heic")), None);
+    }
+
+    #[test]
+    fn containers_by_brand() {
+        assert!(is_container(&mp4(0, 3, 1920, 1080, false)));
+        assert!(is_container(&mp4_box(b"ftyp", b"qt  \0\0\0\0qt  ")));
+        assert!(is_container(&mp4_box(b"moov", &[])));
+
+        assert!(!is_container(&mp4_box(b"ftyp", b"heic\0\0\0\0mif1heic")));
+        assert!(!is_container(&mp4_box(b"ftyp", b"avif\0\0\0\0mif1avif")));
+        assert!(!is_container(include_bytes!(
+            "../../../tests/testimage.png"
+        )));
+    }
+}
@@ -0,0 +1,104 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use tracing::warn;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct XmpMetadata {
+    pub title: Option<String>,
+    pub keywords: Vec<String>,
+}
+
+/// Finds the XMP packet embedded in a file. Scanning for it works for every container format
+/// without having to understand each of them.
+pub fn find_packet(data: &[u8]) -> Option<&str> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+
+    let start = data.windows(START.len()).position(|w| w == START)?;
+    let length = data[start..].windows(END.len()).position(|w| w == END)? + END.len();
+
+    std::str::from_utf8(&data[start..start + length]).ok()
+}
+
+/// Reads the Dublin Core title and subjects, which is where photo managers store titles and
+/// keywords.
+pub fn parse(packet: &str) -> XmpMetadata {
+    let mut reader = Reader::from_str(packet);
+    reader.trim_text(true);
+
+    let mut metadata = XmpMetadata::default();
+    let mut path: Vec<Vec<u8>> = Vec::new();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => path.push(e.local_name().as_ref().to_vec()),
+            Ok(Event::End(_)) => {
+                path.pop();
+            }
+            Ok(Event::Text(e)) => {
+                let text = match e.unescape() {
+                    Ok(text) => text.into_owned(),
+                    Err(_) => continue,
+                };
+
+                let in_list = |name: &[u8]| {
+                    path.last().map(Vec::as_slice) == Some(b"li")
+                        && path.iter().any(|p| p.as_slice() == name)
+                };
+
+                if in_list(b"title") {
+                    metadata.title.get_or_insert(text);
+                } else if in_list(b"subject") {
+                    metadata.keywords.push(text);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Failed to parse XMP metadata: {}", e);
+                break;
+            }
+        }
+    }
+
+    metadata
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PACKET: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+        <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+            <rdf:Description xmlns:dc="http://purl.org/dc/elements/1.1/">
+                <dc:title>
+                    <rdf:Alt><rdf:li xml:lang="x-default">Sunset &amp; sea</rdf:li></rdf:Alt>
+                </dc:title>
+                <dc:subject>
+                    <rdf:Bag><rdf:li>beach</rdf:li><rdf:li>holiday</rdf:li></rdf:Bag>
+                </dc:subject>
+            </rdf:Description>
+        </rdf:RDF>
+    </x:xmpmeta>"#;
+
+    #[test]
+    fn parse_title_and_keywords() {
+        let mut data = b"\xff\xd8garbage".to_vec();
+        data.extend_from_slice(PACKET.as_bytes());
+        data.extend_from_slice(b"\xff\xd9");
+
+        let packet = find_packet(&data).unwrap();
+        assert_eq!(
+            parse(packet),
+            XmpMetadata {
+                title: Some("Sunset & sea".into()),
+                keywords: vec!["beach".into(), "holiday".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn no_packet() {
+        assert_eq!(find_packet(b"<x:xmpmeta>"), None);
+    }
+}
@@ -1,23 +1,41 @@
 use anyhow::Context;
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
-    extract::rejection::JsonRejection,
+    extract::{rejection::JsonRejection, ConnectInfo},
     routing::{post, Router},
-    Extension, Json,
+    Extension, Json, TypedHeader,
 };
+use headers::UserAgent;
 use rand::rngs::OsRng;
 use rand::seq::SliceRandom;
-use rusqlite::{params, OptionalExtension};
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::SystemTime;
 
+mod challenge;
+pub mod lockout;
+pub mod passkey;
+
+use crate::api::auth::{hash_token, session_expired};
 use crate::api::error::Error;
+use crate::api::settings::totp;
 use crate::AppState;
 
 pub fn api_route() -> Router {
-    Router::new().route("/", post(post_login))
+    Router::new()
+        .route("/", post(post_login))
+        .route("/totp", post(post_login_totp))
+        .route("/refresh", post(post_refresh))
+        .route("/passkey", post(passkey::post_login))
+        .route("/passkey/start", post(passkey::post_login_start))
+        .route("/passkey/register", post(passkey::post_register))
+        .route(
+            "/passkey/register/start",
+            post(passkey::post_register_start),
+        )
 }
 
 #[derive(Debug, Deserialize)]
@@ -31,15 +49,145 @@ struct LoginRequest {
 #[serde(rename_all = "camelCase")]
 struct LoginResponse {
     bearer_token: String,
+    refresh_token: String,
+    username: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChallengeResponse {
+    challenge_token: String,
     username: String,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum LoginResult {
+    Session(LoginResponse),
+    /// The password was right but a code of the second factor has to follow.
+    Challenge(ChallengeResponse),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TotpLoginRequest {
+    challenge_token: String,
+    /// Of the authenticator, or one of the recovery codes.
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Only hashes of these are stored, they can't be shown again.
+pub struct SessionTokens {
+    pub bearer_token: String,
+    pub refresh_token: String,
+}
+
+impl SessionTokens {
+    fn generate() -> Self {
+        SessionTokens {
+            bearer_token: generate_token(),
+            refresh_token: generate_token(),
+        }
+    }
+}
+
+pub fn create_session(
+    username: &str,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<SessionTokens> {
+    let tokens = SessionTokens::generate();
+    conn.execute(
+        "INSERT INTO auth_sessions (username, token_hash, refresh_token_hash, created_at, \
+        token_created_at, last_used_at, user_agent, ip) \
+        VALUES (?1, ?2, ?3, ?4, ?4, ?4, ?5, ?6)",
+        params![
+            username,
+            hash_token(&tokens.bearer_token),
+            hash_token(&tokens.refresh_token),
+            now,
+            user_agent,
+            ip
+        ],
+    )
+    .context("Failed inserting session into DB")?;
+
+    Ok(tokens)
+}
+
+/// Replaces both tokens of the session the refresh token belongs to, the old ones stop working.
+/// Returns the user of the session, or nothing if there is no such session or it expired.
+fn refresh_session(
+    refresh_token: &str,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<Option<(String, SessionTokens)>> {
+    let session: Option<(i64, String, u64)> = conn
+        .query_row(
+            "SELECT id, username, last_used_at FROM auth_sessions WHERE refresh_token_hash=?1",
+            params![hash_token(refresh_token)],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .context("Failed to query session")?;
+
+    let (id, username, last_used_at) = match session {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+
+    if session_expired(last_used_at, now) {
+        conn.execute("DELETE FROM auth_sessions WHERE id=?1", params![id])
+            .context("Failed to delete expired session")?;
+
+        return Ok(None);
+    }
+
+    let tokens = SessionTokens::generate();
+    conn.execute(
+        "UPDATE auth_sessions SET token_hash = ?1, refresh_token_hash = ?2, \
+        token_created_at = ?3, last_used_at = ?3 WHERE id = ?4",
+        params![
+            hash_token(&tokens.bearer_token),
+            hash_token(&tokens.refresh_token),
+            now,
+            id
+        ],
+    )
+    .context("Failed to refresh session")?;
+
+    Ok(Some((username, tokens)))
+}
+
 async fn post_login(
     req: Result<Json<LoginRequest>, JsonRejection>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     Extension(state): Extension<Arc<AppState>>,
-) -> Result<Json<LoginResponse>, Error> {
+) -> Result<Json<LoginResult>, Error> {
     let Json(req) = req?;
 
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip().to_string());
+
+    // Checked and counted before the password, locked out attempts don't even get to guess
+    let (username, cip) = (req.username.clone(), ip.clone());
+    let retry_after = state
+        .db
+        .call(move |conn| lockout::reserve_attempt(&username, cip.as_deref(), now, conn))
+        .await?;
+    if let Some(retry_after) = retry_after {
+        return Err(Error::TooManyLoginAttempts { retry_after });
+    }
+
     let username = req.username.clone();
     let result: Option<(String, String)> = state
         .db
@@ -55,43 +203,119 @@ async fn post_login(
         .await
         .context("Failed to query username")?;
 
-    if let Some((username, password_hash)) = result {
+    let verified = if let Some((username, password_hash)) = result {
         let argon2 = Argon2::default();
         let parsed_hash = PasswordHash::new(&password_hash).context("Failed creating hash")?;
 
-        if argon2
+        argon2
             .verify_password(req.password.as_bytes(), &parsed_hash)
             .is_ok()
-        {
-            let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
-            let bearer_token = generate_token();
-            let token = bearer_token.clone();
-
-            let cusername = username.clone();
-            state
-                .db
-                .call(move |conn| {
-                    conn.execute(
-                        "INSERT INTO auth_sessions (username, token, created_at) \
-                    VALUES (?1, ?2, ?3)",
-                        params![cusername, token, now],
-                    )
-                })
-                .await
-                .context("Failed inserting token into DB")?;
+            .then_some(username)
+    } else {
+        None
+    };
 
-            Ok(Json(LoginResponse {
-                bearer_token,
-                username,
-            }))
-        } else {
-            Err(Error::InvalidLogin)
-        }
+    if let Some(username) = verified {
+        let user_agent = user_agent.map(|TypedHeader(agent)| agent.to_string());
+
+        let result = state
+            .db
+            .call(move |conn| {
+                // Failures, including this attempt, are only forgotten once the second factor
+                // was right as well
+                if totp::is_enabled(&username, conn)? {
+                    return Ok::<_, anyhow::Error>(LoginResult::Challenge(ChallengeResponse {
+                        challenge_token: challenge::create(&username, now, conn)?,
+                        username,
+                    }));
+                }
+
+                lockout::record_success(&username, ip.as_deref(), conn)?;
+                let tokens = create_session(&username, user_agent, ip, now, conn)?;
+
+                Ok(LoginResult::Session(LoginResponse {
+                    bearer_token: tokens.bearer_token,
+                    refresh_token: tokens.refresh_token,
+                    username,
+                }))
+            })
+            .await?;
+
+        Ok(Json(result))
     } else {
+        // Unknown usernames were counted as well, or they could be told apart from locked out ones
+        lockout::log_failure(&req.username, ip.as_deref());
+
         Err(Error::InvalidLogin)
     }
 }
 
+async fn post_login_totp(
+    req: Result<Json<TotpLoginRequest>, JsonRejection>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<LoginResponse>, Error> {
+    let Json(req) = req?;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip().to_string());
+    let user_agent = user_agent.map(|TypedHeader(agent)| agent.to_string());
+
+    let response = state
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+            let username = challenge::username(&req.challenge_token, now, &tx)?
+                .ok_or(Error::InvalidChallenge)?;
+
+            // Codes are guessed far quicker than passwords
+            if let Some(retry_after) = lockout::retry_after(&username, ip.as_deref(), now, &tx)? {
+                return Err(Error::TooManyLoginAttempts { retry_after });
+            }
+            if !totp::verify(&username, &req.code, now, &tx)? {
+                lockout::record_failure(&username, ip.as_deref(), now, &tx)?;
+                tx.commit().context("Failed to commit transaction")?;
+
+                return Err(Error::InvalidTwoFactorCode);
+            }
+
+            challenge::remove(&req.challenge_token, &tx)?;
+            lockout::record_success(&username, ip.as_deref(), &tx)?;
+            let tokens = create_session(&username, user_agent, ip, now, &tx)?;
+            tx.commit().context("Failed to commit transaction")?;
+
+            Ok(LoginResponse {
+                bearer_token: tokens.bearer_token,
+                refresh_token: tokens.refresh_token,
+                username,
+            })
+        })
+        .await?;
+
+    Ok(Json(response))
+}
+
+async fn post_refresh(
+    req: Result<Json<RefreshRequest>, JsonRejection>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<LoginResponse>, Error> {
+    let Json(req) = req?;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let (username, tokens) = state
+        .db
+        .call(move |conn| refresh_session(&req.refresh_token, now, conn))
+        .await?
+        .ok_or(Error::InvalidRefreshToken)?;
+
+    Ok(Json(LoginResponse {
+        bearer_token: tokens.bearer_token,
+        refresh_token: tokens.refresh_token,
+        username,
+    }))
+}
+
 fn generate_token() -> String {
     const AUTH_CHARSET: &str = "abcdefghijklmnopqrstuvwxyz\
                            ABCDEFGHIJKLMNOPQRSTUVWXYZ\
@@ -109,3 +333,115 @@ fn generate_token() -> String {
         .collect::<Vec<_>>()
         .join("")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::insert_user;
+    use assert_matches::assert_matches;
+
+    #[tokio::test]
+    async fn refresh_rotates_tokens() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let tokens = create_session(&user, None, None, 0, conn).unwrap();
+
+                // Nothing stored can be used as a token
+                let (token_hash, refresh_token_hash): (String, String) = conn
+                    .query_row(
+                        "SELECT token_hash, refresh_token_hash FROM auth_sessions",
+                        params![],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .unwrap();
+                assert_eq!(token_hash, hash_token(&tokens.bearer_token));
+                assert_eq!(refresh_token_hash, hash_token(&tokens.refresh_token));
+
+                let (username, refreshed) = refresh_session(&tokens.refresh_token, 10, conn)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(username, user);
+                assert_ne!(refreshed.bearer_token, tokens.bearer_token);
+
+                // The old refresh token was replaced
+                assert!(refresh_session(&tokens.refresh_token, 10, conn)
+                    .unwrap()
+                    .is_none());
+
+                let idle = 10 + crate::SESSION_IDLE_SECONDS;
+                assert!(refresh_session(&refreshed.refresh_token, idle, conn)
+                    .unwrap()
+                    .is_none());
+                let sessions: i64 = conn
+                    .query_row("SELECT COUNT(*) FROM auth_sessions", params![], |row| {
+                        row.get(0)
+                    })
+                    .unwrap();
+                assert_eq!(sessions, 0);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn two_step_login() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                crate::api::settings::set_password(&user, "password", conn).unwrap();
+                conn.execute(
+                    "UPDATE users SET totp_secret = ?1 WHERE username = ?2",
+                    params![b"12345678901234567890".to_vec(), user],
+                )
+                .unwrap();
+                conn.execute(
+                    "INSERT INTO recovery_codes (username, code_hash) VALUES (?1, ?2)",
+                    params![user, hash_token("abcdefgh")],
+                )
+                .unwrap();
+            })
+            .await;
+
+        let Json(result) = post_login(
+            Ok(Json(LoginRequest {
+                username: "test".into(),
+                password: "password".into(),
+            })),
+            None,
+            None,
+            Extension(state.clone()),
+        )
+        .await
+        .unwrap();
+        let challenge_token =
+            assert_matches!(result, LoginResult::Challenge(c) => c.challenge_token);
+
+        let second_step = |code: &str| {
+            post_login_totp(
+                Ok(Json(TotpLoginRequest {
+                    challenge_token: challenge_token.clone(),
+                    code: code.into(),
+                })),
+                None,
+                None,
+                Extension(state.clone()),
+            )
+        };
+
+        assert_matches!(
+            second_step("000000").await,
+            Err(Error::InvalidTwoFactorCode)
+        );
+        let Json(response) = second_step("abcd-efgh").await.unwrap();
+        assert_eq!(response.username, "test");
+
+        // The challenge is used up
+        assert_matches!(second_step("abcd-efgh").await, Err(Error::InvalidChallenge));
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::api::auth::hash_token;
+
+/// The second factor has to follow the password this fast.
+const CHALLENGE_SECONDS: u64 = 300;
+
+/// Remembers that the user gave the right password, returns the token which has to be sent
+/// along with the second factor.
+pub fn create(username: &str, now: u64, conn: &Connection) -> anyhow::Result<String> {
+    conn.execute(
+        "DELETE FROM login_challenges WHERE created_at + ?1 <= ?2",
+        params![CHALLENGE_SECONDS, now],
+    )
+    .context("Failed to delete expired login challenges")?;
+
+    let token = super::generate_token();
+    conn.execute(
+        "INSERT INTO login_challenges (username, token_hash, created_at) VALUES (?1, ?2, ?3)",
+        params![username, hash_token(&token), now],
+    )
+    .context("Failed to insert login challenge")?;
+
+    Ok(token)
+}
+
+/// The user who gave their password for the challenge, unless it expired.
+pub fn username(token: &str, now: u64, conn: &Connection) -> anyhow::Result<Option<String>> {
+    let username = conn
+        .query_row(
+            "SELECT username FROM login_challenges WHERE token_hash = ?1 AND created_at + ?2 > ?3",
+            params![hash_token(token), CHALLENGE_SECONDS, now],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Failed to query login challenge")?;
+
+    Ok(username)
+}
+
+pub fn remove(token: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "DELETE FROM login_challenges WHERE token_hash = ?1",
+        params![hash_token(token)],
+    )
+    .context("Failed to delete login challenge")?;
+
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use serde_rusqlite::from_row;
+use tracing::*;
+
+/// Failures are forgotten after a day without another one.
+pub const FAILURE_WINDOW_SECONDS: u64 = 3600 * 24;
+/// The first lockout is this long and every further failure doubles it.
+const BASE_LOCKOUT_SECONDS: u64 = 30;
+const MAX_LOCKOUT_SECONDS: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Username,
+    Ip,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Username => "username",
+            Kind::Ip => "ip",
+        }
+    }
+
+    /// Failures before the first lockout. Friends behind the same address share its attempts.
+    fn allowed_failures(self) -> u64 {
+        match self {
+            Kind::Username => 5,
+            Kind::Ip => 20,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct LoginFailures {
+    pub kind: String,
+    pub subject: String,
+    pub failures: u64,
+    pub last_failure_at: u64,
+    pub locked_until: Option<u64>,
+}
+
+fn subjects<'a>(username: &'a str, ip: Option<&'a str>) -> Vec<(Kind, &'a str)> {
+    let mut subjects = vec![(Kind::Username, username)];
+    if let Some(ip) = ip {
+        subjects.push((Kind::Ip, ip));
+    }
+
+    subjects
+}
+
+fn lockout_seconds(kind: Kind, failures: u64) -> Option<u64> {
+    let allowed = kind.allowed_failures();
+    if failures < allowed {
+        return None;
+    }
+
+    let doublings = (failures - allowed).min(32) as u32;
+    Some((BASE_LOCKOUT_SECONDS << doublings).min(MAX_LOCKOUT_SECONDS))
+}
+
+/// Seconds until the username or the address may try to login again, if either is locked out.
+pub fn retry_after(
+    username: &str,
+    ip: Option<&str>,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<Option<u64>> {
+    let mut retry_after = None;
+    for (kind, subject) in subjects(username, ip) {
+        let locked_until: Option<u64> = conn
+            .query_row(
+                "SELECT locked_until FROM login_failures WHERE kind = ?1 AND subject = ?2",
+                params![kind.as_str(), subject],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to query login failures")?
+            .flatten();
+
+        if let Some(locked_until) = locked_until.filter(|&until| until > now) {
+            retry_after = retry_after.max(Some(locked_until - now));
+        }
+    }
+
+    Ok(retry_after)
+}
+
+/// Checks for a lockout and counts the attempt as failed before the password is verified, or
+/// parallel attempts could all pass the check before any of them was counted. A successful login
+/// forgets it again with `record_success`. Returns the seconds until the next attempt if locked
+/// out.
+pub fn reserve_attempt(
+    username: &str,
+    ip: Option<&str>,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<Option<u64>> {
+    if let Some(retry_after) = retry_after(username, ip, now, conn)? {
+        return Ok(Some(retry_after));
+    }
+
+    count_failure(username, ip, now, conn)?;
+
+    Ok(None)
+}
+
+/// Counts a failed login against the username and the address, and locks them out once they
+/// failed too often.
+pub fn record_failure(
+    username: &str,
+    ip: Option<&str>,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<()> {
+    log_failure(username, ip);
+
+    count_failure(username, ip, now, conn)
+}
+
+/// Only logs a failed login which was already counted by `reserve_attempt`.
+pub fn log_failure(username: &str, ip: Option<&str>) {
+    warn!(
+        "Failed login for {username} from {}",
+        ip.unwrap_or("unknown address")
+    );
+}
+
+fn count_failure(
+    username: &str,
+    ip: Option<&str>,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<()> {
+    for (kind, subject) in subjects(username, ip) {
+        conn.execute(
+            "INSERT INTO login_failures (kind, subject, failures, last_failure_at) \
+            VALUES (?1, ?2, 1, ?3) \
+            ON CONFLICT (kind, subject) DO UPDATE SET \
+            failures = IIF(last_failure_at + ?4 <= ?3, 1, failures + 1), \
+            locked_until = IIF(last_failure_at + ?4 <= ?3, NULL, locked_until), \
+            last_failure_at = ?3",
+            params![kind.as_str(), subject, now, FAILURE_WINDOW_SECONDS],
+        )
+        .context("Failed to record login failure")?;
+
+        let failures: u64 = conn
+            .query_row(
+                "SELECT failures FROM login_failures WHERE kind = ?1 AND subject = ?2",
+                params![kind.as_str(), subject],
+                |row| row.get(0),
+            )
+            .context("Failed to query login failures")?;
+
+        if let Some(seconds) = lockout_seconds(kind, failures) {
+            warn!(
+                "Locking out {} {subject} for {seconds} seconds after {failures} failed logins",
+                kind.as_str()
+            );
+            conn.execute(
+                "UPDATE login_failures SET locked_until = ?1 WHERE kind = ?2 AND subject = ?3",
+                params![now + seconds, kind.as_str(), subject],
+            )
+            .context("Failed to lock out login")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Forgets the failures of the username and the address after a successful login.
+pub fn record_success(username: &str, ip: Option<&str>, conn: &Connection) -> anyhow::Result<()> {
+    for (kind, subject) in subjects(username, ip) {
+        conn.execute(
+            "DELETE FROM login_failures WHERE kind = ?1 AND subject = ?2",
+            params![kind.as_str(), subject],
+        )
+        .context("Failed to clear login failures")?;
+    }
+
+    Ok(())
+}
+
+/// Every username and address with recent failures, most recent first.
+pub fn list(conn: &Connection) -> anyhow::Result<Vec<LoginFailures>> {
+    let mut query = conn
+        .prepare("SELECT * FROM login_failures ORDER BY last_failure_at DESC, kind, subject")
+        .context("Failed to prepare statement for login failures query")?;
+
+    let failures = query
+        .query_map(params![], |row| Ok(from_row::<LoginFailures>(row).unwrap()))
+        .context("Failed to query login failures")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect login failures")?;
+
+    Ok(failures)
+}
+
+/// Clears the failures of a username or address, or of all of them. Returns how many were
+/// cleared.
+pub fn clear(subject: Option<&str>, conn: &Connection) -> anyhow::Result<usize> {
+    let cleared = conn
+        .execute(
+            "DELETE FROM login_failures WHERE ?1 IS NULL OR subject = ?1",
+            params![subject],
+        )
+        .context("Failed to clear login failures")?;
+
+    Ok(cleared)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AppState;
+
+    #[tokio::test]
+    async fn backoff_and_lockout() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let ip = Some("192.0.2.1");
+                for _ in 0..4 {
+                    record_failure("test", ip, 100, conn).unwrap();
+                }
+                assert_eq!(retry_after("test", ip, 100, conn).unwrap(), None);
+
+                record_failure("test", ip, 100, conn).unwrap();
+                assert_eq!(retry_after("TEST", None, 100, conn).unwrap(), Some(30));
+                assert_eq!(retry_after("other", ip, 100, conn).unwrap(), None);
+
+                // Every failure doubles the lockout
+                record_failure("test", ip, 130, conn).unwrap();
+                assert_eq!(retry_after("test", ip, 130, conn).unwrap(), Some(60));
+                assert_eq!(retry_after("test", ip, 190, conn).unwrap(), None);
+
+                // Failures of other usernames add up for the address
+                for i in 0..14 {
+                    record_failure(&format!("user{i}"), ip, 200, conn).unwrap();
+                }
+                assert_eq!(retry_after("other", ip, 200, conn).unwrap(), Some(30));
+                assert_eq!(retry_after("other", None, 200, conn).unwrap(), None);
+
+                // Failures are forgotten after a while
+                let later = 200 + FAILURE_WINDOW_SECONDS;
+                record_failure("test", None, later, conn).unwrap();
+                assert_eq!(retry_after("test", None, later, conn).unwrap(), None);
+
+                record_success("test", ip, conn).unwrap();
+                assert_eq!(retry_after("other", ip, 200, conn).unwrap(), None);
+                assert!(list(conn)
+                    .unwrap()
+                    .iter()
+                    .all(|f| f.subject != "test" && f.kind == "username"));
+
+                assert_eq!(clear(Some("user0"), conn).unwrap(), 1);
+                assert_eq!(clear(None, conn).unwrap(), 13);
+                assert!(list(conn).unwrap().is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn reserved_attempts() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                // Attempts count before they are verified, the sixth one is already locked out
+                for _ in 0..5 {
+                    assert_eq!(reserve_attempt("test", None, 100, conn).unwrap(), None);
+                }
+                assert_eq!(reserve_attempt("test", None, 100, conn).unwrap(), Some(30));
+
+                // The fifth attempt might still have been right
+                record_success("test", None, conn).unwrap();
+                assert_eq!(reserve_attempt("test", None, 100, conn).unwrap(), None);
+            })
+            .await;
+    }
+}
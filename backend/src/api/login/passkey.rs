@@ -0,0 +1,948 @@
+//! Passkey registration and login as described in the WebAuthn specification. Only ES256 keys
+//! are accepted, every authenticator supports them, and attestation statements are not checked,
+//! a friend's choice of authenticator is theirs.
+
+use anyhow::{anyhow, ensure, Context};
+use axum::{
+    extract::{rejection::JsonRejection, ConnectInfo},
+    http::Uri,
+    Extension, Json, TypedHeader,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use headers::UserAgent;
+use rand::{rngs::OsRng, RngCore};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::*;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+mod cbor;
+
+use super::{create_session, lockout, LoginResponse};
+use crate::api::auth::Authorize;
+use crate::api::error::Error;
+use crate::api::settings::verify_password;
+use crate::util::check_length;
+use crate::AppState;
+
+use cbor::Value;
+
+/// Shown by authenticators next to the username.
+const RP_NAME: &str = "hi!friends";
+/// Registration and login have to be finished this fast.
+const CHALLENGE_SECONDS: u64 = 300;
+const CHALLENGE_LENGTH: usize = 32;
+const DEFAULT_NAME: &str = "Passkey";
+pub const MAXIMUM_NAME_LENGTH: u64 = 64;
+/// ES256 in the COSE algorithm registry.
+const COSE_ES256: i128 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// Where the frontend is served from, passkeys are bound to its domain.
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    pub id: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn new(origin: &str) -> anyhow::Result<Self> {
+        let uri: Uri = origin.parse().context("Invalid origin")?;
+        let scheme = uri.scheme_str().context("Origin has no scheme")?;
+        let authority = uri.authority().context("Origin has no host")?;
+
+        // Browsers only allow WebAuthn on secure origins
+        ensure!(
+            scheme == "https" || authority.host() == "localhost",
+            "Origin has to use https"
+        );
+        ensure!(
+            uri.path_and_query().map_or(true, |p| p.as_str() == "/"),
+            "Origin can't have a path"
+        );
+
+        Ok(RelyingParty {
+            id: authority.host().to_owned(),
+            origin: format!("{scheme}://{authority}"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ceremony {
+    Registration,
+    Login,
+}
+
+impl Ceremony {
+    fn as_str(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "registration",
+            Ceremony::Login => "login",
+        }
+    }
+
+    /// The type of the client data the browser signs.
+    fn client_data_type(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "webauthn.create",
+            Ceremony::Login => "webauthn.get",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpEntity {
+    id: String,
+    name: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    alg: i128,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    resident_key: &'static str,
+    require_resident_key: bool,
+    user_verification: &'static str,
+}
+
+/// For `navigator.credentials.create`, binary values are base64url encoded.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    challenge: String,
+    rp: RpEntity,
+    user: UserEntity,
+    pub_key_cred_params: Vec<CredentialParameters>,
+    exclude_credentials: Vec<CredentialDescriptor>,
+    authenticator_selection: AuthenticatorSelection,
+    attestation: &'static str,
+    timeout: u64,
+}
+
+/// For `navigator.credentials.get`, binary values are base64url encoded.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    challenge: String,
+    rp_id: String,
+    /// Empty, passkeys know which user they belong to.
+    allow_credentials: Vec<CredentialDescriptor>,
+    user_verification: &'static str,
+    timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    attestation_object: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    id: String,
+    response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterRequest {
+    name: Option<String>,
+    /// A passkey logs in without the password, a stolen session shouldn't be able to add one.
+    password: String,
+    credential: RegistrationCredential,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+    user_handle: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginRequest {
+    id: String,
+    response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    /// The attested credential data, if any.
+    rest: &'a [u8],
+}
+
+/// What a verified registration adds to the credentials.
+struct NewCredential {
+    challenge: Vec<u8>,
+    credential_id: Vec<u8>,
+    public_key: Vec<u8>,
+    sign_count: u32,
+}
+
+fn base64url(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn from_base64url(data: &str) -> anyhow::Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .context("Invalid base64url")
+}
+
+fn timeout_millis() -> u64 {
+    CHALLENGE_SECONDS * 1000
+}
+
+fn create_challenge(
+    ceremony: Ceremony,
+    username: Option<&str>,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<Vec<u8>> {
+    conn.execute(
+        "DELETE FROM webauthn_challenges WHERE created_at + ?1 <= ?2",
+        params![CHALLENGE_SECONDS, now],
+    )
+    .context("Failed to delete expired passkey challenges")?;
+
+    let mut challenge = vec![0u8; CHALLENGE_LENGTH];
+    OsRng.fill_bytes(&mut challenge);
+    conn.execute(
+        "INSERT INTO webauthn_challenges (challenge, kind, username, created_at) \
+        VALUES (?1, ?2, ?3, ?4)",
+        params![challenge, ceremony.as_str(), username, now],
+    )
+    .context("Failed to insert passkey challenge")?;
+
+    Ok(challenge)
+}
+
+/// Uses up the challenge, returns whether it was issued for the ceremony and user and is still
+/// valid.
+fn take_challenge(
+    challenge: &[u8],
+    ceremony: Ceremony,
+    username: Option<&str>,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<bool> {
+    let taken = conn
+        .execute(
+            "DELETE FROM webauthn_challenges \
+            WHERE challenge = ?1 AND kind = ?2 AND username IS ?3 AND created_at + ?4 > ?5",
+            params![
+                challenge,
+                ceremony.as_str(),
+                username,
+                CHALLENGE_SECONDS,
+                now
+            ],
+        )
+        .context("Failed to use passkey challenge")?;
+
+    Ok(taken > 0)
+}
+
+/// Checks what the browser says was signed, returns the challenge.
+fn verify_client_data(
+    client_data_json: &[u8],
+    ceremony: Ceremony,
+    rp: &RelyingParty,
+) -> anyhow::Result<Vec<u8>> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).context("Invalid client data")?;
+
+    ensure!(
+        client_data.kind == ceremony.client_data_type(),
+        "Client data is for {}",
+        client_data.kind
+    );
+    ensure!(
+        client_data.origin == rp.origin,
+        "Passkey was used on {}",
+        client_data.origin
+    );
+
+    from_base64url(&client_data.challenge)
+}
+
+fn parse_authenticator_data<'a>(
+    data: &'a [u8],
+    rp: &RelyingParty,
+) -> anyhow::Result<AuthenticatorData<'a>> {
+    ensure!(data.len() >= 37, "Authenticator data is too short");
+
+    let (rp_id_hash, rest) = data.split_at(32);
+    ensure!(
+        rp_id_hash == Sha256::digest(rp.id.as_bytes()).as_slice(),
+        "Passkey belongs to another site"
+    );
+
+    // Passkeys replace both the password and the second factor, so the authenticator has to
+    // check a PIN or biometrics as well as the user's presence
+    let flags = rest[0];
+    ensure!(flags & FLAG_USER_PRESENT != 0, "User was not present");
+    ensure!(flags & FLAG_USER_VERIFIED != 0, "User was not verified");
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count: u32::from_be_bytes(rest[1..5].try_into().unwrap()),
+        rest: &rest[5..],
+    })
+}
+
+/// The uncompressed point of an ES256 key in COSE format.
+fn cose_public_key(key: &Value) -> anyhow::Result<Vec<u8>> {
+    let field = |label: i128| key.get(&Value::Integer(label));
+
+    // Key type EC2 on curve P-256
+    ensure!(
+        field(1).and_then(Value::as_integer) == Some(2),
+        "Unsupported key type"
+    );
+    ensure!(
+        field(3).and_then(Value::as_integer) == Some(COSE_ES256),
+        "Unsupported algorithm"
+    );
+    ensure!(
+        field(-1).and_then(Value::as_integer) == Some(1),
+        "Unsupported curve"
+    );
+
+    let x = field(-2)
+        .and_then(Value::as_bytes)
+        .context("Key has no x")?;
+    let y = field(-3)
+        .and_then(Value::as_bytes)
+        .context("Key has no y")?;
+    ensure!(x.len() == 32 && y.len() == 32, "Invalid key coordinates");
+
+    Ok([&[0x04][..], x, y].concat())
+}
+
+fn verify_registration(
+    credential: &RegistrationCredential,
+    rp: &RelyingParty,
+) -> anyhow::Result<NewCredential> {
+    let client_data_json = from_base64url(&credential.response.client_data_json)?;
+    let challenge = verify_client_data(&client_data_json, Ceremony::Registration, rp)?;
+
+    let attestation_object = from_base64url(&credential.response.attestation_object)?;
+    let (attestation, _) = cbor::decode(&attestation_object)?;
+    let auth_data = attestation
+        .get(&Value::Text("authData".into()))
+        .and_then(Value::as_bytes)
+        .context("Attestation has no authenticator data")?;
+
+    let auth_data = parse_authenticator_data(auth_data, rp)?;
+    ensure!(
+        auth_data.flags & FLAG_ATTESTED_CREDENTIAL != 0,
+        "Attestation has no credential"
+    );
+
+    // AAGUID, then the length of the credential ID
+    ensure!(auth_data.rest.len() >= 18, "Credential data is too short");
+    let id_length = u16::from_be_bytes(auth_data.rest[16..18].try_into().unwrap()) as usize;
+    let rest = &auth_data.rest[18..];
+    ensure!(rest.len() > id_length, "Credential data is too short");
+    let (credential_id, key) = rest.split_at(id_length);
+
+    ensure!(
+        from_base64url(&credential.id)? == credential_id,
+        "Credential ID does not match"
+    );
+
+    let (key, _) = cbor::decode(key)?;
+
+    Ok(NewCredential {
+        challenge,
+        credential_id: credential_id.to_vec(),
+        public_key: cose_public_key(&key)?,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+fn start_registration(
+    username: &str,
+    rp: &RelyingParty,
+    now: u64,
+    conn: &Connection,
+) -> anyhow::Result<CreationOptions> {
+    let display_name: Option<String> = conn
+        .query_row(
+            "SELECT display_name FROM users WHERE username = ?1",
+            params![username],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Failed to query display name")?
+        .flatten();
+
+    let mut query = conn
+        .prepare("SELECT credential_id FROM webauthn_credentials WHERE username = ?1")
+        .context("Failed to prepare statement for passkeys query")?;
+    let exclude_credentials = query
+        .query_map(params![username], |row| row.get::<_, Vec<u8>>(0))
+        .context("Failed to query passkeys")?
+        .map(|id| {
+            id.map(|id| CredentialDescriptor {
+                kind: "public-key",
+                id: base64url(&id),
+            })
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to collect passkeys")?;
+
+    let challenge = create_challenge(Ceremony::Registration, Some(username), now, conn)?;
+
+    Ok(CreationOptions {
+        challenge: base64url(&challenge),
+        rp: RpEntity {
+            id: rp.id.clone(),
+            name: RP_NAME,
+        },
+        user: UserEntity {
+            id: base64url(username.as_bytes()),
+            name: username.to_owned(),
+            display_name: display_name.unwrap_or_else(|| username.to_owned()),
+        },
+        pub_key_cred_params: vec![CredentialParameters {
+            kind: "public-key",
+            alg: COSE_ES256,
+        }],
+        exclude_credentials,
+        // Logins don't ask for a username, so the passkey has to remember it
+        authenticator_selection: AuthenticatorSelection {
+            resident_key: "required",
+            require_resident_key: true,
+            user_verification: "required",
+        },
+        attestation: "none",
+        timeout: timeout_millis(),
+    })
+}
+
+fn register(
+    username: &str,
+    request: &RegisterRequest,
+    rp: &RelyingParty,
+    now: u64,
+    conn: &Connection,
+) -> Result<(), Error> {
+    let name = request.name.as_deref().unwrap_or(DEFAULT_NAME);
+    check_length("name", Some(name), MAXIMUM_NAME_LENGTH)?;
+
+    let credential = verify_registration(&request.credential, rp)
+        .map_err(|e| Error::InvalidArguments(e.context("Failed to verify passkey registration")))?;
+
+    if !take_challenge(
+        &credential.challenge,
+        Ceremony::Registration,
+        Some(username),
+        now,
+        conn,
+    )? {
+        return Err(Error::InvalidArguments(anyhow!(
+            "Registration took too long, please try again"
+        )));
+    }
+
+    let inserted = conn
+        .execute(
+            "INSERT INTO webauthn_credentials \
+            (username, credential_id, public_key, sign_count, name, created_at) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
+            ON CONFLICT (credential_id) DO NOTHING",
+            params![
+                username,
+                credential.credential_id,
+                credential.public_key,
+                credential.sign_count,
+                name,
+                now
+            ],
+        )
+        .context("Failed to insert passkey")?;
+    if inserted == 0 {
+        return Err(Error::InvalidArguments(anyhow!(
+            "Passkey is already registered"
+        )));
+    }
+
+    Ok(())
+}
+
+fn start_login(rp: &RelyingParty, now: u64, conn: &Connection) -> anyhow::Result<RequestOptions> {
+    let challenge = create_challenge(Ceremony::Login, None, now, conn)?;
+
+    Ok(RequestOptions {
+        challenge: base64url(&challenge),
+        rp_id: rp.id.clone(),
+        allow_credentials: Vec::new(),
+        user_verification: "required",
+        timeout: timeout_millis(),
+    })
+}
+
+/// Checks the assertion of the credential, returns the new signature counter.
+fn verify_assertion(
+    response: &AssertionResponse,
+    client_data_json: &[u8],
+    public_key: &[u8],
+    stored_sign_count: u32,
+    rp: &RelyingParty,
+) -> anyhow::Result<u32> {
+    let auth_data = from_base64url(&response.authenticator_data)?;
+    let sign_count = parse_authenticator_data(&auth_data, rp)?.sign_count;
+
+    // Authenticators which count can be told apart from clones of them
+    if sign_count != 0 || stored_sign_count != 0 {
+        ensure!(
+            sign_count > stored_sign_count,
+            "Signature counter went from {stored_sign_count} to {sign_count}"
+        );
+    }
+
+    let signed = [
+        auth_data.as_slice(),
+        Sha256::digest(client_data_json).as_slice(),
+    ]
+    .concat();
+    let signature = from_base64url(&response.signature)?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+        .verify(&signed, &signature)
+        .map_err(|_| anyhow!("Invalid signature"))?;
+
+    Ok(sign_count)
+}
+
+/// Returns the user the passkey belongs to if the assertion is valid.
+fn login(
+    request: &LoginRequest,
+    rp: &RelyingParty,
+    now: u64,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let invalid = |e: anyhow::Error| {
+        debug!("Failed passkey login: {e:#}");
+        Error::InvalidLogin
+    };
+
+    let client_data_json = from_base64url(&request.response.client_data_json).map_err(invalid)?;
+    let challenge = verify_client_data(&client_data_json, Ceremony::Login, rp).map_err(invalid)?;
+    if !take_challenge(&challenge, Ceremony::Login, None, now, conn)? {
+        return Err(Error::InvalidChallenge);
+    }
+
+    let credential_id = from_base64url(&request.id).map_err(invalid)?;
+    let credential: Option<(i64, String, Vec<u8>, u32)> = conn
+        .query_row(
+            "SELECT id, username, public_key, sign_count \
+            FROM webauthn_credentials WHERE credential_id = ?1",
+            params![credential_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .context("Failed to query passkey")?;
+    let (id, username, public_key, sign_count) =
+        credential.ok_or_else(|| invalid(anyhow!("Unknown passkey")))?;
+
+    if let Some(user_handle) = &request.response.user_handle {
+        if from_base64url(user_handle).map_err(invalid)? != username.as_bytes() {
+            return Err(invalid(anyhow!("Passkey belongs to another user")));
+        }
+    }
+
+    let sign_count = verify_assertion(
+        &request.response,
+        &client_data_json,
+        &public_key,
+        sign_count,
+        rp,
+    )
+    .map_err(invalid)?;
+
+    conn.execute(
+        "UPDATE webauthn_credentials SET sign_count = ?1, last_used_at = ?2 WHERE id = ?3",
+        params![sign_count, now, id],
+    )
+    .context("Failed to update passkey")?;
+
+    Ok(username)
+}
+
+fn relying_party(state: &AppState) -> Result<RelyingParty, Error> {
+    state.relying_party.clone().ok_or(Error::NotFound)
+}
+
+pub(super) async fn post_register_start(
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<CreationOptions>, Error> {
+    let rp = relying_party(&state)?;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let options = state
+        .db
+        .call(move |conn| start_registration(&username, &rp, now, conn))
+        .await?;
+
+    Ok(Json(options))
+}
+
+pub(super) async fn post_register(
+    request: Result<Json<RegisterRequest>, JsonRejection>,
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<&'static str>, Error> {
+    let Json(request) = request?;
+    let rp = relying_party(&state)?;
+
+    if !verify_password(&username, &request.password, &state).await? {
+        return Err(Error::InvalidPassword);
+    }
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    state
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+            register(&username, &request, &rp, now, &tx)?;
+            tx.commit().context("Failed to commit transaction")?;
+
+            Ok::<_, Error>(())
+        })
+        .await?;
+
+    Ok(Json("Success"))
+}
+
+pub(super) async fn post_login_start(
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<RequestOptions>, Error> {
+    let rp = relying_party(&state)?;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let options = state
+        .db
+        .call(move |conn| start_login(&rp, now, conn))
+        .await?;
+
+    Ok(Json(options))
+}
+
+pub(super) async fn post_login(
+    request: Result<Json<LoginRequest>, JsonRejection>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<LoginResponse>, Error> {
+    let Json(request) = request?;
+    let rp = relying_party(&state)?;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip().to_string());
+    let user_agent = user_agent.map(|TypedHeader(agent)| agent.to_string());
+
+    let response = state
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+            let username = login(&request, &rp, now, &tx)?;
+
+            // A verified passkey is something the user has and knows or is, no second factor needed
+            lockout::record_success(&username, ip.as_deref(), &tx)?;
+            let tokens = create_session(&username, user_agent, ip, now, &tx)?;
+            tx.commit().context("Failed to commit transaction")?;
+
+            Ok::<_, Error>(LoginResponse {
+                bearer_token: tokens.bearer_token,
+                refresh_token: tokens.refresh_token,
+                username,
+            })
+        })
+        .await?;
+
+    Ok(Json(response))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::insert_user;
+    use assert_matches::assert_matches;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    const ORIGIN: &str = "https://friends.example.com";
+
+    /// Does what a security key does, without the key.
+    struct Authenticator {
+        key_pair: EcdsaKeyPair,
+        credential_id: Vec<u8>,
+        sign_count: u32,
+        /// Whether the authenticator checks a PIN or biometrics.
+        verifies_user: bool,
+        rng: SystemRandom,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+
+            let mut credential_id = vec![0u8; 16];
+            OsRng.fill_bytes(&mut credential_id);
+
+            Authenticator {
+                key_pair,
+                credential_id,
+                sign_count: 0,
+                verifies_user: true,
+                rng,
+            }
+        }
+
+        fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+            serde_json::to_vec(&serde_json::json!({
+                "type": kind,
+                "challenge": challenge,
+                "origin": origin,
+                "crossOrigin": false,
+            }))
+            .unwrap()
+        }
+
+        fn authenticator_data(&self, mut flags: u8) -> Vec<u8> {
+            if self.verifies_user {
+                flags |= FLAG_USER_VERIFIED;
+            }
+
+            let rp_id_hash = Sha256::digest(b"friends.example.com");
+            [
+                rp_id_hash.as_slice(),
+                &[flags][..],
+                &self.sign_count.to_be_bytes()[..],
+            ]
+            .concat()
+        }
+
+        fn register(&self, options: &CreationOptions, origin: &str) -> RegistrationCredential {
+            let point = self.key_pair.public_key().as_ref();
+            let key = Value::Map(vec![
+                (Value::Integer(1), Value::Integer(2)),
+                (Value::Integer(3), Value::Integer(COSE_ES256)),
+                (Value::Integer(-1), Value::Integer(1)),
+                (Value::Integer(-2), Value::Bytes(point[1..33].to_vec())),
+                (Value::Integer(-3), Value::Bytes(point[33..].to_vec())),
+            ]);
+
+            let auth_data = [
+                self.authenticator_data(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL),
+                vec![0; 16],
+                (self.credential_id.len() as u16).to_be_bytes().to_vec(),
+                self.credential_id.clone(),
+                cbor::encode(&key),
+            ]
+            .concat();
+            let attestation = Value::Map(vec![
+                (Value::Text("fmt".into()), Value::Text("none".into())),
+                (Value::Text("attStmt".into()), Value::Map(vec![])),
+                (Value::Text("authData".into()), Value::Bytes(auth_data)),
+            ]);
+
+            RegistrationCredential {
+                id: base64url(&self.credential_id),
+                response: AttestationResponse {
+                    client_data_json: base64url(&Self::client_data(
+                        "webauthn.create",
+                        &options.challenge,
+                        origin,
+                    )),
+                    attestation_object: base64url(&cbor::encode(&attestation)),
+                },
+            }
+        }
+
+        fn login(&mut self, options: &RequestOptions, username: &str) -> LoginRequest {
+            self.sign_count += 1;
+
+            let client_data = Self::client_data("webauthn.get", &options.challenge, ORIGIN);
+            let auth_data = self.authenticator_data(FLAG_USER_PRESENT);
+            let signed = [
+                auth_data.as_slice(),
+                Sha256::digest(&client_data).as_slice(),
+            ]
+            .concat();
+            let signature = self.key_pair.sign(&self.rng, &signed).unwrap();
+
+            LoginRequest {
+                id: base64url(&self.credential_id),
+                response: AssertionResponse {
+                    client_data_json: base64url(&client_data),
+                    authenticator_data: base64url(&auth_data),
+                    signature: base64url(signature.as_ref()),
+                    user_handle: Some(base64url(username.as_bytes())),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn relying_party_origin() {
+        let rp = RelyingParty::new("https://friends.example.com:8443/").unwrap();
+        assert_eq!(rp.id, "friends.example.com");
+        assert_eq!(rp.origin, "https://friends.example.com:8443");
+
+        assert!(RelyingParty::new("http://localhost:5173").is_ok());
+        assert!(RelyingParty::new("http://friends.example.com").is_err());
+        assert!(RelyingParty::new("https://example.com/friends").is_err());
+    }
+
+    #[tokio::test]
+    async fn register_and_login() {
+        let state = AppState::in_memory_db().await;
+        let rp = state.relying_party.clone().unwrap();
+
+        state
+            .db
+            .call(move |conn| {
+                let user = insert_user("test", conn);
+                let now = 1_000_000;
+                let mut authenticator = Authenticator::new();
+
+                let options = start_registration(&user, &rp, now, conn).unwrap();
+                assert_eq!(options.rp.id, "friends.example.com");
+                assert_eq!(options.user.id, base64url(b"test"));
+
+                // Passkeys only work where they were registered
+                let phished = authenticator.register(&options, "https://friends.example.net");
+                let request = RegisterRequest {
+                    name: None,
+                    password: String::new(),
+                    credential: phished,
+                };
+                assert_matches!(
+                    register(&user, &request, &rp, now, conn),
+                    Err(Error::InvalidArguments(_))
+                );
+
+                let request = RegisterRequest {
+                    name: Some("Security key".into()),
+                    password: String::new(),
+                    credential: authenticator.register(&options, ORIGIN),
+                };
+                register(&user, &request, &rp, now, conn).unwrap();
+
+                // The challenge was used up
+                assert_matches!(
+                    register(&user, &request, &rp, now, conn),
+                    Err(Error::InvalidArguments(_))
+                );
+                let options = start_registration(&user, &rp, now, conn).unwrap();
+                assert_eq!(
+                    options.exclude_credentials[0].id,
+                    base64url(&authenticator.credential_id)
+                );
+
+                let options = start_login(&rp, now, conn).unwrap();
+                let request = authenticator.login(&options, &user);
+                assert_eq!(login(&request, &rp, now, conn).unwrap(), user);
+                assert_matches!(
+                    login(&request, &rp, now, conn),
+                    Err(Error::InvalidChallenge)
+                );
+
+                // A clone which is behind on signatures
+                let options = start_login(&rp, now, conn).unwrap();
+                authenticator.sign_count = 0;
+                let request = authenticator.login(&options, &user);
+                assert_matches!(login(&request, &rp, now, conn), Err(Error::InvalidLogin));
+
+                // Challenges expire
+                let options = start_login(&rp, now, conn).unwrap();
+                authenticator.sign_count = 5;
+                let request = authenticator.login(&options, &user);
+                let later = now + CHALLENGE_SECONDS;
+                assert_matches!(
+                    login(&request, &rp, later, conn),
+                    Err(Error::InvalidChallenge)
+                );
+
+                // Without a PIN or biometrics the passkey is only something the user has
+                let options = start_login(&rp, now, conn).unwrap();
+                authenticator.verifies_user = false;
+                let request = authenticator.login(&options, &user);
+                assert_matches!(login(&request, &rp, now, conn), Err(Error::InvalidLogin));
+                authenticator.verifies_user = true;
+
+                // Signatures have to be of the registered key
+                let options = start_login(&rp, now, conn).unwrap();
+                let mut request = authenticator.login(&options, &user);
+                request.response.signature = base64url(b"not a signature");
+                assert_matches!(login(&request, &rp, now, conn), Err(Error::InvalidLogin));
+
+                let (sign_count, last_used_at): (u32, Option<u64>) = conn
+                    .query_row(
+                        "SELECT sign_count, last_used_at FROM webauthn_credentials",
+                        params![],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .unwrap();
+                assert_eq!((sign_count, last_used_at), (1, Some(now)));
+            })
+            .await;
+    }
+}
@@ -0,0 +1,201 @@
+//! Just enough of CBOR (RFC 8949) for attestation objects and COSE keys.
+
+use anyhow::{bail, Context};
+
+/// Attestation objects are only a few levels deep, anything deeper is not one.
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    /// Looks up a key of a map.
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i128> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes the first item of the data and returns what follows it.
+pub fn decode(data: &[u8]) -> anyhow::Result<(Value, &[u8])> {
+    decode_item(data, 0)
+}
+
+fn take(data: &[u8], n: usize) -> anyhow::Result<(&[u8], &[u8])> {
+    if data.len() < n {
+        bail!("Unexpected end of CBOR data");
+    }
+
+    Ok(data.split_at(n))
+}
+
+fn decode_item(data: &[u8], depth: usize) -> anyhow::Result<(Value, &[u8])> {
+    if depth > MAX_DEPTH {
+        bail!("CBOR data is nested too deeply");
+    }
+
+    let (&initial, rest) = data.split_first().context("Unexpected end of CBOR data")?;
+    let (major, info) = (initial >> 5, initial & 0x1f);
+
+    let (argument, mut rest) = match info {
+        0..=23 => (info as u64, rest),
+        24..=27 => {
+            let (bytes, rest) = take(rest, 1 << (info - 24))?;
+            let argument = bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+            (argument, rest)
+        }
+        _ => bail!("Indefinite lengths are not supported"),
+    };
+
+    let value = match major {
+        0 => Value::Integer(argument as i128),
+        1 => Value::Integer(-1 - argument as i128),
+        2 | 3 => {
+            let len = usize::try_from(argument)?;
+            let (bytes, remaining) = take(rest, len)?;
+            rest = remaining;
+            if major == 2 {
+                Value::Bytes(bytes.to_vec())
+            } else {
+                Value::Text(String::from_utf8(bytes.to_vec()).context("Invalid CBOR text")?)
+            }
+        }
+        4 => {
+            let mut items = Vec::new();
+            for _ in 0..argument {
+                let (item, remaining) = decode_item(rest, depth + 1)?;
+                items.push(item);
+                rest = remaining;
+            }
+            Value::Array(items)
+        }
+        5 => {
+            let mut entries = Vec::new();
+            for _ in 0..argument {
+                let (key, remaining) = decode_item(rest, depth + 1)?;
+                let (value, remaining) = decode_item(remaining, depth + 1)?;
+                entries.push((key, value));
+                rest = remaining;
+            }
+            Value::Map(entries)
+        }
+        7 => match info {
+            20 => Value::Bool(false),
+            21 => Value::Bool(true),
+            22 => Value::Null,
+            _ => bail!("Unsupported CBOR simple value {info}"),
+        },
+        _ => bail!("Unsupported CBOR major type {major}"),
+    };
+
+    Ok((value, rest))
+}
+
+/// Only authenticators encode, which only exist in tests.
+#[cfg(test)]
+pub fn encode(value: &Value) -> Vec<u8> {
+    fn head(major: u8, argument: u64, out: &mut Vec<u8>) {
+        let major = major << 5;
+        if argument < 24 {
+            out.push(major | argument as u8);
+        } else if argument <= u8::MAX as u64 {
+            out.push(major | 24);
+            out.push(argument as u8);
+        } else if argument <= u16::MAX as u64 {
+            out.push(major | 25);
+            out.extend((argument as u16).to_be_bytes());
+        } else {
+            out.push(major | 27);
+            out.extend(argument.to_be_bytes());
+        }
+    }
+
+    let mut out = Vec::new();
+    match value {
+        Value::Integer(i) if *i >= 0 => head(0, *i as u64, &mut out),
+        Value::Integer(i) => head(1, (-1 - *i) as u64, &mut out),
+        Value::Bytes(bytes) => {
+            head(2, bytes.len() as u64, &mut out);
+            out.extend(bytes);
+        }
+        Value::Text(text) => {
+            head(3, text.len() as u64, &mut out);
+            out.extend(text.as_bytes());
+        }
+        Value::Array(items) => {
+            head(4, items.len() as u64, &mut out);
+            for item in items {
+                out.extend(encode(item));
+            }
+        }
+        Value::Map(entries) => {
+            head(5, entries.len() as u64, &mut out);
+            for (key, value) in entries {
+                out.extend(encode(key));
+                out.extend(encode(value));
+            }
+        }
+        Value::Bool(b) => out.push(0xf4 | *b as u8),
+        Value::Null => out.push(0xf6),
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // {"fmt": "none", 1: 2, -1: h'0102', "list": [true, null, 1000]}
+        let value = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Integer(1), Value::Integer(2)),
+            (Value::Integer(-1), Value::Bytes(vec![1, 2])),
+            (
+                Value::Text("list".into()),
+                Value::Array(vec![Value::Bool(true), Value::Null, Value::Integer(1000)]),
+            ),
+        ]);
+
+        let mut data = encode(&value);
+        assert_eq!(&data[..5], &[0xa4, 0x63, b'f', b'm', b't']);
+
+        data.extend([0xff, 0xfe]);
+        let (decoded, rest) = decode(&data).unwrap();
+        assert_eq!(decoded, value);
+        assert_eq!(rest, &[0xff, 0xfe]);
+        assert_eq!(
+            decoded.get(&Value::Integer(-1)).and_then(Value::as_bytes),
+            Some(&[1u8, 2][..])
+        );
+
+        assert!(decode(&data[..data.len() - 4]).is_err());
+        assert!(decode(&[0x9f]).is_err());
+    }
+}
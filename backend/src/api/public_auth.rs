@@ -5,7 +5,7 @@ use axum::{
     response::{IntoResponse, Response},
     Extension, Json,
 };
-use rusqlite::{params, OptionalExtension};
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::Deserialize;
 use serde_json::json;
 use serde_rusqlite::from_row;
@@ -40,14 +40,7 @@ where
 
         let album_key = state
             .db
-            .call(move |conn| {
-                conn.query_row(
-                    r"SELECT album_key FROM album_share_tokens WHERE share_token=?1",
-                    params![path.token],
-                    |row| Ok(from_row::<String>(row).unwrap()),
-                )
-                .optional()
-            })
+            .call(move |conn| album_of_share_token(&path.token, conn))
             .await
             .map_err(anyhow::Error::new)?;
 
@@ -63,6 +56,18 @@ where
     }
 }
 
+/// Albums in the trash can't be viewed with their share tokens until they are restored.
+pub fn album_of_share_token(token: &str, conn: &Connection) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT t.album_key FROM album_share_tokens t \
+        INNER JOIN albums a ON a.key = t.album_key \
+        WHERE t.share_token=?1 AND a.deleted_at IS NULL",
+        params![token],
+        |row| Ok(from_row::<String>(row).unwrap()),
+    )
+    .optional()
+}
+
 #[derive(Debug, Error)]
 pub enum PublicAuthorizationRejection {
     #[error("{0}")]
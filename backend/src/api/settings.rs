@@ -2,7 +2,7 @@ use anyhow::Context;
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use axum::{
     extract::rejection::JsonRejection,
-    routing::{get, put},
+    routing::{delete, get, post, put},
     Extension, Json, Router,
 };
 use rand::rngs::OsRng;
@@ -12,15 +12,26 @@ use serde_rusqlite::from_row;
 
 use std::sync::Arc;
 
-use crate::api::{auth::Authorize, error::Error};
+use crate::api::auth::{self, Authorize, Session};
+use crate::api::error::Error;
 use crate::util::non_empty_str;
 use crate::AppState;
 
+pub mod passkeys;
+pub mod totp;
+
 pub fn api_route() -> Router {
     Router::new()
         .route("/", get(get_settings))
         .route("/", put(put_settings))
         .route("/password", put(put_password))
+        .route("/totp", post(totp::post_totp))
+        .route("/totp", delete(totp::delete_totp))
+        .route("/totp/confirm", post(totp::post_confirm))
+        .route("/totp/recovery-codes", post(totp::post_recovery_codes))
+        .route("/passkeys", get(passkeys::get_passkeys))
+        .route("/passkeys/:id", put(passkeys::put_passkey))
+        .route("/passkeys/:id", delete(passkeys::delete_passkey))
 }
 
 #[derive(Debug, Serialize)]
@@ -34,6 +45,9 @@ pub struct Settings {
     pub featured_album_key: Option<String>,
     pub country: Option<String>,
     pub color_theme: String,
+    /// Default for images without their own setting.
+    pub strip_metadata: bool,
+    pub totp_enabled: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -46,6 +60,8 @@ pub struct DbSettings {
     pub featured_album_key: Option<String>,
     pub country: Option<String>,
     pub color_theme: String,
+    pub strip_metadata: bool,
+    pub totp_enabled: bool,
 }
 
 async fn get_settings(
@@ -63,7 +79,9 @@ async fn get_settings(
                     banner_key, \
                     accent_color, \
                     featured_album_key, \
-                    color_theme \
+                    color_theme, \
+                    strip_metadata, \
+                    totp_secret IS NOT NULL AS totp_enabled \
                 FROM users WHERE username = ?1",
                 params![username],
                 |row| Ok(from_row::<DbSettings>(row).unwrap()),
@@ -83,6 +101,8 @@ async fn get_settings(
             featured_album_key: db_settings.featured_album_key,
             country: db_settings.country,
             color_theme: db_settings.color_theme,
+            strip_metadata: db_settings.strip_metadata,
+            totp_enabled: db_settings.totp_enabled,
         }))
     } else {
         Err(Error::NotFound)
@@ -108,6 +128,7 @@ pub struct PutSettingsRequest {
     pub country: Option<String>,
     #[serde(default, deserialize_with = "non_empty_str")]
     pub color_theme: Option<String>,
+    pub strip_metadata: Option<bool>,
 }
 
 async fn put_settings(
@@ -152,13 +173,39 @@ pub struct PutPasswordRequest {
 
 async fn put_password(
     request: Result<Json<PutPasswordRequest>, JsonRejection>,
-    Authorize(username): Authorize,
+    Session { id, username }: Session,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Result<Json<&'static str>, Error> {
     let Json(request) = request?;
 
-    let cusername = username.clone();
-    let result = state
+    if !verify_password(&username, &request.old, &state).await? {
+        return Err(Error::InvalidPassword);
+    }
+
+    // Whoever knew the old password should not stay logged in
+    state
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+            set_password(&username, &request.new, &tx)?;
+            auth::revoke_sessions(&username, Some(id), &tx)?;
+            tx.commit().context("Failed to commit transaction")?;
+
+            Ok::<_, Error>(())
+        })
+        .await?;
+
+    Ok(Json("Success"))
+}
+
+/// Whether the password is the one of the user.
+pub(crate) async fn verify_password(
+    username: &str,
+    password: &str,
+    state: &AppState,
+) -> Result<bool, Error> {
+    let cusername = username.to_owned();
+    let password_hash = state
         .db
         .call(move |conn| {
             conn.query_row(
@@ -170,29 +217,15 @@ async fn put_password(
             .optional()
         })
         .await
-        .context("Failed to query username")?;
+        .context("Failed to query username")?
+        .ok_or(Error::NotFound)?;
 
-    if let Some(password_hash) = result {
-        let argon2 = Argon2::default();
-        let parsed_hash = PasswordHash::new(&password_hash).context("Failed creating hash")?;
+    let argon2 = Argon2::default();
+    let parsed_hash = PasswordHash::new(&password_hash).context("Failed creating hash")?;
 
-        if argon2
-            .verify_password(request.old.as_bytes(), &parsed_hash)
-            .is_ok()
-        {
-            state
-                .db
-                .call(move |conn| set_password(&username, &request.new, conn))
-                .await
-                .context("Failed to set password")?;
-
-            Ok(Json("Success"))
-        } else {
-            Err(Error::InvalidPassword)
-        }
-    } else {
-        Err(Error::NotFound)
-    }
+    Ok(argon2
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
 }
 
 pub fn set_password(username: &str, password: &str, conn: &Connection) -> Result<(), Error> {
@@ -248,6 +281,10 @@ impl PutSettingsRequest {
             result.push("color_theme = ?")
         }
 
+        if self.strip_metadata.is_some() {
+            result.push("strip_metadata = ?")
+        }
+
         result.join(", ")
     }
 
@@ -286,6 +323,10 @@ impl PutSettingsRequest {
             params.push(Box::new(color_theme));
         }
 
+        if let Some(strip_metadata) = self.strip_metadata.take() {
+            params.push(Box::new(strip_metadata));
+        }
+
         params
     }
 }
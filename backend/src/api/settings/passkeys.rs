@@ -0,0 +1,192 @@
+use anyhow::Context;
+use axum::{
+    extract::{rejection::JsonRejection, Path},
+    Extension, Json,
+};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::api::auth::Authorize;
+use crate::api::error::Error;
+use crate::api::login::passkey::MAXIMUM_NAME_LENGTH;
+use crate::util::check_length;
+use crate::AppState;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyInfo {
+    pub id: i64,
+    pub name: String,
+    pub created_at: u64,
+    pub last_used_at: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameRequest {
+    name: String,
+}
+
+/// Passkeys of `username`, oldest first.
+pub fn select_passkeys(username: &str, conn: &Connection) -> anyhow::Result<Vec<PasskeyInfo>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, name, created_at, last_used_at FROM webauthn_credentials \
+            WHERE username = ?1 ORDER BY created_at, id",
+        )
+        .context("Failed to prepare statement for passkeys query")?;
+
+    let passkeys = stmt
+        .query_map(params![username], |row| {
+            Ok(PasskeyInfo {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                created_at: row.get(2)?,
+                last_used_at: row.get(3)?,
+            })
+        })
+        .context("Failed to query passkeys")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect passkeys")?;
+
+    Ok(passkeys)
+}
+
+/// Returns whether `username` had a passkey with the id.
+pub fn rename_passkey(
+    id: i64,
+    name: &str,
+    username: &str,
+    conn: &Connection,
+) -> anyhow::Result<bool> {
+    let renamed = conn
+        .execute(
+            "UPDATE webauthn_credentials SET name = ?1 WHERE id = ?2 AND username = ?3",
+            params![name, id, username],
+        )
+        .context("Failed to rename passkey")?;
+
+    Ok(renamed > 0)
+}
+
+/// Returns whether `username` had a passkey with the id.
+pub fn remove_passkey(id: i64, username: &str, conn: &Connection) -> anyhow::Result<bool> {
+    let deleted = conn
+        .execute(
+            "DELETE FROM webauthn_credentials WHERE id = ?1 AND username = ?2",
+            params![id, username],
+        )
+        .context("Failed to delete passkey")?;
+
+    Ok(deleted > 0)
+}
+
+pub(super) async fn get_passkeys(
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<PasskeyInfo>>, Error> {
+    let passkeys = state
+        .db
+        .call(move |conn| select_passkeys(&username, conn))
+        .await?;
+
+    Ok(Json(passkeys))
+}
+
+pub(super) async fn put_passkey(
+    Path(id): Path<i64>,
+    request: Result<Json<RenameRequest>, JsonRejection>,
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<&'static str>, Error> {
+    let Json(request) = request?;
+    check_length("name", Some(&request.name), MAXIMUM_NAME_LENGTH)?;
+
+    let renamed = state
+        .db
+        .call(move |conn| rename_passkey(id, &request.name, &username, conn))
+        .await?;
+
+    if renamed {
+        Ok(Json("Success"))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+pub(super) async fn delete_passkey(
+    Path(id): Path<i64>,
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<&'static str>, Error> {
+    let removed = state
+        .db
+        .call(move |conn| remove_passkey(id, &username, conn))
+        .await?;
+
+    if removed {
+        Ok(Json("Success"))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::insert_user;
+
+    fn insert_passkey(username: &str, name: &str, created_at: u64, conn: &Connection) -> i64 {
+        conn.execute(
+            "INSERT INTO webauthn_credentials \
+            (username, credential_id, public_key, sign_count, name, created_at) \
+            VALUES (?1, ?2, x'04', 0, ?3, ?4)",
+            params![username, name.as_bytes(), name, created_at],
+        )
+        .unwrap();
+
+        conn.last_insert_rowid()
+    }
+
+    #[tokio::test]
+    async fn manage_passkeys() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let other = insert_user("other", conn);
+                let phone = insert_passkey(&user, "phone", 20, conn);
+                let key = insert_passkey(&user, "key", 10, conn);
+                let foreign = insert_passkey(&other, "foreign", 30, conn);
+
+                let ids: Vec<_> = select_passkeys(&user, conn)
+                    .unwrap()
+                    .iter()
+                    .map(|p| p.id)
+                    .collect();
+                assert_eq!(ids, vec![key, phone]);
+
+                // Passkeys of other users can't be touched
+                assert!(!rename_passkey(foreign, "mine", &user, conn).unwrap());
+                assert!(!remove_passkey(foreign, &user, conn).unwrap());
+
+                assert!(rename_passkey(key, "Security key", &user, conn).unwrap());
+                assert!(remove_passkey(phone, &user, conn).unwrap());
+                assert_eq!(
+                    select_passkeys(&user, conn).unwrap(),
+                    vec![PasskeyInfo {
+                        id: key,
+                        name: "Security key".into(),
+                        created_at: 10,
+                        last_used_at: None,
+                    }]
+                );
+                assert_eq!(select_passkeys(&other, conn).unwrap().len(), 1);
+            })
+            .await;
+    }
+}
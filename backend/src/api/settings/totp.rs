@@ -0,0 +1,400 @@
+use anyhow::{anyhow, Context};
+use axum::{extract::rejection::JsonRejection, Extension, Json};
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::api::auth::{hash_token, Authorize};
+use crate::api::error::Error;
+use crate::util::{hmac, percent_encode};
+use crate::AppState;
+
+/// Shown by authenticator apps next to the username.
+const ISSUER: &str = "hi!friends";
+const SECRET_LENGTH: usize = 20;
+const DIGITS: u32 = 6;
+const STEP_SECONDS: u64 = 30;
+/// Codes of the steps before and after the current one are accepted too, clocks drift.
+const ALLOWED_DRIFT: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+    /// For typing into an authenticator which can't scan the QR code of the URI.
+    secret: String,
+    provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CodeRequest {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DisableRequest {
+    password: String,
+}
+
+/// RFC 4648 without padding, the encoding authenticators expect for secrets.
+fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut result = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    result
+}
+
+fn provisioning_uri(username: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        percent_encode(ISSUER),
+        percent_encode(username),
+        base32(secret),
+        percent_encode(ISSUER),
+    )
+}
+
+/// HOTP as described in RFC 4226, TOTP uses the time step as the counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let hash = hmac::<Sha1>(secret, &counter.to_be_bytes());
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The time step the code belongs to, if it is valid right now.
+fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = now / STEP_SECONDS;
+    (current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT)
+        .find(|&step| hotp(secret, step) == code)
+}
+
+/// Codes are typed in with spaces or dashes and in any case.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+pub fn is_enabled(username: &str, conn: &Connection) -> anyhow::Result<bool> {
+    let enabled = conn
+        .query_row(
+            "SELECT totp_secret IS NOT NULL FROM users WHERE username = ?1",
+            params![username],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Failed to query two-factor authentication")?;
+
+    Ok(enabled.unwrap_or(false))
+}
+
+/// Checks a code of the authenticator or one of the recovery codes. Either can only be used once.
+pub fn verify(username: &str, code: &str, now: u64, conn: &Connection) -> anyhow::Result<bool> {
+    let code = normalize(code);
+
+    let result: Option<(Option<Vec<u8>>, Option<u64>)> = conn
+        .query_row(
+            "SELECT totp_secret, totp_last_step FROM users WHERE username = ?1",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .context("Failed to query TOTP secret")?;
+    let (secret, last_step) = match result {
+        Some((Some(secret), last_step)) => (secret, last_step),
+        _ => return Ok(false),
+    };
+
+    if let Some(step) = matching_step(&secret, &code, now) {
+        if last_step.map_or(false, |last| step <= last) {
+            return Ok(false);
+        }
+
+        conn.execute(
+            "UPDATE users SET totp_last_step = ?1 WHERE username = ?2",
+            params![step, username],
+        )
+        .context("Failed to update last TOTP step")?;
+
+        return Ok(true);
+    }
+
+    let used = conn
+        .execute(
+            "DELETE FROM recovery_codes WHERE username = ?1 AND code_hash = ?2",
+            params![username, hash_token(&code)],
+        )
+        .context("Failed to use recovery code")?;
+
+    Ok(used > 0)
+}
+
+/// Replaces the recovery codes of the user with new ones, which are only shown this once.
+fn replace_recovery_codes(username: &str, conn: &Connection) -> anyhow::Result<Vec<String>> {
+    conn.execute(
+        "DELETE FROM recovery_codes WHERE username = ?1",
+        params![username],
+    )
+    .context("Failed to delete recovery codes")?;
+
+    let mut codes = Vec::new();
+    for _ in 0..RECOVERY_CODES {
+        let mut bytes = [0u8; 5];
+        OsRng.fill_bytes(&mut bytes);
+        let code = base32(&bytes).to_lowercase();
+
+        conn.execute(
+            "INSERT INTO recovery_codes (username, code_hash) VALUES (?1, ?2)",
+            params![username, hash_token(&code)],
+        )
+        .context("Failed to insert recovery code")?;
+
+        codes.push(format!("{}-{}", &code[..4], &code[4..]));
+    }
+
+    Ok(codes)
+}
+
+/// Turns off two-factor authentication, also used when a user lost their authenticator.
+pub fn disable(username: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE users SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL \
+        WHERE username = ?1",
+        params![username],
+    )
+    .context("Failed to disable two-factor authentication")?;
+    conn.execute(
+        "DELETE FROM recovery_codes WHERE username = ?1",
+        params![username],
+    )
+    .context("Failed to delete recovery codes")?;
+
+    Ok(())
+}
+
+/// Starts enrollment with a new secret, which is only used once a code of it was confirmed.
+fn enroll(username: &str, conn: &Connection) -> Result<Enrollment, Error> {
+    if is_enabled(username, conn)? {
+        return Err(Error::InvalidArguments(anyhow!(
+            "Two-factor authentication is already enabled"
+        )));
+    }
+
+    let mut secret = vec![0u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    conn.execute(
+        "UPDATE users SET totp_pending_secret = ?1 WHERE username = ?2",
+        params![secret, username],
+    )
+    .context("Failed to store pending TOTP secret")?;
+
+    Ok(Enrollment {
+        secret: base32(&secret),
+        provisioning_uri: provisioning_uri(username, &secret),
+    })
+}
+
+fn confirm(username: &str, code: &str, now: u64, conn: &Connection) -> Result<Vec<String>, Error> {
+    let secret: Option<Vec<u8>> = conn
+        .query_row(
+            "SELECT totp_pending_secret FROM users WHERE username = ?1",
+            params![username],
+            |row| row.get(0),
+        )
+        .optional()
+        .context("Failed to query pending TOTP secret")?
+        .flatten();
+    let secret = secret.ok_or_else(|| {
+        Error::InvalidArguments(anyhow!("Two-factor authentication was not set up"))
+    })?;
+
+    let step = matching_step(&secret, &normalize(code), now).ok_or(Error::InvalidTwoFactorCode)?;
+    conn.execute(
+        "UPDATE users SET totp_secret = totp_pending_secret, totp_pending_secret = NULL, \
+        totp_last_step = ?1 WHERE username = ?2",
+        params![step, username],
+    )
+    .context("Failed to enable two-factor authentication")?;
+
+    Ok(replace_recovery_codes(username, conn)?)
+}
+
+pub(super) async fn post_totp(
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Enrollment>, Error> {
+    let enrollment = state.db.call(move |conn| enroll(&username, conn)).await?;
+
+    Ok(Json(enrollment))
+}
+
+pub(super) async fn post_confirm(
+    request: Result<Json<CodeRequest>, JsonRejection>,
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<RecoveryCodes>, Error> {
+    let Json(request) = request?;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let recovery_codes = state
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+            let codes = confirm(&username, &request.code, now, &tx)?;
+            tx.commit().context("Failed to commit transaction")?;
+
+            Ok::<_, Error>(codes)
+        })
+        .await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+pub(super) async fn post_recovery_codes(
+    request: Result<Json<CodeRequest>, JsonRejection>,
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<RecoveryCodes>, Error> {
+    let Json(request) = request?;
+
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let recovery_codes = state
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction().context("Failed to create transaction")?;
+            if !verify(&username, &request.code, now, &tx)? {
+                return Err(Error::InvalidTwoFactorCode);
+            }
+            let codes = replace_recovery_codes(&username, &tx)?;
+            tx.commit().context("Failed to commit transaction")?;
+
+            Ok(codes)
+        })
+        .await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+pub(super) async fn delete_totp(
+    request: Result<Json<DisableRequest>, JsonRejection>,
+    Authorize(username): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<&'static str>, Error> {
+    let Json(request) = request?;
+
+    if !super::verify_password(&username, &request.password, &state).await? {
+        return Err(Error::InvalidPassword);
+    }
+
+    state.db.call(move |conn| disable(&username, conn)).await?;
+
+    Ok(Json("Success"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::insert_user;
+
+    #[test]
+    fn rfc_test_vectors() {
+        // RFC 6238 appendix B, truncated to six digits
+        let secret = b"12345678901234567890";
+        assert_eq!(hotp(secret, 59 / STEP_SECONDS), 287082);
+        assert_eq!(hotp(secret, 1111111109 / STEP_SECONDS), 81804);
+        assert_eq!(hotp(secret, 2000000000 / STEP_SECONDS), 279037);
+
+        assert_eq!(
+            matching_step(secret, "081804", 1111111109 + 30),
+            Some(37037036)
+        );
+        assert_eq!(matching_step(secret, "081804", 1111111109 + 60), None);
+        assert_eq!(matching_step(secret, "81804", 1111111109), None);
+
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[tokio::test]
+    async fn enroll_and_verify() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let now = 1_000_000;
+
+                let enrollment = enroll(&user, conn).unwrap();
+                assert!(enrollment
+                    .provisioning_uri
+                    .starts_with("otpauth://totp/hi%21friends:test?secret="));
+                assert!(!is_enabled(&user, conn).unwrap());
+
+                let secret: Vec<u8> = conn
+                    .query_row(
+                        "SELECT totp_pending_secret FROM users WHERE username = ?1",
+                        params![user],
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+                let code = |step: u64| format!("{:06}", hotp(&secret, step));
+
+                assert!(matches!(
+                    confirm(&user, "000000", now, conn),
+                    Err(Error::InvalidTwoFactorCode)
+                ));
+                let recovery_codes = confirm(&user, &code(now / STEP_SECONDS), now, conn).unwrap();
+                assert_eq!(recovery_codes.len(), RECOVERY_CODES);
+                assert!(is_enabled(&user, conn).unwrap());
+
+                // The code used for confirming can't be used again
+                let later = now + STEP_SECONDS;
+                assert!(!verify(&user, &code(now / STEP_SECONDS), later, conn).unwrap());
+                assert!(verify(&user, &code(later / STEP_SECONDS), later, conn).unwrap());
+
+                // Recovery codes only work once, in any spelling
+                let recovery = recovery_codes[0].to_uppercase().replace('-', " ");
+                assert!(verify(&user, &recovery, later, conn).unwrap());
+                assert!(!verify(&user, &recovery_codes[0], later, conn).unwrap());
+                assert!(verify(&user, &recovery_codes[1], later, conn).unwrap());
+
+                disable(&user, conn).unwrap();
+                assert!(!is_enabled(&user, conn).unwrap());
+                assert!(!verify(&user, &recovery_codes[2], later, conn).unwrap());
+            })
+            .await;
+    }
+}
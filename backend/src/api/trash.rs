@@ -0,0 +1,551 @@
+use anyhow::Context;
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_rusqlite::from_row;
+use tracing::*;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::api::auth::Authorize;
+use crate::api::error::Error;
+use crate::api::image::{tombstone, DbImage, Image};
+use crate::AppState;
+
+/// Used when `TRASH_RETENTION_DAYS` is not set.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(3600 * 24 * 30);
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+pub fn api_route() -> Router {
+    Router::new()
+        .route("/", get(get_trash))
+        .route("/images/:key/restore", post(restore_image))
+        .route("/albums/:key/restore", post(restore_album))
+        .route("/comments/:id/restore", post(restore_comment))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Trash {
+    images: Vec<TrashedImage>,
+    albums: Vec<TrashedAlbum>,
+    comments: Vec<TrashedComment>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedImage {
+    #[serde(flatten)]
+    image: Image,
+    deleted_at: u64,
+    purge_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedAlbum {
+    key: String,
+    title: String,
+    cover_key: String,
+    deleted_at: u64,
+    purge_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedComment {
+    id: i64,
+    text: String,
+    author: String,
+    image_key: String,
+    album_key: String,
+    deleted_at: u64,
+    purge_at: u64,
+}
+
+pub fn add_image(key: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE images SET deleted_at = ?1 WHERE key = ?2",
+        params![now, key],
+    )
+    .context("Failed to move image to the trash")?;
+
+    Ok(())
+}
+
+pub fn add_album(key: &str, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE albums SET deleted_at = ?1 WHERE key = ?2",
+        params![now, key],
+    )
+    .context("Failed to move album to the trash")?;
+
+    Ok(())
+}
+
+pub fn add_comment(id: i64, now: u64, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE comments SET deleted_at = ?1 WHERE id = ?2",
+        params![now, id],
+    )
+    .context("Failed to move comment to the trash")?;
+
+    Ok(())
+}
+
+/// Everything `user` moved to the trash, or may restore, most recently deleted first.
+fn select_trash(user: &str, retention: u64, conn: &Connection) -> anyhow::Result<Trash> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT * FROM images \
+            WHERE uploader = ?1 AND deleted_at IS NOT NULL \
+            ORDER BY deleted_at DESC",
+        )
+        .context("Failed to prepare statement for trashed images query")?;
+    let images = stmt
+        .query_map(params![user], |row| {
+            let deleted_at: u64 = row.get("deleted_at")?;
+
+            Ok(TrashedImage {
+                image: Image::from_db(from_row::<DbImage>(row).unwrap()),
+                deleted_at,
+                purge_at: deleted_at + retention,
+            })
+        })
+        .context("Failed to query trashed images")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect trashed images")?;
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT key, title, cover_key, deleted_at FROM albums \
+            WHERE author = ?1 AND deleted_at IS NOT NULL \
+            ORDER BY deleted_at DESC",
+        )
+        .context("Failed to prepare statement for trashed albums query")?;
+    let albums = stmt
+        .query_map(params![user], |row| {
+            let deleted_at: u64 = row.get(3)?;
+
+            Ok(TrashedAlbum {
+                key: row.get(0)?,
+                title: row.get(1)?,
+                cover_key: row.get(2)?,
+                deleted_at,
+                purge_at: deleted_at + retention,
+            })
+        })
+        .context("Failed to query trashed albums")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect trashed albums")?;
+
+    // Comments are deleted by their author or the author of the album, both can restore them
+    let mut stmt = conn
+        .prepare(
+            "SELECT c.id, c.text, c.author, c.image_key, c.album_key, c.deleted_at \
+            FROM comments c \
+            INNER JOIN albums a ON a.key = c.album_key \
+            WHERE (c.author = ?1 OR a.author = ?1) AND c.deleted_at IS NOT NULL \
+            ORDER BY c.deleted_at DESC",
+        )
+        .context("Failed to prepare statement for trashed comments query")?;
+    let comments = stmt
+        .query_map(params![user], |row| {
+            let deleted_at: u64 = row.get(5)?;
+
+            Ok(TrashedComment {
+                id: row.get(0)?,
+                text: row.get(1)?,
+                author: row.get(2)?,
+                image_key: row.get(3)?,
+                album_key: row.get(4)?,
+                deleted_at,
+                purge_at: deleted_at + retention,
+            })
+        })
+        .context("Failed to query trashed comments")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect trashed comments")?;
+
+    Ok(Trash {
+        images,
+        albums,
+        comments,
+    })
+}
+
+async fn get_trash(
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Trash>, Error> {
+    let retention = state.trash_retention.as_secs();
+
+    state
+        .db
+        .call(move |conn| Ok(Json(select_trash(&user, retention, conn)?)))
+        .await
+}
+
+/// Restores a row if `user` owns it, `owners` selects its owner and optionally a second one who
+/// may restore it as well.
+fn restore(
+    user: &str,
+    owners: &str,
+    restore: &str,
+    key: &dyn rusqlite::ToSql,
+    conn: &Connection,
+) -> Result<Json<()>, Error> {
+    let owners: Option<(String, Option<String>)> = conn
+        .query_row(owners, params![key], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()
+        .context("Failed to query trash")?;
+
+    match owners {
+        Some((owner, other)) if owner == user || other.as_deref() == Some(user) => {}
+        Some(_) => return Err(Error::Unathorized),
+        None => return Err(Error::NotFound),
+    }
+
+    conn.execute(restore, params![key])
+        .context("Failed to restore from the trash")?;
+
+    Ok(Json(()))
+}
+
+async fn restore_image(
+    Path(key): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<()>, Error> {
+    info!("Restoring image {key}");
+    state
+        .db
+        .call(move |conn| {
+            restore(
+                &user,
+                "SELECT uploader, NULL FROM images WHERE key = ?1 AND deleted_at IS NOT NULL",
+                "UPDATE images SET deleted_at = NULL WHERE key = ?1",
+                &key,
+                conn,
+            )
+        })
+        .await
+}
+
+async fn restore_album(
+    Path(key): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<()>, Error> {
+    info!("Restoring album {key}");
+    state
+        .db
+        .call(move |conn| {
+            restore(
+                &user,
+                "SELECT author, NULL FROM albums WHERE key = ?1 AND deleted_at IS NOT NULL",
+                "UPDATE albums SET deleted_at = NULL WHERE key = ?1",
+                &key,
+                conn,
+            )
+        })
+        .await
+}
+
+async fn restore_comment(
+    Path(id): Path<i64>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<()>, Error> {
+    info!("Restoring comment {id}");
+    state
+        .db
+        .call(move |conn| {
+            restore(
+                &user,
+                "SELECT c.author, a.author FROM comments c \
+                INNER JOIN albums a ON a.key = c.album_key \
+                WHERE c.id = ?1 AND c.deleted_at IS NOT NULL",
+                "UPDATE comments SET deleted_at = NULL WHERE id = ?1",
+                &id,
+                conn,
+            )
+        })
+        .await
+}
+
+/// Deletes everything which was moved to the trash before `older_than` for good and returns the
+/// number of purged images, whose files are left to the reaper. Images still covering an album or
+/// shown in a profile are kept until they aren't anymore.
+pub fn purge_expired(older_than: u64, now: u64, conn: &Connection) -> anyhow::Result<usize> {
+    conn.execute(
+        "DELETE FROM comments WHERE deleted_at < ?1",
+        params![older_than],
+    )
+    .context("Failed to purge comments")?;
+
+    conn.execute(
+        "UPDATE users SET featured_album_key = NULL WHERE featured_album_key IN ( \
+            SELECT key FROM albums WHERE deleted_at < ?1 \
+        )",
+        params![older_than],
+    )
+    .context("Failed to clear featured albums")?;
+    conn.execute(
+        "DELETE FROM albums WHERE deleted_at < ?1",
+        params![older_than],
+    )
+    .context("Failed to purge albums")?;
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT key FROM images i \
+            WHERE deleted_at < ?1 \
+            AND NOT EXISTS (SELECT 1 FROM albums a WHERE a.cover_key = i.key) \
+            AND NOT EXISTS ( \
+                SELECT 1 FROM users u WHERE u.avatar_key = i.key OR u.banner_key = i.key \
+            )",
+        )
+        .context("Failed to prepare statement for expired images query")?;
+    let keys = stmt
+        .query_map(params![older_than], |row| row.get::<_, String>(0))
+        .context("Failed to query expired images")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect expired images")?;
+
+    for key in &keys {
+        tombstone::delete(key, now, conn)?;
+    }
+
+    Ok(keys.len())
+}
+
+pub async fn purge(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap();
+        let older_than = now.saturating_sub(state.trash_retention).as_secs();
+        let purged = state
+            .db
+            .call(move |conn| {
+                let tx = conn.transaction().context("Failed to create transaction")?;
+                let purged = purge_expired(older_than, now.as_secs(), &tx)?;
+                tx.commit().context("Failed to commit transaction")?;
+
+                Ok::<_, anyhow::Error>(purged)
+            })
+            .await;
+
+        match purged {
+            Ok(0) => {}
+            Ok(purged) => {
+                info!("Purged {purged} images from the trash");
+                state.reaper.notify_one();
+            }
+            Err(e) => error!("Failed to purge the trash: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::album::{InsertAlbum, InsertShareToken};
+    use crate::api::image::image_exists;
+    use crate::api::image::media::{shared_image, viewable_by};
+    use crate::api::public_auth::album_of_share_token;
+    use crate::util::test::{
+        insert_album, insert_comment, insert_image, insert_share_token, insert_user,
+    };
+    use assert_matches::assert_matches;
+
+    #[tokio::test]
+    async fn restore_from_trash() {
+        let state = AppState::in_memory_db().await;
+
+        let (user, other, image, album, comment) = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let other = insert_user("other", conn);
+                let image = insert_image(&user, conn);
+                let album = insert_album(
+                    InsertAlbum {
+                        cover_key: &image,
+                        image_keys: &[image.clone()],
+                        author: &user,
+                        ..Default::default()
+                    },
+                    conn,
+                );
+                let comment = insert_comment(&other, &image, &album, "foo", conn).id;
+
+                add_comment(comment, 10, conn).unwrap();
+                add_album(&album, 20, conn).unwrap();
+
+                (user, other, image, album, comment)
+            })
+            .await;
+
+        let trash = state
+            .db
+            .call(|conn| select_trash("test", 100, conn))
+            .await
+            .unwrap();
+        assert_eq!(trash.albums.len(), 1);
+        assert_eq!(trash.albums[0].purge_at, 120);
+        assert_eq!(trash.comments.len(), 1);
+        assert!(trash.images.is_empty());
+
+        let result = restore_album(
+            Path(album.clone()),
+            Authorize(other.clone()),
+            Extension(state.clone()),
+        )
+        .await;
+        assert_matches!(result, Err(Error::Unathorized));
+
+        let result = restore_album(
+            Path(album),
+            Authorize(user.clone()),
+            Extension(state.clone()),
+        )
+        .await;
+        assert_matches!(result, Ok(_));
+
+        // The author of the comment may restore it as well as the author of the album
+        let result =
+            restore_comment(Path(comment), Authorize(other), Extension(state.clone())).await;
+        assert_matches!(result, Ok(_));
+
+        let result = restore_image(Path(image), Authorize(user), Extension(state.clone())).await;
+        assert_matches!(result, Err(Error::NotFound));
+
+        let trash = state
+            .db
+            .call(|conn| select_trash("test", 100, conn))
+            .await
+            .unwrap();
+        assert!(trash.albums.is_empty());
+        assert!(trash.comments.is_empty());
+    }
+
+    #[tokio::test]
+    async fn trashed_image_media() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let other = insert_user("other", conn);
+                let image = insert_image(&user, conn);
+
+                assert!(viewable_by(&image, &other, conn).unwrap());
+
+                // The uploader can still look at it before restoring it
+                add_image(&image, 10, conn).unwrap();
+                assert!(!viewable_by(&image, &other, conn).unwrap());
+                assert!(viewable_by(&image, &user, conn).unwrap());
+
+                conn.execute(
+                    "UPDATE images SET deleted_at = NULL WHERE key = ?1",
+                    params![image],
+                )
+                .unwrap();
+                assert!(viewable_by(&image, &other, conn).unwrap());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn trashed_album_share_token() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let image = insert_image(&user, conn);
+                let album = insert_album(
+                    InsertAlbum {
+                        cover_key: &image,
+                        image_keys: &[image.clone()],
+                        author: &user,
+                        ..Default::default()
+                    },
+                    conn,
+                );
+                let token = insert_share_token(
+                    InsertShareToken {
+                        album_key: &album,
+                        created_by: &user,
+                        ..Default::default()
+                    },
+                    conn,
+                );
+
+                assert!(shared_image(&album, &token, &image, conn).unwrap());
+
+                add_album(&album, 10, conn).unwrap();
+                assert_eq!(album_of_share_token(&token, conn).unwrap(), None);
+                assert!(!shared_image(&album, &token, &image, conn).unwrap());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn purge_expired_rows() {
+        let state = AppState::in_memory_db().await;
+
+        state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                let cover = insert_image(&user, conn);
+                let expired = insert_image(&user, conn);
+                let recent = insert_image(&user, conn);
+                let album = insert_album(
+                    InsertAlbum {
+                        cover_key: &cover,
+                        image_keys: &[cover.clone(), expired.clone(), recent.clone()],
+                        author: &user,
+                        ..Default::default()
+                    },
+                    conn,
+                );
+
+                add_image(&cover, 10, conn).unwrap();
+                add_image(&expired, 10, conn).unwrap();
+                add_image(&recent, 30, conn).unwrap();
+
+                // The cover is kept as long as the album uses it
+                assert_eq!(purge_expired(20, 40, conn).unwrap(), 1);
+                assert_eq!(tombstone::pending(conn).unwrap(), vec![expired.clone()]);
+
+                add_album(&album, 10, conn).unwrap();
+                assert_eq!(purge_expired(20, 40, conn).unwrap(), 1);
+                assert_eq!(tombstone::pending(conn).unwrap().len(), 2);
+
+                let restored = |key: &str| {
+                    conn.execute(
+                        "UPDATE images SET deleted_at = NULL WHERE key = ?1",
+                        params![key],
+                    )
+                    .unwrap();
+                    image_exists(key, conn).unwrap()
+                };
+                assert!(!restored(&cover));
+                assert!(restored(&recent));
+            })
+            .await;
+    }
+}
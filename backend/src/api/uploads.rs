@@ -0,0 +1,553 @@
+use anyhow::Context;
+use axum::{
+    body::Bytes,
+    extract::{ContentLengthLimit, Path, Query},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_rusqlite::from_row;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::*;
+
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::auth::Authorize;
+use super::error::Error;
+use super::image::upload::{
+    maximum_size, store_file, UploadOptions, UploadedFile, MAXIMUM_VIDEO_SIZE, MB,
+};
+use super::image::Image;
+use crate::util::check_length;
+use crate::AppState;
+
+const MAXIMUM_CHUNK_SIZE: u64 = 16 * MB;
+const SESSION_TIMEOUT: Duration = Duration::from_secs(3600 * 24);
+const GC_INTERVAL: Duration = Duration::from_secs(3600);
+/// Enough of the file to tell images and clips apart.
+const HEAD_LENGTH: u64 = 16;
+/// A finish request which didn't release its claim by then is assumed to have died with the server.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(600);
+
+pub fn api_route() -> Router {
+    Router::new()
+        .route("/", post(post_session))
+        .route("/:id", get(get_session).delete(delete_session))
+        .route("/:id/chunks/:index", put(put_chunk))
+        .route("/:id/finish", post(finish))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct UploadSession {
+    id: String,
+    #[serde(skip_serializing)]
+    uploader: String,
+    file_name: String,
+    description: Option<String>,
+    size_bytes: u64,
+    received_bytes: u64,
+    next_chunk: u64,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateSessionRequest {
+    file_name: String,
+    description: Option<String>,
+    size_bytes: u64,
+}
+
+fn upload_path(data_path: &std::path::Path) -> PathBuf {
+    data_path.join(".uploads")
+}
+
+fn select_session(id: &str, conn: &Connection) -> anyhow::Result<Option<UploadSession>> {
+    conn.query_row(
+        "SELECT * FROM upload_sessions WHERE id = ?1",
+        params![id],
+        |row| Ok(from_row::<UploadSession>(row).unwrap()),
+    )
+    .optional()
+    .context("Failed to query upload session")
+}
+
+fn delete_session_row(id: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM upload_sessions WHERE id = ?1", params![id])
+        .context("Failed to delete upload session")?;
+
+    Ok(())
+}
+
+/// Sessions can only be seen by the user who created them.
+async fn owned_session(id: String, user: String, state: &AppState) -> Result<UploadSession, Error> {
+    let session = state.db.call(move |conn| select_session(&id, conn)).await?;
+
+    match session {
+        Some(session) if session.uploader == user => Ok(session),
+        _ => Err(Error::NotFound),
+    }
+}
+
+async fn post_session(
+    Authorize(uploader): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(request): Json<CreateSessionRequest>,
+) -> Result<Json<UploadSession>, Error> {
+    check_length(
+        "fileName",
+        Some(&request.file_name),
+        super::image::MAXIMUM_FILE_NAME_LENGTH,
+    )?;
+    check_length(
+        "description",
+        request.description.as_deref(),
+        super::image::MAXIMUM_DESCRIPTION_LENGTH,
+    )?;
+
+    // Whether it is an image or a clip is only known once the first chunk arrived
+    if request.size_bytes > MAXIMUM_VIDEO_SIZE {
+        return Err(Error::FileTooLarge(MAXIMUM_VIDEO_SIZE / MB));
+    }
+
+    let now = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+
+    let session = UploadSession {
+        id: blob_uuid::random_blob(),
+        uploader,
+        file_name: request.file_name,
+        description: request.description.filter(|d| !d.is_empty()),
+        size_bytes: request.size_bytes,
+        received_bytes: 0,
+        next_chunk: 0,
+        created_at: now,
+        updated_at: now,
+    };
+
+    fs::create_dir_all(upload_path(&state.data_path))
+        .await
+        .context("Failed to create upload directory")?;
+
+    let csession = session.clone();
+    state
+        .db
+        .call(move |conn| {
+            conn.execute(
+                "INSERT INTO upload_sessions ( \
+                    id, uploader, file_name, description, size_bytes, created_at, updated_at \
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+                params![
+                    csession.id,
+                    csession.uploader,
+                    csession.file_name,
+                    csession.description,
+                    csession.size_bytes,
+                    csession.created_at,
+                ],
+            )
+            .context("Failed to insert upload session")
+        })
+        .await?;
+
+    Ok(Json(session))
+}
+
+async fn get_session(
+    Path(id): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<UploadSession>, Error> {
+    Ok(Json(owned_session(id, user, &state).await?))
+}
+
+async fn delete_session(
+    Path(id): Path<String>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<()>, Error> {
+    let session = owned_session(id, user, &state).await?;
+
+    remove_session(session.id, &state).await?;
+
+    Ok(Json(()))
+}
+
+/// Appends chunk `index` to the upload, chunks have to be sent in order. Repeating the last chunk
+/// is accepted without changes in case its response got lost.
+async fn put_chunk(
+    Path((id, index)): Path<(String, u64)>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+    ContentLengthLimit(data): ContentLengthLimit<Bytes, { MAXIMUM_CHUNK_SIZE }>,
+) -> Result<Json<UploadSession>, Error> {
+    let session = owned_session(id, user, &state).await?;
+
+    if index + 1 == session.next_chunk {
+        return Ok(Json(session));
+    }
+    if index != session.next_chunk {
+        return Err(Error::UnexpectedChunk {
+            expected: session.next_chunk,
+        });
+    }
+
+    if index == 0 {
+        check_size(&session, &data)?;
+    }
+
+    let received_bytes = session.received_bytes + data.len() as u64;
+    if received_bytes > session.size_bytes {
+        return Err(Error::InvalidArguments(anyhow::anyhow!(
+            "Chunk exceeds the announced size of {} bytes",
+            session.size_bytes
+        )));
+    }
+
+    let path = upload_path(&state.data_path).join(&session.id);
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .await
+        .context("Failed to open upload file")?;
+
+    // Drop anything written by a chunk which never made it into the database
+    file.set_len(session.received_bytes)
+        .await
+        .context("Failed to truncate upload file")?;
+    file.seek(SeekFrom::End(0))
+        .await
+        .context("Failed to seek upload file")?;
+    file.write_all(&data)
+        .await
+        .context("Failed to write chunk")?;
+    file.sync_all().await.context("Failed to sync chunk")?;
+
+    let now = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+
+    let id = session.id.clone();
+    let session = state
+        .db
+        .call(move |conn| {
+            let updated = conn
+                .execute(
+                    "UPDATE upload_sessions \
+                    SET received_bytes = ?1, next_chunk = ?2, updated_at = ?3 \
+                    WHERE id = ?4 AND next_chunk = ?5",
+                    params![received_bytes, index + 1, now, id, index],
+                )
+                .context("Failed to update upload session")?;
+
+            let session = select_session(&id, conn)?.context("Upload session disappeared")?;
+            if updated == 0 {
+                // A concurrent request was faster
+                return Err(Error::UnexpectedChunk {
+                    expected: session.next_chunk,
+                });
+            }
+
+            Ok(session)
+        })
+        .await?;
+
+    Ok(Json(session))
+}
+
+/// Hands the complete file to the regular upload pipeline.
+async fn finish(
+    Path(id): Path<String>,
+    Query(options): Query<UploadOptions>,
+    Authorize(user): Authorize,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Image>, Error> {
+    let session = owned_session(id, user, &state).await?;
+
+    if session.received_bytes < session.size_bytes {
+        return Err(Error::IncompleteUpload {
+            missing_bytes: session.size_bytes - session.received_bytes,
+        });
+    }
+
+    // The first chunk might have been too short to tell, the file is only read after this
+    let path = upload_path(&state.data_path).join(&session.id);
+    let mut head = Vec::new();
+    fs::File::open(&path)
+        .await
+        .context("Failed to open upload file")?
+        .take(HEAD_LENGTH)
+        .read_to_end(&mut head)
+        .await
+        .context("Failed to read upload file")?;
+    if let Err(e) = check_size(&session, &head) {
+        remove_session(session.id, &state).await?;
+        return Err(e);
+    }
+
+    let now = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+
+    let id = session.id.clone();
+    let claimed = state
+        .db
+        .call(move |conn| claim_finishing(&id, now, conn))
+        .await?;
+    if !claimed {
+        return Err(Error::UploadFinishing);
+    }
+
+    // Keeps going if the client disconnects, so the claim is released
+    let image = tokio::spawn(async move {
+        let id = session.id.clone();
+        let result = store_session(session, options, &state).await;
+
+        // Internal errors might go away when trying again, anything else won't
+        if matches!(result, Err(Error::InternalError(_))) {
+            state
+                .db
+                .call(move |conn| release_finishing(&id, conn))
+                .await?;
+        } else {
+            remove_session(id, &state).await?;
+        }
+
+        result
+    })
+    .await
+    .context("Failed to finish upload")??;
+
+    Ok(Json(image))
+}
+
+/// Whether the announced size is within the limit of the kind of file `head` starts.
+fn check_size(session: &UploadSession, head: &[u8]) -> Result<(), Error> {
+    let maximum_size = maximum_size(head);
+    if session.size_bytes > maximum_size {
+        return Err(Error::FileTooLarge(maximum_size / MB));
+    }
+
+    Ok(())
+}
+
+/// Claims the session for a finish request, returns false if another one is finishing it. Claims
+/// older than `FINISH_TIMEOUT` were abandoned and can be taken over.
+fn claim_finishing(id: &str, now: u64, conn: &Connection) -> anyhow::Result<bool> {
+    let abandoned_before = now.saturating_sub(FINISH_TIMEOUT.as_secs());
+    let updated = conn
+        .execute(
+            "UPDATE upload_sessions SET finishing_at = ?1 \
+            WHERE id = ?2 AND (finishing_at IS NULL OR finishing_at <= ?3)",
+            params![now, id, abandoned_before],
+        )
+        .context("Failed to claim upload session")?;
+
+    Ok(updated == 1)
+}
+
+fn release_finishing(id: &str, conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE upload_sessions SET finishing_at = NULL WHERE id = ?1",
+        params![id],
+    )
+    .context("Failed to release upload session")?;
+
+    Ok(())
+}
+
+async fn store_session(
+    session: UploadSession,
+    options: UploadOptions,
+    state: &AppState,
+) -> Result<Image, Error> {
+    let path = upload_path(&state.data_path).join(&session.id);
+    let mut data = fs::read(&path)
+        .await
+        .context("Failed to read upload file")?;
+    data.truncate(session.received_bytes as usize);
+
+    let uploaded_at = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .context("Failed to get timestamp")?
+        .as_secs();
+
+    let file = UploadedFile {
+        file_name: session.file_name,
+        data,
+        description: session.description,
+        allow_duplicate: options.allow_duplicates,
+    };
+
+    store_file(file, &session.uploader, uploaded_at, state).await
+}
+
+async fn remove_session(id: String, state: &AppState) -> Result<(), Error> {
+    let path = upload_path(&state.data_path).join(&id);
+
+    state
+        .db
+        .call(move |conn| delete_session_row(&id, conn))
+        .await?;
+
+    if let Err(e) = fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(anyhow::Error::new(e)
+                .context("Failed to remove upload file")
+                .into());
+        }
+    }
+
+    Ok(())
+}
+
+fn stale_sessions(older_than: u64, conn: &Connection) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn
+        .prepare("SELECT id FROM upload_sessions WHERE updated_at < ?1")
+        .context("Failed to prepare statement for upload sessions query")?;
+
+    let ids = stmt
+        .query_map(params![older_than], |row| {
+            Ok(from_row::<String>(row).unwrap())
+        })
+        .context("Failed to query upload sessions")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect upload sessions")?;
+
+    Ok(ids)
+}
+
+/// Periodically removes sessions which were not touched in a while and files left over without a
+/// session.
+pub async fn collect_garbage(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(GC_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = remove_stale_sessions(&state).await {
+            error!("Failed to remove stale upload sessions: {:?}", e);
+        }
+    }
+}
+
+async fn remove_stale_sessions(state: &AppState) -> anyhow::Result<()> {
+    let now = SystemTime::UNIX_EPOCH.elapsed()?;
+    let older_than = (now - SESSION_TIMEOUT).as_secs();
+
+    let ids = state
+        .db
+        .call(move |conn| stale_sessions(older_than, conn))
+        .await?;
+
+    for id in ids {
+        info!("Removing stale upload session {id}");
+        remove_session(id, state).await?;
+    }
+
+    let mut entries = match fs::read_dir(upload_path(&state.data_path)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("Failed to read upload directory"),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let id = entry.file_name().to_string_lossy().to_string();
+        let cid = id.clone();
+        let session = state
+            .db
+            .call(move |conn| select_session(&cid, conn))
+            .await?;
+
+        if session.is_none() {
+            info!("Removing orphaned upload file {id}");
+            fs::remove_file(entry.path())
+                .await
+                .context("Failed to remove upload file")?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::insert_user;
+
+    #[tokio::test]
+    async fn stale_sessions_by_last_update() {
+        let state = AppState::in_memory_db().await;
+
+        let ids = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                for (id, updated_at) in [("old", 10), ("new", 30)] {
+                    conn.execute(
+                        "INSERT INTO upload_sessions ( \
+                            id, uploader, file_name, size_bytes, created_at, updated_at \
+                        ) VALUES (?1, ?2, 'file.jpg', 100, 0, ?3)",
+                        params![id, user, updated_at],
+                    )
+                    .unwrap();
+                }
+
+                stale_sessions(20, conn).unwrap()
+            })
+            .await;
+
+        assert_eq!(ids, vec!["old"]);
+    }
+
+    #[tokio::test]
+    async fn finishing_is_claimed_once() {
+        let state = AppState::in_memory_db().await;
+
+        let claims = state
+            .db
+            .call(|conn| {
+                let user = insert_user("test", conn);
+                conn.execute(
+                    "INSERT INTO upload_sessions ( \
+                        id, uploader, file_name, size_bytes, created_at, updated_at \
+                    ) VALUES ('session', ?1, 'file.jpg', 100, 0, 0)",
+                    params![user],
+                )
+                .unwrap();
+
+                let expired = 100 + FINISH_TIMEOUT.as_secs();
+                let claims = [
+                    claim_finishing("session", 100, conn).unwrap(),
+                    claim_finishing("session", 110, conn).unwrap(),
+                    claim_finishing("session", expired - 1, conn).unwrap(),
+                    // The first request never released its claim
+                    claim_finishing("session", expired, conn).unwrap(),
+                ];
+
+                release_finishing("session", conn).unwrap();
+                (
+                    claims,
+                    claim_finishing("session", expired + 1, conn).unwrap(),
+                )
+            })
+            .await;
+
+        assert_eq!(claims, ([true, false, false, true], true));
+    }
+}
@@ -80,7 +80,7 @@ pub fn get_all(conn: &Connection) -> Result<Vec<User>, Error> {
             .prepare(
                 "SELECT a.\"key\" FROM albums a \
                     WHERE a.author = ?1 \
-                    AND a.draft == false",
+                    AND a.draft == false AND a.deleted_at IS NULL",
             )
             .context("Failed to prepare user albums query")?;
         let album_key_iter = stmt
@@ -102,7 +102,7 @@ pub fn get_all(conn: &Connection) -> Result<Vec<User>, Error> {
                         INNER JOIN users u2 ON uaa2.username = u2.username \
                         WHERE u1.username = ?1 \
                         AND u2.username != ?1 \
-                        AND a.draft == false
+                        AND a.draft == false AND a.deleted_at IS NULL
                         GROUP BY u2.username",
             )
             .context("Failed to prepare met users query")?;
@@ -177,7 +177,7 @@ async fn get_user_by_username(
                 let mut stmt = conn.prepare(
                     "SELECT a.\"key\" FROM albums a \
                     WHERE a.author = ?1 \
-                    AND a.draft == false",
+                    AND a.draft == false AND a.deleted_at IS NULL",
                 )?;
                 let album_iter = stmt.query_map(params![cusername], |row| {
                     Ok(from_row::<String>(row).unwrap())
@@ -200,7 +200,7 @@ async fn get_user_by_username(
                     INNER JOIN users u2 ON uaa2.username = u2.username \
                     WHERE u1.username = ?1 \
                     AND u2.username != ?1 \
-                    AND a.draft == false
+                    AND a.draft == false AND a.deleted_at IS NULL
                     GROUP BY u2.username",
                 )?;
                 let album_iter = stmt.query_map(params![cusername], |row| {
@@ -1,16 +1,23 @@
-use std::{
-    fs::File,
-    io::{Cursor, Read, Write},
-    path::PathBuf,
-};
+use std::{io::Cursor, io::Write, path::PathBuf, time::SystemTime};
 
 use anyhow::{bail, Context};
 use argh::FromArgs;
 use rusqlite::params;
 use serde_rusqlite::from_row;
-use tracing::{error, info, warn};
+use tracing::{error, info};
 
-use crate::api::image::{orientation::ExifOrientation, DbImage};
+use crate::api::image::{
+    dedup,
+    dimensions::{self, VariantDimensions},
+    fsck,
+    orientation::ExifOrientation,
+    original_path, processing,
+    size::{ImageConfig, Variant},
+    tombstone, transform, DbImage, DbImageMetadata,
+};
+use crate::api::login::lockout;
+use crate::api::settings::totp;
+use crate::storage::{self, Storage};
 
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 /// Top-level command.
@@ -25,6 +32,12 @@ pub enum SubCommands {
     AddUser(AddUserArgs),
     EditUser(EditUserArgs),
     ReencodeImages(ReencodeImageArgs),
+    Dedup(DedupArgs),
+    FixTimestamps(FixTimestampsArgs),
+    Dimensions(DimensionsArgs),
+    MigrateStorage(MigrateStorageArgs),
+    Fsck(FsckArgs),
+    Lockouts(LockoutsArgs),
 }
 
 #[derive(FromArgs, PartialEq, Eq, Debug)]
@@ -51,6 +64,11 @@ pub struct EditUserArgs {
     #[argh(positional)]
     /// password
     pub password: Option<String>,
+
+    #[argh(switch)]
+    /// turn off two-factor authentication, e.g. after the authenticator was lost, and keep the
+    /// password unless one is given
+    pub reset_2fa: bool,
 }
 
 #[derive(FromArgs, PartialEq, Eq, Debug)]
@@ -60,6 +78,60 @@ pub struct ReencodeImageArgs {
     #[argh(positional)]
     /// quality
     pub quality: u8,
+
+    #[argh(switch)]
+    /// only generate sizes and formats which are missing, e.g. after new ones were added
+    pub missing: bool,
+}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// find images uploaded more than once.
+#[argh(subcommand, name = "dedup")]
+pub struct DedupArgs {
+    #[argh(switch)]
+    /// keep only the oldest upload of every user and point albums, comments and profiles to it
+    pub merge: bool,
+}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// read capture times from the originals again, with their timezones.
+#[argh(subcommand, name = "fix-timestamps")]
+pub struct FixTimestampsArgs {
+    #[argh(switch)]
+    /// also overwrite capture times which were changed by hand
+    pub force: bool,
+}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// store the dimensions of images processed before they were recorded.
+#[argh(subcommand, name = "dimensions")]
+pub struct DimensionsArgs {}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// copy the files of the data directory into the storage selected with STORAGE.
+#[argh(subcommand, name = "migrate-storage")]
+pub struct MigrateStorageArgs {}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// find images and directories of the storage which don't match up.
+#[argh(subcommand, name = "fsck")]
+pub struct FsckArgs {
+    #[argh(switch)]
+    /// queue missing derivatives and remove the files of deleted images and orphaned directories
+    pub repair: bool,
+}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// list usernames and addresses with failed logins and whether they are locked out.
+#[argh(subcommand, name = "lockouts")]
+pub struct LockoutsArgs {
+    #[argh(option)]
+    /// clear the failed logins of a username or address
+    pub clear: Option<String>,
+
+    #[argh(switch)]
+    /// clear the failed logins of every username and address
+    pub clear_all: bool,
 }
 
 pub async fn run_subcommand(
@@ -85,6 +157,17 @@ pub async fn run_subcommand(
             {
                 bail!("User {} does not exist", args.username);
             }
+
+            if args.reset_2fa {
+                let username = args.username.clone();
+                db.call(move |conn| totp::disable(&username, conn)).await?;
+                println!("Turned off two-factor authentication of {}", args.username);
+
+                if args.password.is_none() {
+                    return Ok(());
+                }
+            }
+
             let password = if let Some(password) = args.password {
                 password
             } else {
@@ -100,6 +183,12 @@ pub async fn run_subcommand(
             let data_path: PathBuf = std::env::var("DATA_PATH")
                 .context("DATA_PATH not set")?
                 .into();
+            let storage = storage::from_env(&data_path)?;
+
+            let image_sizes = std::env::var("IMAGE_SIZES").ok();
+            let mut config = ImageConfig::new(args.quality, image_sizes.as_deref())
+                .context("Failed to parse IMAGE_SIZES")?;
+            config.poster_command = std::env::var("POSTER_COMMAND").ok();
 
             print!("Does a backup of the data directory exist?<y/N>");
             std::io::stdout().flush()?;
@@ -127,57 +216,405 @@ pub async fn run_subcommand(
                 .await?;
 
             for image in images {
+                let variants = if args.missing {
+                    missing_variants(&*storage, &image.key, &config).await?
+                } else {
+                    config.variants()
+                };
+
+                // Images processed before perceptual hashes existed still need one
+                if variants.is_empty() && image.perceptual_hash.is_some() {
+                    continue;
+                }
+
                 info!("Reencoding {}", image.key);
 
-                let mut image_path = PathBuf::from(&data_path);
-                image_path.push(&image.key);
+                let key = image.key.clone();
+                let operations = db
+                    .call(move |conn| transform::current_operations(&key, conn))
+                    .await?;
 
-                for name in &["full.jpg", "large.jpg", "medium.jpg", "tiny.jpg"] {
-                    let mut rm_path = image_path.clone();
-                    rm_path.push(name);
-                    std::fs::remove_file(rm_path)?;
+                match processing::generate_derivatives(
+                    &*storage,
+                    data_path.clone(),
+                    &config,
+                    &image,
+                    &operations,
+                    &variants,
+                )
+                .await
+                {
+                    Ok(None) => {}
+                    Ok(Some(analysis)) => {
+                        let key = image.key.clone();
+                        db.call(move |conn| processing::store_analysis(&key, &analysis, conn))
+                            .await?;
+                    }
+                    Err(e) => {
+                        use std::error::Error;
+                        error!(
+                            "Failed to reencode image {}: {}",
+                            image.key,
+                            e.source().unwrap()
+                        );
+                    }
                 }
+            }
+        }
+        SubCommands::Dedup(args) => {
+            let data_path: PathBuf = std::env::var("DATA_PATH")
+                .context("DATA_PATH not set")?
+                .into();
+            let storage = storage::from_env(&data_path)?;
 
-                image_path.push("original");
-                image_path.push(&image.metadata.file_name);
+            backfill_content_hashes(&*storage, db).await?;
 
-                let mut image_data: Vec<u8> = Vec::new();
-                let mut file = File::open(image_path)?;
-                file.read_to_end(&mut image_data)?;
+            let groups = db.call(|conn| dedup::duplicate_groups(conn)).await?;
+            if groups.is_empty() {
+                println!("No duplicates found");
+                return Ok(());
+            }
 
-                let mut orientation = None;
+            for group in &groups {
+                let keys: Vec<_> = group
+                    .iter()
+                    .map(|(key, uploader)| format!("{key} ({uploader})"))
+                    .collect();
+                println!("{}", keys.join(", "));
+            }
 
-                let mut bufreader = std::io::BufReader::new(Cursor::new(&*image_data));
-                let exifreader = exif::Reader::new();
-                match exifreader.read_from_container(&mut bufreader) {
-                    Ok(exif) => {
-                        orientation = crate::api::image::upload::orientation_from_exif(&exif);
-                    }
+            if !args.merge {
+                return Ok(());
+            }
+
+            print!("Does a backup of the data directory exist?<y/N>");
+            std::io::stdout().flush()?;
+
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            if !input.to_lowercase().starts_with('y') {
+                return Ok(());
+            }
+
+            for group in groups {
+                info!(
+                    "Merging {} into {}",
+                    group[1..]
+                        .iter()
+                        .map(|(key, _)| key.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    group[0].0
+                );
+
+                let now = SystemTime::UNIX_EPOCH.elapsed()?.as_secs();
+                db.call(move |conn| {
+                    let tx = conn.transaction().context("Failed to create transaction")?;
+                    dedup::merge_group(&group, now, &tx)?;
+                    tx.commit().context("Failed to commit transaction")?;
+
+                    Ok::<_, anyhow::Error>(())
+                })
+                .await?;
+            }
+
+            tombstone::reap(&*storage, db).await?;
+        }
+        SubCommands::FixTimestamps(args) => {
+            let data_path: PathBuf = std::env::var("DATA_PATH")
+                .context("DATA_PATH not set")?
+                .into();
+            let storage = storage::from_env(&data_path)?;
+
+            let images = db
+                .call(|conn| {
+                    let mut query = conn
+                        .prepare("SELECT key, file_name FROM images")
+                        .context("Failed to prepare statement for images query")?;
+
+                    let images = query
+                        .query_map(params![], |row| {
+                            Ok(from_row::<(String, String)>(row).unwrap())
+                        })
+                        .context("Failed to query images")?
+                        .collect::<Result<Vec<_>, _>>()
+                        .context("Failed to collect images")?;
+
+                    Ok::<_, anyhow::Error>(images)
+                })
+                .await?;
+
+            for (key, file_name) in images {
+                let data = match storage.read(&original_path(&key, &file_name)).await {
+                    Ok(data) => data,
                     Err(e) => {
-                        warn!("Failed to read EXIF metadata: {}", e);
+                        error!("{e:?}");
+                        continue;
+                    }
+                };
+
+                let mut metadata = DbImageMetadata::default();
+                match crate::api::image::upload::read_exif(&data) {
+                    Some(exif) => {
+                        crate::api::image::upload::populate_metadata_from_exif(&mut metadata, &exif)
                     }
+                    None => continue,
                 }
 
-                if let Err(e) = crate::api::image::upload::store_image(
-                    data_path.clone(),
-                    &image.key,
-                    &image.metadata.file_name,
-                    &image_data,
-                    &orientation.unwrap_or(ExifOrientation::Normal),
-                    args.quality,
-                )
-                .await
-                {
-                    use std::error::Error;
-                    error!(
-                        "Failed to reencode image {}: {}",
-                        image.key,
-                        e.source().unwrap()
-                    );
+                let taken_at = match metadata.taken_at {
+                    Some(taken_at) => taken_at,
+                    None => continue,
+                };
+                // Before offsets were stored the local time was taken as UTC
+                let assumed_utc = taken_at + metadata.taken_at_offset.unwrap_or(0) as i64;
+
+                // Editing the capture time clears the local time, so an image without one still
+                // has the time read at upload only if it matches the old assumption
+                let ckey = key.clone();
+                let updated = db
+                    .call(move |conn| {
+                        conn.execute(
+                            "UPDATE images \
+                            SET taken_at = ?1, taken_at_local = ?2, taken_at_offset = ?3 \
+                            WHERE key = ?4 \
+                            AND (?5 OR taken_at_local IS NOT NULL OR taken_at = ?6)",
+                            params![
+                                metadata.taken_at,
+                                metadata.taken_at_local,
+                                metadata.taken_at_offset,
+                                ckey,
+                                args.force,
+                                assumed_utc,
+                            ],
+                        )
+                        .context("Failed to update capture time")
+                    })
+                    .await?;
+
+                if updated == 0 {
+                    info!("Keeping capture time of {key} which was changed by hand");
+                } else {
+                    info!("Updated capture time of {key}");
                 }
             }
         }
+        SubCommands::Dimensions(_) => {
+            let data_path: PathBuf = std::env::var("DATA_PATH")
+                .context("DATA_PATH not set")?
+                .into();
+            let storage = storage::from_env(&data_path)?;
+
+            // Only the names of the sizes matter here
+            let image_sizes = std::env::var("IMAGE_SIZES").ok();
+            let config = ImageConfig::new(75, image_sizes.as_deref())
+                .context("Failed to parse IMAGE_SIZES")?;
+
+            let images = db.call(|conn| dimensions::missing(conn)).await?;
+            for (key, file_name) in images {
+                let (width, height, variants) =
+                    match read_dimensions(&*storage, &key, &file_name, &config).await {
+                        Ok(dimensions) => dimensions,
+                        Err(e) => {
+                            error!("Failed to read dimensions of {key}: {e:?}");
+                            continue;
+                        }
+                    };
+
+                info!("Storing dimensions of {key}");
+                db.call(move |conn| dimensions::store(&key, width, height, &variants, conn))
+                    .await?;
+            }
+        }
+        SubCommands::MigrateStorage(_) => {
+            let data_path: PathBuf = std::env::var("DATA_PATH")
+                .context("DATA_PATH not set")?
+                .into();
+            if std::env::var("STORAGE").map_or(true, |s| s == "local") {
+                bail!("STORAGE has to select another storage than the data directory");
+            }
+            let storage = storage::from_env(&data_path)?;
+
+            let copied = storage::migrate(&data_path, &*storage).await?;
+            println!("Copied {copied} files");
+        }
+        SubCommands::Fsck(args) => {
+            let data_path: PathBuf = std::env::var("DATA_PATH")
+                .context("DATA_PATH not set")?
+                .into();
+            let storage = storage::from_env(&data_path)?;
+
+            let image_sizes = std::env::var("IMAGE_SIZES").ok();
+            let mut config = ImageConfig::new(75, image_sizes.as_deref())
+                .context("Failed to parse IMAGE_SIZES")?;
+            config.poster_command = std::env::var("POSTER_COMMAND").ok();
+
+            let now = SystemTime::now();
+            let report = fsck::check(&*storage, db, &config, now).await?;
+            if report.is_empty() {
+                println!("No problems found");
+                return Ok(());
+            }
+
+            for (problem, keys) in [
+                ("Missing original", &report.missing_originals),
+                ("Missing derivatives", &report.missing_derivatives),
+                ("Files of deleted image", &report.tombstones),
+                ("Orphaned directory", &report.orphans),
+            ] {
+                for key in keys {
+                    println!("{problem}: {key}");
+                }
+            }
+
+            if args.repair {
+                fsck::repair(&report, &*storage, db, now).await?;
+                println!("Repaired everything but missing originals");
+            }
+        }
+        SubCommands::Lockouts(args) => {
+            if args.clear.is_some() || args.clear_all {
+                let cleared = db
+                    .call(move |conn| lockout::clear(args.clear.as_deref(), conn))
+                    .await?;
+                println!("Cleared failed logins of {cleared} usernames and addresses");
+                return Ok(());
+            }
+
+            let failures = db.call(|conn| lockout::list(conn)).await?;
+            if failures.is_empty() {
+                println!("No failed logins");
+                return Ok(());
+            }
+
+            let now = SystemTime::UNIX_EPOCH.elapsed()?.as_secs();
+            for f in failures {
+                let locked = match f.locked_until {
+                    Some(until) if until > now => format!(", locked out for {}s", until - now),
+                    _ => String::new(),
+                };
+                println!(
+                    "{} {}: {} failed logins, last {}s ago{locked}",
+                    f.kind,
+                    f.subject,
+                    f.failures,
+                    now.saturating_sub(f.last_failure_at)
+                );
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Reads the dimensions from the headers of the files of an image.
+async fn read_dimensions(
+    storage: &dyn Storage,
+    key: &str,
+    file_name: &str,
+    config: &ImageConfig,
+) -> anyhow::Result<(u32, u32, Vec<VariantDimensions>)> {
+    let original = storage
+        .read(&original_path(key, file_name))
+        .await
+        .context("Failed to read original")?;
+    let (width, height) = image::io::Reader::new(std::io::Cursor::new(&original))
+        .with_guessed_format()
+        .context("Failed to guess image format")?
+        .into_dimensions()
+        .context("Failed to read dimensions of original")?;
+    let (width, height) = crate::api::image::upload::read_exif(&original)
+        .and_then(|exif| crate::api::image::upload::orientation_from_exif(&exif))
+        .unwrap_or(ExifOrientation::Normal)
+        .apply_to_dimensions(width, height);
+
+    let files = storage.list(key).await?;
+
+    let mut variants = Vec::new();
+    for size in config.sizes() {
+        // Not every format can be decoded, but all of them share the dimensions of the JPEG
+        let jpeg = match storage
+            .read(&storage::path(&[key, &format!("{}.jpg", size.name)]))
+            .await
+        {
+            Ok(jpeg) => jpeg,
+            Err(_) => continue,
+        };
+        let (width, height) = match image::io::Reader::new(Cursor::new(&jpeg))
+            .with_guessed_format()
+            .ok()
+            .and_then(|r| r.into_dimensions().ok())
+        {
+            Some(dimensions) => dimensions,
+            None => continue,
+        };
+
+        for variant in config.variants() {
+            let file_name = variant.file_name();
+            if variant.size.name == size.name && files.contains(&storage::path(&[key, &file_name]))
+            {
+                variants.push(VariantDimensions {
+                    file_name,
+                    width,
+                    height,
+                });
+            }
+        }
+    }
+
+    Ok((width, height, variants))
+}
+
+/// Hashes the originals of images uploaded before content hashes were stored.
+async fn backfill_content_hashes(
+    storage: &dyn Storage,
+    db: &tokio_rusqlite::Connection,
+) -> anyhow::Result<()> {
+    let images = db
+        .call(|conn| {
+            let mut query = conn
+                .prepare("SELECT key, file_name FROM images WHERE content_hash IS NULL")
+                .context("Failed to prepare statement for images query")?;
+
+            let images = query
+                .query_map(params![], |row| {
+                    Ok(from_row::<(String, String)>(row).unwrap())
+                })
+                .context("Failed to query images")?
+                .collect::<Result<Vec<_>, _>>()
+                .context("Failed to collect images")?;
+
+            Ok::<_, anyhow::Error>(images)
+        })
+        .await?;
+
+    for (key, file_name) in images {
+        let data = match storage.read(&original_path(&key, &file_name)).await {
+            Ok(data) => data,
+            Err(e) => {
+                error!("{e:?}");
+                continue;
+            }
+        };
+
+        let hash = dedup::content_hash(&data);
+        db.call(move |conn| dedup::set_content_hash(&key, &hash, conn))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Variants for which no file exists, e.g. because the size or format was added later.
+async fn missing_variants(
+    storage: &dyn Storage,
+    key: &str,
+    config: &ImageConfig,
+) -> anyhow::Result<Vec<Variant>> {
+    let files = storage.list(key).await?;
+
+    Ok(config
+        .variants()
+        .into_iter()
+        .filter(|v| !files.contains(&storage::path(&[key, &v.file_name()])))
+        .collect())
+}
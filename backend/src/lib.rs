@@ -1,12 +1,12 @@
 use axum::{
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get_service, Router},
+    http::header,
+    middleware,
+    routing::{get, Router},
     Extension,
 };
 use rusqlite::params;
 use rusqlite_migration::{Migrations, M};
-use tower_http::{cors::CorsLayer, services::ServeDir, trace::TraceLayer};
+use tower_http::{cors::CorsLayer, trace::TraceLayer};
 
 use tracing::*;
 
@@ -16,8 +16,19 @@ use std::time::{Duration, SystemTime};
 
 pub struct AppState {
     db: tokio_rusqlite::Connection,
+    /// Unfinished uploads and other temporary files, media is kept in `storage`.
     data_path: PathBuf,
-    image_quality: u8,
+    storage: Arc<dyn storage::Storage>,
+    image_config: api::image::size::ImageConfig,
+    processing: api::image::processing::Queue,
+    /// Signs media urls, loaded from the database on first use.
+    media_secret: tokio::sync::OnceCell<Vec<u8>>,
+    /// Wakes the reaper right after images were deleted.
+    reaper: tokio::sync::Notify,
+    /// How long deleted images, albums and comments can be restored.
+    trash_retention: Duration,
+    /// Passkeys can only be used once the origin of the frontend is known.
+    relying_party: Option<api::login::passkey::RelyingParty>,
 }
 
 #[cfg(test)]
@@ -28,16 +39,26 @@ impl AppState {
             .path()
             .to_path_buf();
         let db = util::test::setup_database().await;
+        let (processing, _) = api::image::processing::Queue::new();
 
         Arc::new(AppState {
             db,
+            storage: Arc::new(storage::LocalStorage::new(data_path.clone())),
             data_path,
-            image_quality: 0,
+            image_config: Default::default(),
+            processing,
+            media_secret: Default::default(),
+            reaper: Default::default(),
+            trash_retention: api::trash::DEFAULT_RETENTION,
+            relying_party: Some(
+                api::login::passkey::RelyingParty::new("https://friends.example.com").unwrap(),
+            ),
         })
     }
 }
 
 pub mod cli;
+pub mod storage;
 pub mod util;
 
 pub mod api {
@@ -51,13 +72,49 @@ pub mod api {
     pub mod login;
     pub mod public_auth;
     pub mod settings;
+    pub mod trash;
+    pub mod uploads;
     pub mod user;
 }
 
-const AUTH_TIME_SECONDS: u64 = 3600 * 24 * 30;
+/// Bearer tokens have to be refreshed this often.
+const ACCESS_TOKEN_SECONDS: u64 = 3600;
+/// Sessions which were not used for this long expire, refresh token included.
+const SESSION_IDLE_SECONDS: u64 = 3600 * 24 * 30;
 
-pub fn api_route(db: tokio_rusqlite::Connection, data_path: PathBuf, image_quality: u8) -> Router {
-    let cors = CorsLayer::permissive();
+pub fn api_route(
+    db: tokio_rusqlite::Connection,
+    data_path: PathBuf,
+    storage: Arc<dyn storage::Storage>,
+    image_config: api::image::size::ImageConfig,
+    trash_retention: Duration,
+    relying_party: Option<api::login::passkey::RelyingParty>,
+) -> Router {
+    // The cors layer overwrites any Vary header, image derivatives are negotiated by Accept too
+    let cors = CorsLayer::permissive().vary([
+        header::ORIGIN,
+        header::ACCESS_CONTROL_REQUEST_METHOD,
+        header::ACCESS_CONTROL_REQUEST_HEADERS,
+        header::ACCEPT,
+    ]);
+
+    let (processing, processing_jobs) = api::image::processing::Queue::new();
+    let state = Arc::new(AppState {
+        db,
+        data_path,
+        storage,
+        image_config,
+        processing,
+        media_secret: Default::default(),
+        reaper: Default::default(),
+        trash_retention,
+        relying_party,
+    });
+
+    tokio::spawn(api::image::processing::run(state.clone(), processing_jobs));
+    tokio::spawn(api::uploads::collect_garbage(state.clone()));
+    tokio::spawn(api::image::tombstone::run(state.clone()));
+    tokio::spawn(api::trash::purge(state.clone()));
 
     Router::new()
         .nest("/api/auth", api::auth::api_route())
@@ -66,35 +123,57 @@ pub fn api_route(db: tokio_rusqlite::Connection, data_path: PathBuf, image_quali
         .nest("/api/comments", api::comment::api_route())
         .nest("/api/public/comments", api::comment::public_api_route())
         .nest("/api/images", api::image::api_route())
+        .nest("/api/uploads", api::uploads::api_route())
         .nest("/api/albums", api::album::api_route())
         .nest("/api/public/albums", api::album::public_api_route())
         .nest("/api/users", api::user::api_route())
         .nest("/api/aliases", api::alias::api_route())
         .nest("/api/settings", api::settings::api_route())
+        .nest("/api/trash", api::trash::api_route())
         .nest(
             "/data/image",
-            get_service(ServeDir::new(data_path.clone())).handle_error(handle_error),
+            get(api::image::media::serve)
+                .layer(middleware::from_fn(api::image::media::cache))
+                .layer(middleware::from_fn(api::image::media::negotiate_format))
+                .layer(middleware::from_fn(api::image::media::strip_original))
+                .layer(middleware::from_fn(api::image::media::authorize))
+                .layer(middleware::from_fn(api::image::media::hide_internal)),
         )
         .layer(cors)
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(Arc::new(AppState {
-            db,
-            data_path,
-            image_quality,
-        })))
+        .layer(Extension(state))
 }
 
-async fn handle_error(_err: std::io::Error) -> impl IntoResponse {
-    (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong...")
-}
-
-pub(crate) const MIGRATIONS: [M; 3] = [
+pub(crate) const MIGRATIONS: [M; 24] = [
     M::up(include_str!("../migrations/001_initial.sql")),
     M::up(include_str!(
         "../migrations/002_username_collate_nocase.sql"
     ))
     .foreign_key_check(),
     M::up(include_str!("../migrations/003_activity_changes.sql")).foreign_key_check(),
+    M::up(include_str!("../migrations/004_image_jobs.sql")),
+    M::up(include_str!("../migrations/005_upload_sessions.sql")),
+    M::up(include_str!("../migrations/006_image_content_hash.sql")),
+    M::up(include_str!("../migrations/007_image_perceptual_hash.sql")),
+    M::up(include_str!("../migrations/008_image_exif_fields.sql")),
+    M::up(include_str!("../migrations/009_image_capture_offset.sql")),
+    M::up(include_str!("../migrations/010_image_variants.sql")),
+    M::up(include_str!("../migrations/011_image_placeholder.sql")),
+    M::up(include_str!("../migrations/012_image_kind.sql")),
+    M::up(include_str!("../migrations/013_image_edits.sql")),
+    M::up(include_str!("../migrations/014_strip_metadata.sql")),
+    M::up(include_str!("../migrations/015_server_secrets.sql")),
+    M::up(include_str!("../migrations/016_image_version.sql")),
+    M::up(include_str!("../migrations/017_image_tombstones.sql")),
+    M::up(include_str!("../migrations/018_trash.sql")),
+    M::up(include_str!("../migrations/019_session_details.sql")),
+    M::up(include_str!("../migrations/020_hashed_session_tokens.sql")),
+    M::up(include_str!("../migrations/021_login_failures.sql")),
+    M::up(include_str!("../migrations/022_two_factor.sql")),
+    M::up(include_str!("../migrations/023_passkeys.sql")),
+    M::up(include_str!(
+        "../migrations/024_upload_session_finishing.sql"
+    )),
 ];
 
 pub async fn setup_database(path: &Path) -> anyhow::Result<tokio_rusqlite::Connection> {
@@ -113,14 +192,25 @@ pub async fn setup_database(path: &Path) -> anyhow::Result<tokio_rusqlite::Conne
 
     info!("Clearing old auth sessions");
     let now = SystemTime::UNIX_EPOCH.elapsed().unwrap();
-    let oldest_auth_time = now - Duration::from_secs(AUTH_TIME_SECONDS);
+    let oldest_auth_time = now - Duration::from_secs(SESSION_IDLE_SECONDS);
     db.call(move |conn| {
         conn.execute(
-            "DELETE FROM auth_sessions WHERE created_at<?1",
+            "DELETE FROM auth_sessions WHERE last_used_at<?1",
             params![oldest_auth_time.as_secs()],
         )
     })
     .await?;
 
+    info!("Clearing old login failures");
+    let oldest_failure_time =
+        now - Duration::from_secs(api::login::lockout::FAILURE_WINDOW_SECONDS);
+    db.call(move |conn| {
+        conn.execute(
+            "DELETE FROM login_failures WHERE last_failure_at<?1",
+            params![oldest_failure_time.as_secs()],
+        )
+    })
+    .await?;
+
     Ok(db)
 }
@@ -1,11 +1,15 @@
 use anyhow::Context;
 use tracing::*;
 
-use hivefriends::{api_route, cli, setup_database};
+use hivefriends::{
+    api::{self, image::size::ImageConfig},
+    api_route, cli, setup_database, storage,
+};
 
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
@@ -36,9 +40,10 @@ async fn run() -> anyhow::Result<()> {
         return cli::run_subcommand(sub, &db).await;
     }
 
-    let data_path = std::env::var("DATA_PATH")
+    let data_path: PathBuf = std::env::var("DATA_PATH")
         .context("DATA_PATH not set")?
         .into();
+    let storage = storage::from_env(&data_path)?;
 
     let image_quality = std::env::var("IMAGE_QUALITY")
         .as_deref()
@@ -46,6 +51,26 @@ async fn run() -> anyhow::Result<()> {
         .unwrap_or(Ok(75))
         .context("Failed to parse IMAGE_QUALITY")?;
 
+    let image_sizes = std::env::var("IMAGE_SIZES").ok();
+    let mut image_config = ImageConfig::new(image_quality, image_sizes.as_deref())
+        .context("Failed to parse IMAGE_SIZES")?;
+    // e.g. `ffmpeg -y -loglevel error -i {input} -frames:v 1 {output}`
+    image_config.poster_command = std::env::var("POSTER_COMMAND").ok();
+
+    let trash_retention = std::env::var("TRASH_RETENTION_DAYS")
+        .as_deref()
+        .map(u64::from_str)
+        .map(|days| days.map(|d| Duration::from_secs(3600 * 24 * d)))
+        .unwrap_or(Ok(api::trash::DEFAULT_RETENTION))
+        .context("Failed to parse TRASH_RETENTION_DAYS")?;
+
+    // e.g. `https://friends.hivecom.net`, where the frontend is served from
+    let relying_party = std::env::var("WEBAUTHN_ORIGIN")
+        .ok()
+        .map(|origin| api::login::passkey::RelyingParty::new(&origin))
+        .transpose()
+        .context("Failed to parse WEBAUTHN_ORIGIN")?;
+
     let bind_addr: SocketAddr = std::env::var("BIND_ADDRESS")
         .context("BIND_ADDRESS not set")?
         .parse()
@@ -53,7 +78,17 @@ async fn run() -> anyhow::Result<()> {
 
     info!("listening on {}", bind_addr);
     axum::Server::try_bind(&bind_addr)?
-        .serve(api_route(db, data_path, image_quality).into_make_service())
+        .serve(
+            api_route(
+                db,
+                data_path,
+                storage,
+                image_config,
+                trash_retention,
+                relying_party,
+            )
+            .into_make_service_with_connect_info::<SocketAddr>(),
+        )
         .await
         .unwrap();
 
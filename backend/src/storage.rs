@@ -0,0 +1,102 @@
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+mod local;
+mod s3;
+
+pub use local::LocalStorage;
+pub use s3::{S3Config, S3Storage};
+
+/// Size and modification time of a stored file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileInfo {
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+/// Where the files of images are kept. Paths are relative and separated by `/`, e.g.
+/// `<key>/original/<file_name>`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// `None` if there is no such file.
+    async fn info(&self, path: &str) -> anyhow::Result<Option<FileInfo>>;
+
+    async fn read(&self, path: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// Reads the bytes from `start` up to but excluding `end`.
+    async fn read_range(&self, path: &str, start: u64, end: u64) -> anyhow::Result<Vec<u8>>;
+
+    /// Replaces the file as a whole, readers never see a partially written file.
+    async fn write(&self, path: &str, data: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Makes `path` serve the contents of `target`, a file name in the same directory.
+    async fn alias(&self, path: &str, target: &str) -> anyhow::Result<()>;
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()>;
+
+    /// Missing files are ignored.
+    async fn remove(&self, path: &str) -> anyhow::Result<()>;
+
+    /// Removes every file below the directory.
+    async fn remove_all(&self, directory: &str) -> anyhow::Result<()>;
+
+    /// Paths of every file below the directory, including those of subdirectories.
+    async fn list(&self, directory: &str) -> anyhow::Result<Vec<String>>;
+
+    /// The file on disk if it can be handed to external commands as it is.
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Selects the storage with `STORAGE`, either `local` which keeps files in the data directory or
+/// `s3` for any S3 compatible object storage configured with the `S3_*` variables.
+pub fn from_env(data_path: &Path) -> anyhow::Result<Arc<dyn Storage>> {
+    let storage = std::env::var("STORAGE").unwrap_or_else(|_| "local".into());
+
+    match storage.as_str() {
+        "local" => Ok(Arc::new(LocalStorage::new(data_path.to_owned()))),
+        "s3" => Ok(Arc::new(S3Storage::new(S3Config::from_env()?)?)),
+        _ => bail!("Unknown STORAGE {storage}, expected local or s3"),
+    }
+}
+
+/// Joins the segments of a path, e.g. `path(&[key, "original", file_name])`.
+pub fn path(segments: &[&str]) -> String {
+    segments.join("/")
+}
+
+/// Copies every file of the data directory into the storage, files which were copied already are
+/// skipped so an interrupted migration can be resumed.
+pub async fn migrate(data_path: &Path, storage: &dyn Storage) -> anyhow::Result<usize> {
+    let source = LocalStorage::new(data_path.to_owned());
+    let mut copied = 0;
+
+    for path in source.list("").await? {
+        // Unfinished uploads and temporary files stay where they are
+        if path.split('/').any(|s| s.starts_with('.')) {
+            continue;
+        }
+
+        let info = source
+            .info(&path)
+            .await?
+            .with_context(|| format!("{path} disappeared while migrating"))?;
+        if storage.info(&path).await?.map(|i| i.len) == Some(info.len) {
+            continue;
+        }
+
+        let data = source.read(&path).await?;
+        storage
+            .write(&path, data)
+            .await
+            .with_context(|| format!("Failed to copy {path}"))?;
+        copied += 1;
+    }
+
+    Ok(copied)
+}
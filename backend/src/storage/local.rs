@@ -0,0 +1,259 @@
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use std::io::{ErrorKind, SeekFrom};
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+
+use super::{FileInfo, Storage};
+
+/// Keeps the files in a directory on disk.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        LocalStorage { root }
+    }
+
+    /// Only plain relative paths are accepted, nothing outside of the root can be reached.
+    fn resolve(&self, path: &str) -> anyhow::Result<PathBuf> {
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("Invalid storage path {path}");
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    /// A path next to the file which nothing else writes to, hidden from listings and the media
+    /// route.
+    fn temporary_path(path: &Path) -> anyhow::Result<PathBuf> {
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("Storage path has no file name")?;
+
+        Ok(path.with_file_name(format!(".{file_name}.{:08x}", rand::random::<u32>())))
+    }
+
+    async fn create_parent(path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn info(&self, path: &str) -> anyhow::Result<Option<FileInfo>> {
+        match fs::metadata(self.resolve(path)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(FileInfo {
+                len: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .context("Failed to read modification time")?,
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read metadata of {path}")),
+        }
+    }
+
+    async fn read(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        fs::read(self.resolve(path)?)
+            .await
+            .with_context(|| format!("Failed to read {path}"))
+    }
+
+    async fn read_range(&self, path: &str, start: u64, end: u64) -> anyhow::Result<Vec<u8>> {
+        let mut file = fs::File::open(self.resolve(path)?)
+            .await
+            .with_context(|| format!("Failed to open {path}"))?;
+        file.seek(SeekFrom::Start(start))
+            .await
+            .with_context(|| format!("Failed to seek in {path}"))?;
+
+        let mut data = Vec::with_capacity(end.saturating_sub(start) as usize);
+        file.take(end.saturating_sub(start))
+            .read_to_end(&mut data)
+            .await
+            .with_context(|| format!("Failed to read {path}"))?;
+
+        Ok(data)
+    }
+
+    async fn write(&self, path: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let path = self.resolve(path)?;
+        Self::create_parent(&path).await?;
+
+        // Writing to the file directly would also write through a symlink left by `alias`
+        let temporary_path = Self::temporary_path(&path)?;
+        fs::write(&temporary_path, data)
+            .await
+            .with_context(|| format!("Failed to write {}", temporary_path.display()))?;
+        fs::rename(&temporary_path, &path)
+            .await
+            .with_context(|| format!("Failed to move file to {}", path.display()))
+    }
+
+    async fn alias(&self, path: &str, target: &str) -> anyhow::Result<()> {
+        let path = self.resolve(path)?;
+        Self::create_parent(&path).await?;
+
+        let temporary_path = Self::temporary_path(&path)?;
+        symlink(target, &temporary_path).context("Failed to create symlink")?;
+        fs::rename(&temporary_path, &path)
+            .await
+            .with_context(|| format!("Failed to move symlink to {}", path.display()))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let (from, to) = (self.resolve(from)?, self.resolve(to)?);
+        Self::create_parent(&to).await?;
+
+        fs::rename(&from, &to)
+            .await
+            .with_context(|| format!("Failed to rename {}", from.display()))
+    }
+
+    async fn remove(&self, path: &str) -> anyhow::Result<()> {
+        match fs::remove_file(self.resolve(path)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {path}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn remove_all(&self, directory: &str) -> anyhow::Result<()> {
+        match fs::remove_dir_all(self.resolve(directory)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {directory}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, directory: &str) -> anyhow::Result<Vec<String>> {
+        let mut paths = Vec::new();
+        let mut directories = vec![directory.trim_matches('/').to_owned()];
+
+        while let Some(directory) = directories.pop() {
+            let absolute = if directory.is_empty() {
+                self.root.clone()
+            } else {
+                self.resolve(&directory)?
+            };
+
+            let mut entries = match fs::read_dir(&absolute).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to read directory {}", absolute.display())
+                    })
+                }
+            };
+
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .context("Failed to read directory entry")?
+            {
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(name) => bail!("File name {name:?} is not valid UTF-8"),
+                };
+                let path = if directory.is_empty() {
+                    name
+                } else {
+                    format!("{directory}/{name}")
+                };
+
+                // Symlinks are followed, they are listed like the file they point to
+                match fs::metadata(entry.path()).await {
+                    Ok(metadata) if metadata.is_dir() => directories.push(path),
+                    Ok(_) => paths.push(path),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).context("Failed to read metadata"),
+                }
+            }
+        }
+
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.resolve(path).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn write_alias_and_list() {
+        let temp = tempdir::TempDir::new("hivefriends-storage").unwrap();
+        let storage = LocalStorage::new(temp.path().to_owned());
+
+        storage
+            .write("key/full.jpg", b"full".to_vec())
+            .await
+            .unwrap();
+        storage.alias("key/tiny.jpg", "full.jpg").await.unwrap();
+        storage
+            .write("key/original/photo.jpg", b"original".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.list("key").await.unwrap(),
+            vec!["key/full.jpg", "key/original/photo.jpg", "key/tiny.jpg"]
+        );
+        assert_eq!(storage.read("key/tiny.jpg").await.unwrap(), b"full");
+        assert_eq!(
+            storage
+                .read_range("key/original/photo.jpg", 2, 5)
+                .await
+                .unwrap(),
+            b"igi"
+        );
+
+        // Replacing an alias must not write through to the file it points to
+        storage
+            .write("key/tiny.jpg", b"tiny".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(storage.read("key/full.jpg").await.unwrap(), b"full");
+
+        storage.remove_all("key").await.unwrap();
+        assert!(storage.list("").await.unwrap().is_empty());
+        assert_eq!(storage.info("key/full.jpg").await.unwrap(), None);
+    }
+
+    #[test]
+    fn reject_paths_outside_of_root() {
+        let storage = LocalStorage::new("/data".into());
+
+        assert!(storage.resolve("key/../../etc/passwd").is_err());
+        assert!(storage.resolve("/etc/passwd").is_err());
+        assert_eq!(
+            storage.resolve("key/full.jpg").unwrap(),
+            PathBuf::from("/data/key/full.jpg")
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use crate::api::error::Error;
 use serde::{Deserialize, Deserializer};
+use sha2::{digest::Output, Digest, Sha256};
 
 pub(super) fn non_empty_str<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
     let o: Option<String> = Option::deserialize(d)?;
@@ -38,6 +39,44 @@ pub fn check_length(
     Ok(())
 }
 
+/// Encodes everything but unreserved characters, for query values and path segments.
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
+
+/// HMAC as described in RFC 2104, for hashes with 64 byte blocks like SHA-1 and SHA-256.
+pub fn hmac<D: Digest>(key: &[u8], message: &[u8]) -> Output<D> {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        let digest = D::digest(key);
+        block[..digest.len()].copy_from_slice(&digest);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner = D::new()
+        .chain_update(block.map(|b| b ^ 0x36))
+        .chain_update(message)
+        .finalize();
+
+    D::new()
+        .chain_update(block.map(|b| b ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Output<Sha256> {
+    hmac::<Sha256>(key, message)
+}
+
 #[cfg(test)]
 pub mod test {
     use crate::api::{
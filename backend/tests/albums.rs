@@ -203,3 +203,52 @@ async fn change_images_with_order() {
     let description = json["description"].as_str().unwrap();
     assert_eq!(description, expected_descrption);
 }
+
+#[tokio::test]
+async fn similar_images() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+    let album_key = create_test_album(&client, &token).await;
+    let image_key1 = upload_test_image("./tests/testimage.png", &client, &token).await;
+    let other_key = upload_test_image("./tests/exif.jpg", &client, &token).await;
+    let image_key2 = upload_test_image("./tests/testimage.png", &client, &token).await;
+
+    let res = client
+        .put(&format!("/api/albums/{album_key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({
+            "imageKeys": [image_key1, other_key, image_key2],
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get(&format!("/api/albums/{album_key}/similar"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json, json!([[image_key1, image_key2]]));
+
+    let res = client
+        .get("/api/images/similar")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    // The image of the test album is the same as well
+    let json = res.json::<Value>().await;
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0].as_array().unwrap().len(), 3);
+
+    let res = client
+        .get("/api/albums/missing/similar")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 404);
+}
@@ -1,5 +1,9 @@
 use assert_matches::assert_matches;
-use axum::http::header::AUTHORIZATION;
+use axum::http::header::{
+    ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE,
+    VARY,
+};
+use reqwest::multipart::{Form, Part};
 use serde_json::*;
 
 mod util;
@@ -7,10 +11,505 @@ use util::*;
 
 #[tokio::test]
 async fn upload() {
+    let (client, temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let image_key = upload_test_image("./tests/testimage.png", &client, &token).await;
+
+    let image_dir = temp.path().join("data").join(&image_key);
+    for name in ["full", "large", "medium", "tiny"] {
+        for extension in ["jpg", "webp", "avif"] {
+            let name = format!("{name}.{extension}");
+            assert!(image_dir.join(&name).exists(), "{name} was not generated");
+        }
+    }
+}
+
+#[test_case::test_case("image/avif,image/webp,*/*", "image/avif")]
+#[test_case::test_case("image/webp,*/*", "image/webp")]
+#[test_case::test_case("*/*", "image/jpeg")]
+#[tokio::test]
+async fn negotiate_image_format(accept: &str, content_type: &str) {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let image_key = upload_test_image("./tests/testimage.png", &client, &token).await;
+
+    let res = client
+        .get(&format!("/data/image/{image_key}/medium.jpg"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .header(ACCEPT, accept)
+        .send()
+        .await;
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()[CONTENT_TYPE], content_type);
+    assert!(res.headers().get_all(VARY).iter().any(|v| v == "accept"));
+}
+
+#[tokio::test]
+async fn list_variants() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let image_key = upload_test_image("./tests/testimage.png", &client, &token).await;
+
+    let res = client
+        .get(&format!("/api/images/{image_key}/variants"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    let variants = json.as_array().unwrap();
+    assert_eq!(variants.len(), 12);
+
+    let tiny = variants
+        .iter()
+        .find(|v| v["fileName"] == "tiny.webp")
+        .unwrap();
+    assert_eq!(tiny["name"], "tiny");
+    assert_eq!(tiny["mime"], "image/webp");
+    assert_eq!(tiny["maxDimension"], 360);
+    assert_eq!(tiny["mode"], "fit");
+    assert_eq!(tiny["width"], 350);
+
+    let res = client
+        .get("/api/images/missing/variants")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn upload_video() {
+    let (client, temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let key = upload_test_image("./tests/testvideo.mp4", &client, &token).await;
+
+    let res = client
+        .get(&format!("/api/images/{key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json["kind"], "video");
+    assert_eq!(json["duration"], 4.5);
+    assert_eq!(json["takenAt"], 1656676800);
+    // Recorded sideways and rotated
+    assert_eq!(json["width"], 1080);
+    assert_eq!(json["height"], 1920);
+
+    let image_dir = temp.path().join("data").join(&key);
+    assert!(image_dir.join("tiny.jpg").exists());
+    assert!(!image_dir.join("poster.png").exists());
+}
+
+#[tokio::test]
+async fn transform_image() {
+    let (client, temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let key = upload_test_image("./tests/testimage.png", &client, &token).await;
+    let image_dir = temp.path().join("data").join(&key);
+    let original = std::fs::read_dir(image_dir.join("original"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let original_data = std::fs::read(&original).unwrap();
+
+    let (width, height) = image_dimensions(&key, &client, &token).await;
+
+    let res = client
+        .put(&format!("/api/images/{key}/transform"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({
+            "operations": [
+                { "op": "rotate", "degrees": 90 },
+                { "op": "crop", "x": 0, "y": 0, "width": height, "height": 10 },
+            ]
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    wait_for_processing(&key, &client, &token).await;
+
+    assert_eq!(image_dimensions(&key, &client, &token).await, (height, 10));
+    assert_eq!(std::fs::read(&original).unwrap(), original_data);
+
+    let res = client
+        .put(&format!("/api/images/{key}/transform"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({
+            "operations": [{ "op": "crop", "x": 1, "y": 0, "width": width, "height": height }]
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 400);
+
+    let res = client
+        .delete(&format!("/api/images/{key}/transform"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    wait_for_processing(&key, &client, &token).await;
+
+    assert_eq!(
+        image_dimensions(&key, &client, &token).await,
+        (width, height)
+    );
+
+    let res = client
+        .delete(&format!("/api/images/{key}/transform"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 404);
+}
+
+async fn image_dimensions(
+    key: &str,
+    client: &axum_test_helper::TestClient,
+    token: &str,
+) -> (u64, u64) {
+    let res = client
+        .get(&format!("/api/images/{key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    let json = res.json::<Value>().await;
+
+    (
+        json["width"].as_u64().unwrap(),
+        json["height"].as_u64().unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn strip_original_metadata() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let image_key = upload_test_image("./tests/exif.jpg", &client, &token).await;
+    let original = std::fs::read("./tests/exif.jpg").unwrap();
+    let path = format!("/data/image/{image_key}/original/testimage.png");
+
+    let res = client
+        .put("/api/settings")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({ "stripMetadata": true }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    // The uploader always gets the original
+    let res = client
+        .get(&path)
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.to_vec(), original);
+
+    // Share links never include the coordinates
+    let res = client
+        .post("/api/albums")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({
+            "title": "Trip",
+            "coverKey": image_key,
+            "draft": false,
+            "timeframe": {},
+            "imageKeys": [image_key],
+            "taggedUsers": [],
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    let album_key = res.json::<Value>().await["key"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let share_token = share_test_album(&album_key, &client, &token).await;
+
+    let res = client
+        .get(&format!("{path}?album={album_key}&token={share_token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let stripped = res.bytes().await.to_vec();
+    assert!(stripped.len() < original.len());
+    if let Some(metadata) = hivefriends::api::image::upload::read_exif(&stripped) {
+        assert!(metadata
+            .fields()
+            .all(|f| f.tag.context() != exif::Context::Gps && f.tag != exif::Tag::Make));
+    }
+
+    let res = client
+        .get(&format!("/api/public/albums/{album_key}/{share_token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json["images"][0]["location"], Value::Null);
+    assert_eq!(json["images"][0]["cameraBrand"], Value::Null);
+    assert!(json["images"][0]["stripMetadata"].is_null());
+}
+
+#[tokio::test]
+async fn authorize_media() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let album_key = create_test_album(&client, &token).await;
+    let share_token = share_test_album(&album_key, &client, &token).await;
+    let other_key = upload_test_image("./tests/testimage.png", &client, &token).await;
+
+    let res = client
+        .get(&format!("/api/albums/{album_key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    let image_key = res.json::<Value>().await["images"][0]["key"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let path = format!("/data/image/{image_key}/medium.jpg");
+
+    let res = client.get(&path).send().await;
+    assert_eq!(res.status(), 401);
+
+    let res = client
+        .get("/api/images/media-token")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    let query = res.json::<Value>().await["query"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+
+    let res = client.get(&format!("{path}?{query}")).send().await;
+    assert_eq!(res.status(), 200);
+
+    let forged = query.replace("user=username", "user=someone");
+    let res = client.get(&format!("{path}?{forged}")).send().await;
+    assert_eq!(res.status(), 401);
+
+    let shared = format!("album={album_key}&token={share_token}");
+    let res = client.get(&format!("{path}?{shared}")).send().await;
+    assert_eq!(res.status(), 200);
+
+    // The share token only covers the images of its album
+    let res = client
+        .get(&format!("/data/image/{other_key}/medium.jpg?{shared}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 401);
+
+    // Signed queries end with their session
+    let res = client
+        .post("/api/auth/logout")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client.get(&format!("{path}?{query}")).send().await;
+    assert_eq!(res.status(), 401);
+}
+
+#[tokio::test]
+async fn cache_media() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let key = upload_test_image("./tests/testimage.png", &client, &token).await;
+    let version = image_version(&key, &client, &token).await;
+
+    let path = format!("/data/image/{key}/medium.jpg?v={version}");
+    let res = client
+        .get(&path)
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.headers()[CACHE_CONTROL],
+        "private, max-age=31536000, immutable"
+    );
+    let etag = res.headers()[ETAG].clone();
+
+    let res = client
+        .get(&path)
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .header(IF_NONE_MATCH, etag.clone())
+        .send()
+        .await;
+    assert_eq!(res.status(), 304);
+    assert_eq!(res.headers()[ETAG], etag);
+
+    let res = client
+        .get(&format!("/data/image/{key}/original/testimage.png"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .header(RANGE, "bytes=0-9")
+        .send()
+        .await;
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.headers()[CONTENT_LENGTH], "10");
+    assert_eq!(res.headers()[CACHE_CONTROL], "private, no-cache");
+    assert!(res.headers().contains_key(ETAG));
+
+    let res = client
+        .put(&format!("/api/images/{key}/transform"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({ "operations": [{ "op": "rotate", "degrees": 90 }] }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    wait_for_processing(&key, &client, &token).await;
+
+    assert!(image_version(&key, &client, &token).await > version);
+}
+
+async fn image_version(key: &str, client: &axum_test_helper::TestClient, token: &str) -> u64 {
+    let res = client
+        .get(&format!("/api/images/{key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+
+    res.json::<Value>().await["version"].as_u64().unwrap()
+}
+
+#[tokio::test]
+async fn upload_invalid_image() {
     let (client, _temp) = setup_test_client().await;
     let (token, _) = authenticate(&client).await;
 
-    upload_test_image("./tests/testimage.png", &client, &token).await;
+    let part = Part::bytes(b"not an image".to_vec()).file_name("broken.jpg");
+    let form = Form::new().part("file", part);
+
+    let res = client
+        .post("/api/images")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .multipart(form)
+        .send()
+        .await;
+    assert_eq!(dbg!(res.status()), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json[0]["fileName"], "broken.jpg");
+    assert!(json[0]["message"].is_string());
+}
+
+#[tokio::test]
+async fn upload_multiple_files() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let image = std::fs::read("./tests/testimage.png").unwrap();
+    let form = Form::new()
+        .part("file", Part::bytes(image.clone()).file_name("first.png"))
+        .text("description", "The first one")
+        .part(
+            "file",
+            Part::bytes(b"nope".to_vec()).file_name("broken.png"),
+        )
+        .text("description", "Not an image")
+        .part("file", Part::bytes(image).file_name("second.png"));
+
+    let res = client
+        .post("/api/images?allowDuplicates=true")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .multipart(form)
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    dbg!(&json);
+    let results = json.as_array().unwrap();
+    assert_eq!(results.len(), 3);
+
+    assert_eq!(results[0]["fileName"], "first.png");
+    assert_eq!(results[0]["description"], "The first one");
+    assert!(results[0]["key"].is_string());
+
+    assert_eq!(results[1]["fileName"], "broken.png");
+    assert!(results[1]["key"].is_null());
+    assert!(results[1]["message"].is_string());
+
+    assert_eq!(results[2]["fileName"], "second.png");
+    assert!(results[2]["description"].is_null());
+
+    for result in [&results[0], &results[2]] {
+        wait_for_processing(result["key"].as_str().unwrap(), &client, &token).await;
+    }
+}
+
+#[tokio::test]
+async fn upload_duplicate() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let key = upload_test_image("./tests/testimage.png", &client, &token).await;
+    let image = std::fs::read("./tests/testimage.png").unwrap();
+
+    let form = Form::new().part("file", Part::bytes(image.clone()).file_name("again.png"));
+    let res = client
+        .post("/api/images")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .multipart(form)
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json[0]["fileName"], "again.png");
+    assert_eq!(json[0]["existingKey"], key);
+    assert!(json[0]["message"].is_string());
+
+    let form = Form::new().part("file", Part::bytes(image).file_name("again.png"));
+    let res = client
+        .post("/api/images?allowDuplicates=true")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .multipart(form)
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    let other = json[0]["key"].as_str().unwrap();
+    assert_ne!(other, key);
+    wait_for_processing(other, &client, &token).await;
+}
+
+#[tokio::test]
+async fn upload_without_files() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let form = Form::new().text("description", "Nothing to describe");
+
+    let res = client
+        .post("/api/images")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .multipart(form)
+        .send()
+        .await;
+    assert_eq!(res.status(), 400);
 }
 
 #[tokio::test]
@@ -32,7 +531,10 @@ async fn upload_with_exif() {
     dbg!(&json);
     assert_eq!(status, 200);
 
-    assert_eq!(json["takenAt"].as_u64().unwrap().to_owned(), 1224692919,);
+    // Taken in Italy during summer time
+    assert_eq!(json["takenAt"].as_u64().unwrap().to_owned(), 1224685719,);
+    assert_eq!(json["takenAtLocal"], "2008-10-22T16:28:39");
+    assert_eq!(json["takenAtOffset"], 7200);
     assert_eq!(json["cameraBrand"].as_str().unwrap().to_owned(), "NIKON",);
 
     let location = json
@@ -52,6 +554,31 @@ async fn upload_with_exif() {
         location["longitude"].as_str().unwrap().to_owned(),
         "11.885126666663888",
     );
+
+    assert_eq!(json["iso"], 64);
+    assert_eq!(json["whiteBalance"], "auto white balance");
+    // Stored upright
+    assert_eq!(json["width"], 480);
+    assert_eq!(json["height"], 640);
+    assert_eq!(json["aspectRatio"], 0.75);
+    assert_eq!(json["keywords"], json!([]));
+
+    let res = client
+        .get(&format!("/api/images/{image_key}/exif"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    let make = json["exif"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|field| field["tag"] == "Make")
+        .unwrap();
+    assert_eq!(make["value"], "\"NIKON\"");
+    assert!(json["xmp"].as_str().unwrap().contains("MicrosoftPhoto"));
 }
 
 #[tokio::test]
@@ -74,6 +601,8 @@ async fn get_by_key() {
     assert_eq!(status, 200);
 
     assert_eq!(json["uploader"].as_str().unwrap().to_owned(), username);
+    assert_eq!(json["blurhash"].as_str().unwrap().len(), 28);
+    assert!(json["dominantColor"].as_str().unwrap().starts_with('#'));
 }
 
 #[tokio::test]
@@ -95,10 +624,6 @@ async fn delete_by_key() {
     let status = dbg!(res.status());
     assert_eq!(status, 200);
 
-    assert_matches!(std::fs::read_dir(image_dir), Err(err) => {
-        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
-    });
-
     let res = client
         .get(&format!("/api/images/{image_key}"))
         .header(AUTHORIZATION, format!("Bearer {token}"))
@@ -107,6 +632,32 @@ async fn delete_by_key() {
 
     let status = dbg!(res.status());
     assert_eq!(status, 404);
+
+    // The image is in the trash until it is purged, its files are kept until then
+    assert_matches!(std::fs::read_dir(&image_dir), Ok(_));
+
+    let res = client
+        .get("/api/trash")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+    let json = res.json::<Value>().await;
+    assert_eq!(json["images"][0]["key"], image_key);
+
+    let res = client
+        .post(&format!("/api/trash/images/{image_key}/restore"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get(&format!("/api/images/{image_key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
 }
 
 /* TODO Functionality to list images is still missing
@@ -1,5 +1,8 @@
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER, USER_AGENT};
 use serde_json::{json, Value};
 
+use std::sync::Arc;
+
 mod util;
 use util::setup_test_client;
 
@@ -38,4 +41,193 @@ async fn authenticate() {
 
     assert_eq!(status, 200);
     assert_eq!(token.len(), 64);
+    assert_eq!(json["refreshToken"].as_str().unwrap().len(), 64);
+}
+
+#[tokio::test]
+async fn refresh() {
+    let (client, _temp) = setup_test_client().await;
+
+    let res = client
+        .post("/api/login")
+        .json(&json!({"username":"username","password":"password"}))
+        .send()
+        .await;
+    let json = res.json::<Value>().await;
+    let token = json["bearerToken"].as_str().unwrap();
+    let refresh_token = json["refreshToken"].as_str().unwrap();
+
+    let res = client
+        .post("/api/login/refresh")
+        .json(&json!({ "refreshToken": refresh_token }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json["username"], "username");
+    let new_token = json["bearerToken"].as_str().unwrap();
+
+    let res = client
+        .get("/api/auth")
+        .header(AUTHORIZATION, format!("Bearer {new_token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    // Both old tokens were replaced
+    let res = client
+        .get("/api/auth")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 401);
+
+    let res = client
+        .post("/api/login/refresh")
+        .json(&json!({ "refreshToken": refresh_token }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 401);
+}
+
+#[tokio::test]
+async fn sessions() {
+    let (client, _temp) = setup_test_client().await;
+
+    let mut tokens = Vec::new();
+    for agent in ["first", "second", "third"] {
+        let res = client
+            .post("/api/login")
+            .header(USER_AGENT, agent)
+            .json(&json!({"username":"username","password":"password"}))
+            .send()
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let json = res.json::<Value>().await;
+        tokens.push(json["bearerToken"].as_str().unwrap().to_owned());
+    }
+
+    let res = client
+        .get("/api/auth/sessions")
+        .header(AUTHORIZATION, format!("Bearer {}", tokens[0]))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let sessions = res.json::<Value>().await;
+    let sessions = sessions.as_array().unwrap();
+    assert_eq!(sessions.len(), 3);
+    let current: Vec<_> = sessions
+        .iter()
+        .filter(|s| s["current"].as_bool().unwrap())
+        .collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0]["userAgent"], "first");
+    assert!(current[0]["lastUsedAt"].is_u64());
+
+    let second = sessions
+        .iter()
+        .find(|s| s["userAgent"] == "second")
+        .unwrap();
+    let res = client
+        .delete(&format!("/api/auth/sessions/{}", second["id"]))
+        .header(AUTHORIZATION, format!("Bearer {}", tokens[0]))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get("/api/auth")
+        .header(AUTHORIZATION, format!("Bearer {}", tokens[1]))
+        .send()
+        .await;
+    assert_eq!(res.status(), 401);
+
+    let res = client
+        .post("/api/auth/logout")
+        .header(AUTHORIZATION, format!("Bearer {}", tokens[2]))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get("/api/auth")
+        .header(AUTHORIZATION, format!("Bearer {}", tokens[2]))
+        .send()
+        .await;
+    assert_eq!(res.status(), 401);
+
+    // Log out everywhere, including the session asking for it
+    let res = client
+        .delete("/api/auth/sessions")
+        .header(AUTHORIZATION, format!("Bearer {}", tokens[0]))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get("/api/auth")
+        .header(AUTHORIZATION, format!("Bearer {}", tokens[0]))
+        .send()
+        .await;
+    assert_eq!(res.status(), 401);
+}
+
+#[tokio::test]
+async fn lockout() {
+    let (client, _temp) = setup_test_client().await;
+
+    for _ in 0..5 {
+        let res = client
+            .post("/api/login")
+            .json(&json!({"username":"username","password":"invalid"}))
+            .send()
+            .await;
+        assert_eq!(res.status(), 401);
+    }
+
+    // Even the right password is turned away until the lockout ends
+    let res = client
+        .post("/api/login")
+        .json(&json!({"username":"username","password":"password"}))
+        .send()
+        .await;
+    assert_eq!(res.status(), 429);
+    let retry_after: u64 = res.headers()[RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 30);
+}
+
+#[tokio::test]
+async fn concurrent_lockout() {
+    let (client, _temp) = setup_test_client().await;
+    let client = Arc::new(client);
+
+    // Attempts are counted before the slow password check, a burst can't get more guesses
+    let attempts: Vec<_> = (0..10)
+        .map(|_| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .post("/api/login")
+                    .json(&json!({"username":"username","password":"invalid"}))
+                    .send()
+                    .await
+                    .status()
+            })
+        })
+        .collect();
+
+    let mut statuses = Vec::new();
+    for attempt in attempts {
+        statuses.push(attempt.await.unwrap());
+    }
+
+    assert_eq!(statuses.iter().filter(|s| **s == 401).count(), 5);
+    assert_eq!(statuses.iter().filter(|s| **s == 429).count(), 5);
 }
@@ -8,6 +8,7 @@ use util::*;
 async fn put_password() {
     let (client, _temp) = setup_test_client().await;
     let (token, username) = authenticate(&client).await;
+    let (other_token, _) = authenticate(&client).await;
 
     let new_password = "not password";
 
@@ -27,6 +28,21 @@ async fn put_password() {
     dbg!(&json);
     assert_eq!(status, 200);
 
+    // Only the session which changed the password stays logged in
+    let res = client
+        .get("/api/auth")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get("/api/auth")
+        .header(AUTHORIZATION, format!("Bearer {other_token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 401);
+
     let res = client
         .post("/api/login")
         .json(&json!({
@@ -44,3 +60,48 @@ async fn put_password() {
 
     assert_eq!(json["username"].as_str().unwrap().to_owned(), username);
 }
+
+#[tokio::test]
+async fn totp_enrollment() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let res = client
+        .post("/api/settings/totp")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json["secret"].as_str().unwrap().len(), 32);
+    assert!(json["provisioningUri"]
+        .as_str()
+        .unwrap()
+        .starts_with("otpauth://totp/hi%21friends:username?secret="));
+
+    let res = client
+        .post("/api/settings/totp/confirm")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({ "code": "not a code" }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 400);
+
+    // Nothing changes until a code of the new secret was confirmed
+    let res = client
+        .get("/api/settings")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    let json = res.json::<Value>().await;
+    assert_eq!(json["totpEnabled"], false);
+
+    let res = client
+        .post("/api/login")
+        .json(&json!({"username":"username","password":"password"}))
+        .send()
+        .await;
+    let json = res.json::<Value>().await;
+    assert!(json["bearerToken"].is_string());
+}
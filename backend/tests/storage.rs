@@ -0,0 +1,125 @@
+use axum::http::header::{AUTHORIZATION, CONTENT_LENGTH, RANGE};
+use tempdir::TempDir;
+
+use hivefriends::storage::{self, LocalStorage, S3Storage, Storage};
+
+use std::sync::Arc;
+
+mod util;
+use util::*;
+
+#[tokio::test]
+async fn object_storage() {
+    let storage = S3Storage::new(start_object_storage().await).unwrap();
+
+    storage
+        .write("key/full.jpg", b"full".to_vec())
+        .await
+        .unwrap();
+    storage.alias("key/tiny.jpg", "full.jpg").await.unwrap();
+    storage
+        .write("key/original/my photo.jpg", b"original".to_vec())
+        .await
+        .unwrap();
+    storage
+        .write("other/full.jpg", b"other".to_vec())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        storage.info("key/full.jpg").await.unwrap().map(|i| i.len),
+        Some(4)
+    );
+    assert_eq!(storage.info("key/missing.jpg").await.unwrap(), None);
+    assert_eq!(storage.read("key/tiny.jpg").await.unwrap(), b"full");
+    assert_eq!(
+        storage
+            .read_range("key/original/my photo.jpg", 2, 5)
+            .await
+            .unwrap(),
+        b"igi"
+    );
+
+    storage
+        .rename("key/original/my photo.jpg", "key/original/renamed.jpg")
+        .await
+        .unwrap();
+    assert_eq!(
+        storage.list("key").await.unwrap(),
+        vec!["key/full.jpg", "key/original/renamed.jpg", "key/tiny.jpg"]
+    );
+
+    storage.remove_all("key").await.unwrap();
+    assert_eq!(storage.list("").await.unwrap(), vec!["other/full.jpg"]);
+}
+
+#[tokio::test]
+async fn serve_from_object_storage() {
+    let storage = Arc::new(S3Storage::new(start_object_storage().await).unwrap());
+    let temp_dir = TempDir::new("hivefriends-test").unwrap();
+    let (client, _temp) = setup_test_client_with_storage(temp_dir, storage.clone()).await;
+    let (token, _) = authenticate(&client).await;
+
+    let key = upload_test_image("./tests/testimage.png", &client, &token).await;
+    assert!(storage
+        .info(&format!("{key}/original/testimage.png"))
+        .await
+        .unwrap()
+        .is_some());
+
+    let res = client
+        .get(&format!("/data/image/{key}/medium.jpg"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get(&format!("/data/image/{key}/original/testimage.png"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .header(RANGE, "bytes=0-9")
+        .send()
+        .await;
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.headers()[CONTENT_LENGTH], "10");
+
+    let res = client
+        .delete(&format!("/api/images/{key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    // Files are kept while the image is in the trash
+    assert!(!storage.list(&key).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn migrate_data_directory() {
+    let temp_dir = TempDir::new("hivefriends-test").unwrap();
+    let local = LocalStorage::new(temp_dir.path().to_owned());
+    local.write("key/full.jpg", b"full".to_vec()).await.unwrap();
+    local.alias("key/tiny.jpg", "full.jpg").await.unwrap();
+    local
+        .write(".uploads/unfinished", b"upload".to_vec())
+        .await
+        .unwrap();
+
+    let storage = S3Storage::new(start_object_storage().await).unwrap();
+    assert_eq!(
+        storage::migrate(temp_dir.path(), &storage).await.unwrap(),
+        2
+    );
+
+    assert_eq!(
+        storage.list("").await.unwrap(),
+        vec!["key/full.jpg", "key/tiny.jpg"]
+    );
+    assert_eq!(storage.read("key/tiny.jpg").await.unwrap(), b"full");
+
+    // Files which were copied already are skipped
+    assert_eq!(
+        storage::migrate(temp_dir.path(), &storage).await.unwrap(),
+        0
+    );
+}
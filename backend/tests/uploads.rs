@@ -0,0 +1,160 @@
+use axum::http::header::AUTHORIZATION;
+use serde_json::*;
+
+mod util;
+use util::*;
+
+#[tokio::test]
+async fn resumable_upload() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let data = std::fs::read("./tests/testimage.png").unwrap();
+    let (first, second) = data.split_at(data.len() / 2);
+
+    let res = client
+        .post("/api/uploads")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({
+            "fileName": "testimage.png",
+            "description": "Sent in pieces",
+            "sizeBytes": data.len(),
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    let id = json["id"].as_str().unwrap().to_owned();
+    assert_eq!(json["receivedBytes"], 0);
+
+    let res = client
+        .post(&format!("/api/uploads/{id}/finish"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 400);
+
+    for _ in 0..2 {
+        let res = client
+            .put(&format!("/api/uploads/{id}/chunks/0"))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(first.to_vec())
+            .send()
+            .await;
+        assert_eq!(res.status(), 200);
+    }
+
+    let res = client
+        .put(&format!("/api/uploads/{id}/chunks/2"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .body(second.to_vec())
+        .send()
+        .await;
+    assert_eq!(res.status(), 409);
+
+    let res = client
+        .get(&format!("/api/uploads/{id}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json["receivedBytes"], first.len());
+    assert_eq!(json["nextChunk"], 1);
+
+    let res = client
+        .put(&format!("/api/uploads/{id}/chunks/1"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .body(second.to_vec())
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .post(&format!("/api/uploads/{id}/finish"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    assert_eq!(json["fileName"], "testimage.png");
+    assert_eq!(json["description"], "Sent in pieces");
+    assert_eq!(json["sizeBytes"], data.len());
+
+    wait_for_processing(json["key"].as_str().unwrap(), &client, &token).await;
+
+    let res = client
+        .get(&format!("/api/uploads/{id}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn upload_chunk_beyond_size() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    let res = client
+        .post("/api/uploads")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({
+            "fileName": "small.png",
+            "sizeBytes": 4,
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    let id = json["id"].as_str().unwrap();
+
+    let res = client
+        .put(&format!("/api/uploads/{id}/chunks/0"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .body(vec![0; 8])
+        .send()
+        .await;
+    assert_eq!(res.status(), 400);
+
+    let res = client
+        .delete(&format!("/api/uploads/{id}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+}
+
+#[tokio::test]
+async fn upload_image_beyond_limit() {
+    let (client, _temp) = setup_test_client().await;
+    let (token, _) = authenticate(&client).await;
+
+    // Sessions may announce the size of a clip
+    let res = client
+        .post("/api/uploads")
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .json(&json!({
+            "fileName": "large.png",
+            "sizeBytes": 100 * 1024 * 1024,
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), 200);
+
+    let json = res.json::<Value>().await;
+    let id = json["id"].as_str().unwrap();
+
+    // But images are turned away as soon as the first chunk shows what they are
+    let res = client
+        .put(&format!("/api/uploads/{id}/chunks/0"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .body(include_bytes!("testimage.png").to_vec())
+        .send()
+        .await;
+    assert_eq!(res.status(), 400);
+}
@@ -1,23 +1,47 @@
 #![allow(dead_code)]
 
-use axum::http::header::AUTHORIZATION;
+use axum::{
+    body::Bytes,
+    http::{
+        header::{AUTHORIZATION, CONTENT_LENGTH, RANGE},
+        HeaderMap, Method, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    routing::any,
+    Extension, Router,
+};
 use axum_test_helper::TestClient;
+use headers::{HeaderMapExt, LastModified};
 use reqwest::multipart::{Form, Part};
 use serde_json::{json, Value};
 use tempdir::TempDir;
 
 use hivefriends::{
+    api::{image::size::ImageConfig, trash},
     api_route,
     cli::{run_subcommand, AddUserArgs, SubCommands},
     setup_database,
+    storage::{LocalStorage, S3Config, Storage},
 };
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 pub async fn setup_test_client() -> (TestClient, TempDir) {
     let temp_dir = TempDir::new("hivefriends-test").unwrap();
+    let storage = Arc::new(LocalStorage::new(temp_dir.path().join("data")));
+
+    setup_test_client_with_storage(temp_dir, storage).await
+}
 
+pub async fn setup_test_client_with_storage(
+    temp_dir: TempDir,
+    storage: Arc<dyn Storage>,
+) -> (TestClient, TempDir) {
     let db_path = temp_dir.path().join("test.db");
     let db = setup_database(&db_path).await.unwrap();
 
@@ -30,7 +54,21 @@ pub async fn setup_test_client() -> (TestClient, TempDir) {
     let sub = SubCommands::AddUser(args);
     run_subcommand(sub, &db).await.unwrap();
 
-    (TestClient::new(api_route(db, data_path, 75)), temp_dir)
+    // Stands in for ffmpeg, every video gets the test image as its poster
+    let mut image_config = ImageConfig::default();
+    image_config.poster_command = Some("cp ./tests/testimage.png {output}".into());
+
+    (
+        TestClient::new(api_route(
+            db,
+            data_path,
+            storage,
+            image_config,
+            trash::DEFAULT_RETENTION,
+            None,
+        )),
+        temp_dir,
+    )
 }
 
 pub async fn authenticate(client: &TestClient) -> (String, String) {
@@ -63,8 +101,9 @@ pub async fn upload_test_image(path: &str, client: &TestClient, token: &str) ->
     let part = Part::bytes(data).file_name("testimage.png");
     let form = Form::new().part("file", part);
 
+    // Tests upload the same file to get several images
     let res = client
-        .post("/api/images")
+        .post("/api/images?allowDuplicates=true")
         .header(AUTHORIZATION, format!("Bearer {token}"))
         .multipart(form)
         .send()
@@ -76,7 +115,30 @@ pub async fn upload_test_image(path: &str, client: &TestClient, token: &str) ->
     dbg!(&json);
     assert_eq!(status, 200);
 
-    json["key"].as_str().unwrap().into()
+    let key = json[0]["key"].as_str().unwrap();
+    wait_for_processing(key, client, token).await;
+
+    key.into()
+}
+
+pub async fn wait_for_processing(key: &str, client: &TestClient, token: &str) {
+    for _ in 0..200 {
+        let res = client
+            .get(&format!("/api/images/{key}"))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .send()
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let json = res.json::<Value>().await;
+        match json["processing"]["state"].as_str() {
+            None => return,
+            Some("failed") => panic!("Failed to process {key}: {}", json["processing"]),
+            Some(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+
+    panic!("Processing {key} did not finish in time");
 }
 
 pub async fn create_test_album(client: &TestClient, token: &str) -> String {
@@ -111,3 +173,180 @@ pub async fn create_test_album(client: &TestClient, token: &str) -> String {
 
     json["key"].as_str().unwrap().into()
 }
+
+pub async fn share_test_album(album_key: &str, client: &TestClient, token: &str) -> String {
+    let res = client
+        .post(&format!("/api/public/albums/{album_key}"))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await;
+
+    let status = dbg!(res.status());
+
+    let json = res.json::<Value>().await;
+    dbg!(&json);
+    assert_eq!(status, 200);
+
+    json["token"].as_str().unwrap().into()
+}
+
+type Objects = Arc<Mutex<BTreeMap<String, (Vec<u8>, SystemTime)>>>;
+
+/// Only two keys are listed at once so continuation tokens are used.
+const LIST_PAGE_SIZE: usize = 2;
+
+/// Stands in for an S3 compatible storage, keeping the objects of a single bucket in memory.
+pub async fn start_object_storage() -> S3Config {
+    let objects = Objects::default();
+    let app = Router::new()
+        .route("/test-bucket", any(object_storage))
+        .route("/test-bucket/*key", any(object_storage))
+        .layer(Extension(objects));
+
+    let server =
+        axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+    let address = server.local_addr();
+    tokio::spawn(server);
+
+    S3Config {
+        endpoint: format!("http://{address}"),
+        bucket: "test-bucket".into(),
+        region: "us-east-1".into(),
+        access_key_id: "access".into(),
+        secret_access_key: "secret".into(),
+    }
+}
+
+async fn object_storage(
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    Extension(objects): Extension<Objects>,
+    body: Bytes,
+) -> Response {
+    // The signature itself isn't checked, only that there is one
+    let signed = headers
+        .get(AUTHORIZATION)
+        .and_then(|a| a.to_str().ok())
+        .map_or(false, |a| {
+            a.starts_with("AWS4-HMAC-SHA256 Credential=access/")
+        });
+    if !signed {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let key = match uri.path().strip_prefix("/test-bucket/") {
+        Some(key) => percent_decode(key),
+        None => return list_objects(&uri, &objects).into_response(),
+    };
+    let mut objects = objects.lock().unwrap();
+
+    match method {
+        Method::PUT => {
+            let data = match headers.get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = percent_decode(source.to_str().unwrap());
+                    let source = source.strip_prefix("/test-bucket/").unwrap();
+                    match objects.get(source) {
+                        Some((data, _)) => data.clone(),
+                        None => return StatusCode::NOT_FOUND.into_response(),
+                    }
+                }
+                None => body.to_vec(),
+            };
+            objects.insert(key, (data, SystemTime::now()));
+
+            StatusCode::OK.into_response()
+        }
+        Method::GET | Method::HEAD => {
+            let (data, modified) = match objects.get(&key) {
+                Some(object) => object.clone(),
+                None => return StatusCode::NOT_FOUND.into_response(),
+            };
+
+            let range = headers
+                .get(RANGE)
+                .and_then(|r| r.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+                .map(|(start, end)| (start.parse().unwrap(), end.parse::<usize>().unwrap() + 1));
+            let (status, data) = match range {
+                Some((start, end)) => (StatusCode::PARTIAL_CONTENT, data[start..end].to_vec()),
+                None => (StatusCode::OK, data),
+            };
+
+            let mut response_headers = HeaderMap::new();
+            response_headers.insert(CONTENT_LENGTH, data.len().into());
+            response_headers.typed_insert(LastModified::from(modified));
+            if method == Method::HEAD {
+                return (status, response_headers).into_response();
+            }
+
+            (status, response_headers, data).into_response()
+        }
+        Method::DELETE => {
+            objects.remove(&key);
+
+            StatusCode::NO_CONTENT.into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+fn list_objects(uri: &Uri, objects: &Objects) -> String {
+    let query: BTreeMap<_, _> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .map(|(name, value)| (name, percent_decode(value)))
+        .collect();
+    assert_eq!(query.get("list-type").map(String::as_str), Some("2"));
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let after = query.get("continuation-token").cloned().unwrap_or_default();
+
+    let objects = objects.lock().unwrap();
+    let keys: Vec<_> = objects
+        .keys()
+        .filter(|k| k.starts_with(&prefix) && **k > after)
+        .take(LIST_PAGE_SIZE + 1)
+        .collect();
+
+    let contents: String = keys
+        .iter()
+        .take(LIST_PAGE_SIZE)
+        .map(|k| {
+            format!(
+                "<Contents><Key>{}</Key></Contents>",
+                k.replace('&', "&amp;")
+            )
+        })
+        .collect();
+    let continuation = if keys.len() > LIST_PAGE_SIZE {
+        format!(
+            "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+            keys[LIST_PAGE_SIZE - 1].replace('&', "&amp;")
+        )
+    } else {
+        "<IsTruncated>false</IsTruncated>".into()
+    };
+
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult>{contents}{continuation}</ListBucketResult>")
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::new();
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+            decoded.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).unwrap()
+}
@@ -3,6 +3,11 @@ import { merge } from "lodash"
 export const rootUrl = "https://friends.hivecom.net"
 export const url = process.env.NODE_ENV === "development" ? "localhost:3000" : rootUrl
 
+// Appended to media urls, <img> tags can't send the bearer token
+export function mediaQuery() {
+  return localStorage.getItem("media_query") ?? ""
+}
+
 export function get(url: string, options?: object) {
   return _handleFetch(
     url,
@@ -47,6 +52,9 @@ export function put(url: string, body: object | string, options?: object) {
  * Special function to handle file uploads
  */
 
+// Number of images sent in a single upload request
+export const UPLOAD_BATCH_SIZE = 10
+
 export function upload(url: string, body: object | string, options?: object) {
   return _handleFetch(url, {
     method: "POST",
@@ -69,7 +77,7 @@ export function del(url: string, options?: object) {
 
 // Private handler functions
 
-async function _handleFetch(url: string, options: object) {
+async function _handleFetch(url: string, options: object, retry = true): Promise<any> {
   const token = localStorage.getItem("bearer_token")
 
   merge(options, {
@@ -79,7 +87,50 @@ async function _handleFetch(url: string, options: object) {
     }
   })
 
-  return fetch(rootUrl + url, options).then(_handleResponse)
+  const response = await fetch(rootUrl + url, options)
+
+  // Bearer tokens expire after an hour, get a new one and try again
+  if (response.status === 401 && retry && (await _refreshSession())) {
+    return _handleFetch(url, options, false)
+  }
+
+  return _handleResponse(response)
+}
+
+// Shared by requests failing at the same time, a refresh token only works once
+let refreshing: Promise<boolean> | null = null
+
+function _refreshSession(): Promise<boolean> {
+  const refreshToken = localStorage.getItem("refresh_token")
+  if (!refreshToken) {
+    return Promise.resolve(false)
+  }
+
+  if (!refreshing) {
+    refreshing = fetch(rootUrl + "/api/login/refresh", {
+      method: "POST",
+      mode: "cors",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ refreshToken })
+    })
+      .then(async (response) => {
+        if (response.status !== 200) {
+          return false
+        }
+
+        const data = await response.json()
+        localStorage.setItem("bearer_token", data.bearerToken)
+        localStorage.setItem("refresh_token", data.refreshToken)
+
+        return true
+      })
+      .catch(() => false)
+      .finally(() => {
+        refreshing = null
+      })
+  }
+
+  return refreshing
 }
 
 async function _handleResponse(response: Response) {
@@ -87,6 +138,8 @@ async function _handleResponse(response: Response) {
   if ([401, 403].includes(response.status)) {
     localStorage.removeItem("user")
     localStorage.removeItem("bearer_token")
+    localStorage.removeItem("refresh_token")
+    localStorage.removeItem("media_query")
 
     setTimeout(() => {
       if (window.location.href === "/login") {
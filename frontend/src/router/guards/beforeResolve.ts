@@ -6,6 +6,7 @@ import { isArray } from "lodash"
 function _clearUser(next: NavigationGuardNext) {
   localStorage.removeItem("user")
   localStorage.removeItem("bearer_token")
+  localStorage.removeItem("refresh_token")
 
   return next({ name: "Login" })
 }
@@ -48,6 +49,10 @@ export default async function (to: RouteLocationNormalized, from: RouteLocationN
             })
       }
 
+      // Media of the shared album is authorized by the share token
+      const album = to.name === "PublicAlbumDetail" ? to.params.id : to.params.album
+      localStorage.setItem("media_query", `album=${album}&token=${auth.public_token}`)
+
       return next()
     } else {
       return next(from.path)
@@ -58,6 +63,7 @@ export default async function (to: RouteLocationNormalized, from: RouteLocationN
     if (!token || !key) {
       return _clearUser(next)
     } else if (!auth.logged) {
+      await auth.fetchMediaToken()
       await auth.fetchUser(key)
     }
   }
@@ -1,6 +1,6 @@
 import { defineStore } from 'pinia'
 import { remove } from 'lodash'
-import { del, get, post, put, rootUrl } from '../js/fetch'
+import { del, get, mediaQuery, post, put, rootUrl } from '../js/fetch'
 import type { FetchError } from '../js/global-types'
 import { query } from '../js/query'
 import { useLoading } from './loading'
@@ -9,9 +9,15 @@ import { useFilters } from './filters'
 
 export interface Image {
   key: string
+  kind: 'image' | 'video'
+  // Seconds, only set for videos
+  duration?: number
   fileName: string
   sizeBytes: number
   takenAt: number
+  // Time on the camera's clock where the photo was taken
+  takenAtLocal?: string
+  takenAtOffset?: number
   location?: {
     latitude: string | number
     longitude: string | number
@@ -21,9 +27,26 @@ export interface Image {
   exposureTime: string
   fNumber: string
   focalLength: string
+  iso?: number
+  lensModel?: string
+  flash?: string
+  whiteBalance?: string
+  altitude?: number
+  gpsDirection?: number
+  width?: number
+  height?: number
+  aspectRatio?: number
+  blurhash?: string
+  dominantColor?: string
+  // Changes whenever the derivatives were generated again
+  version?: number
+  title?: string
+  keywords: Array<string>
   description?: string
   uploader: string
   uploadedAt: number
+  // Overrides the setting of the uploader
+  stripMetadata?: boolean
   commentCount?: number
 }
 
@@ -307,8 +330,12 @@ export const useAlbums = defineStore('album', {
 
 type sizes = 'full' | 'large' | 'medium' | 'tiny'
 
-export function imageUrl(key: string, size: sizes = 'full') {
+// Derivatives requested with the version of the image are cached by the browser until it changes
+export function imageUrl(key: string, size: sizes = 'full', version?: number) {
   if (!key)
     return ''
-  return `${rootUrl}/data/image/${key}/${size}.jpg`
+  const query = [mediaQuery(), version !== undefined ? `v=${version}` : '']
+    .filter(Boolean)
+    .join('&')
+  return `${rootUrl}/data/image/${key}/${size}.jpg${query ? `?${query}` : ''}`
 }
@@ -29,6 +29,8 @@ export interface UserSettings {
   featredAlbumKey: string
   // private: boolean
   colorTheme: 'light-theme' | 'dark-normal' | 'dark-contrast'
+  // Remove location and camera from originals served to others
+  stripMetadata: boolean
 }
 
 export interface State {
@@ -39,6 +41,10 @@ export interface State {
   public_token: string | undefined
 }
 
+// Signed media queries expire, they are replaced an hour before so open tabs keep loading media
+const MEDIA_TOKEN_REFRESH_MARGIN = 3600
+let mediaTokenTimeout: ReturnType<typeof setTimeout> | undefined
+
 export const useUser = defineStore('user', {
   state: () => ({
     user: {},
@@ -48,6 +54,8 @@ export const useUser = defineStore('user', {
     public_token: undefined,
   } as State),
   actions: {
+    // Resolves to a challenge token if the account has two-factor authentication, the code then
+    // has to be sent with signInTotp
     async signIn(credentials: { username: string; password: string }) {
       const { addLoading, delLoading } = useLoading()
 
@@ -55,11 +63,25 @@ export const useUser = defineStore('user', {
 
       return post('/api/login', credentials)
         .then(async (res) => {
-          localStorage.setItem('bearer_token', res.bearerToken)
+          if (res.challengeToken)
+            return res.challengeToken as string
 
-          await this.fetchUser(res.username)
+          await this.startSession(res)
+        })
+        .catch((error: FetchError) => {
+          const toast = useToast()
+          toast.add(error.message, 'error')
+        })
+        .finally(() => delLoading('login'))
+    },
+    async signInTotp(challengeToken: string, code: string) {
+      const { addLoading, delLoading } = useLoading()
 
-          this.logged = true
+      addLoading('login')
+
+      return post('/api/login/totp', { challengeToken, code })
+        .then(async (res) => {
+          await this.startSession(res)
         })
         .catch((error: FetchError) => {
           const toast = useToast()
@@ -67,6 +89,15 @@ export const useUser = defineStore('user', {
         })
         .finally(() => delLoading('login'))
     },
+    async startSession(res: { bearerToken: string; refreshToken: string; username: string }) {
+      localStorage.setItem('bearer_token', res.bearerToken)
+      localStorage.setItem('refresh_token', res.refreshToken)
+
+      await this.fetchMediaToken()
+      await this.fetchUser(res.username)
+
+      this.logged = true
+    },
     async fetchUser(username: string | number, notme?: boolean) {
       const { addLoading, delLoading } = useLoading()
 
@@ -114,6 +145,24 @@ export const useUser = defineStore('user', {
     signOut() {
       this.logged = false
       localStorage.removeItem('bearer_token')
+      localStorage.removeItem('refresh_token')
+      localStorage.removeItem('media_query')
+      clearTimeout(mediaTokenTimeout)
+    },
+
+    async fetchMediaToken() {
+      return get('/api/images/media-token')
+        .then((response) => {
+          localStorage.setItem('media_query', response.query)
+
+          const refreshIn = response.expiresAt - MEDIA_TOKEN_REFRESH_MARGIN - Date.now() / 1000
+          clearTimeout(mediaTokenTimeout)
+          mediaTokenTimeout = setTimeout(() => this.fetchMediaToken(), Math.max(refreshIn, 60) * 1000)
+        })
+        .catch((error: FetchError) => {
+          const toast = useToast()
+          toast.add(error.message, 'error')
+        })
     },
 
     async fetchSettings() {